@@ -275,7 +275,11 @@ impl Apic {
     }
 
     pub fn get_id(&self) -> u32 {
-        self.read_register(Register::ID).get_bits(24..32)
+        match self.0 {
+            Type::xAPIC(_) => self.read_register(Register::ID).get_bits(24..32),
+            // x2APIC IDs occupy the entire register.
+            Type::x2APIC => self.read_register(Register::ID),
+        }
     }
 
     #[inline]
@@ -299,8 +303,20 @@ impl Apic {
     /// An invalid or unexpcted interrupt command could potentially put the core in an unusable state.
     #[inline]
     pub unsafe fn send_int_cmd(&self, interrupt_command: InterruptCommand) {
-        self.write_register(Register::ICRL, interrupt_command.get_id());
-        self.write_register(Register::ICRH, interrupt_command.get_cmd());
+        match self.0 {
+            Type::xAPIC(_) => {
+                // Writing the low register sends the interrupt, so the destination must be written first.
+                self.write_register(Register::ICRH, interrupt_command.get_id() << 24);
+                self.write_register(Register::ICRL, interrupt_command.get_cmd());
+            }
+
+            // x2APIC combines the interrupt command registers into a single MSR.
+            Type::x2APIC => msr::wrmsr(
+                Register::ICRL.x2apic_msr(),
+                (u64::from(interrupt_command.get_id()) << 32)
+                    | u64::from(interrupt_command.get_cmd()),
+            ),
+        }
     }
 
     /// ## Safety
@@ -7,3 +7,100 @@ pub fn invlpg(page: Address<Page>) {
         core::arch::asm!("invlpg [{}]", in(reg) page.get().get(), options(nostack, preserves_flags));
     }
 }
+
+/// Kinds of invalidation performed by [`invpcid`].
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvpcidKind {
+    /// Invalidates a single page, tagged with the given PCID.
+    Address = 0,
+    /// Invalidates every non-global entry tagged with the given PCID.
+    Context = 1,
+    /// Invalidates every entry, including global pages.
+    AllIncludingGlobal = 2,
+    /// Invalidates every non-global entry.
+    AllNonGlobal = 3,
+}
+
+/// Whether the `INVPCID` instruction is supported.
+pub fn has_invpcid() -> bool {
+    use crate::arch::x86_64::cpuid;
+
+    cpuid::EXT_FEATURE_INFO
+        .as_ref()
+        .is_some_and(cpuid::ExtendedFeatures::has_invpcid)
+}
+
+/// Invalidates the TLB entries selected by `kind`, `pcid`, and `page`.
+///
+/// ## Safety
+///
+/// `INVPCID` must be supported (see [`has_invpcid`]).
+pub unsafe fn invpcid(kind: InvpcidKind, pcid: u16, page: Address<Page>) {
+    #[repr(C, align(16))]
+    struct Descriptor {
+        pcid: u64,
+        address: u64,
+    }
+
+    let descriptor = Descriptor {
+        pcid: u64::from(pcid),
+        address: page.get().get() as u64,
+    };
+
+    // Safety: Caller is required to ensure the instruction is supported, and invalidating the cache has no
+    //         program side effects.
+    unsafe {
+        core::arch::asm!(
+            "invpcid {}, [{}]",
+            in(reg) kind as u64,
+            in(reg) &raw const descriptor,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+/// Invalidates every entry in the TLB, including global pages and those tagged with any PCID.
+pub fn flush_all() {
+    use crate::arch::x86_64::registers::control::{CR4, CR4Flags};
+
+    if has_invpcid() {
+        // Safety: `INVPCID` is supported.
+        unsafe {
+            invpcid(InvpcidKind::AllIncludingGlobal, 0, Address::new_truncate(0));
+        }
+
+        return;
+    }
+
+    let flags = CR4::read();
+    if flags.contains(CR4Flags::PGE) {
+        // Safety: Toggling `CR4.PGE` only invalidates the TLB, and it's restored immediately.
+        unsafe {
+            CR4::write(flags.difference(CR4Flags::PGE));
+            CR4::write(flags);
+        }
+    } else {
+        crate::arch::x86_64::registers::control::CR3::refresh();
+    }
+}
+
+/// Invalidates every non-global entry of the current address space.
+pub fn flush_context() {
+    use crate::arch::x86_64::registers::control::{CR3, CR4, CR4Flags};
+
+    // Without `CR4.PCIDE`, the low bits of CR3 aren't a PCID.
+    if has_invpcid() && CR4::read().contains(CR4Flags::PCIDE) {
+        // Safety: `INVPCID` is supported.
+        unsafe {
+            invpcid(
+                InvpcidKind::Context,
+                CR3::read_pcid(),
+                Address::new_truncate(0),
+            );
+        }
+    } else {
+        // Reloading CR3 (without the no-flush bit) invalidates the current PCID.
+        CR3::refresh();
+    }
+}
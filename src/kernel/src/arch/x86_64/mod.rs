@@ -96,6 +96,15 @@ pub unsafe fn configure_hwthread() {
         }
     }
 
+    // Program the page attribute table, so every `CacheMode` can be selected by page table entries.
+    if cpuid::FEATURE_INFO.has_pat() {
+        // Safety: The layout matches the one already programmed by the bootloader, so no existing
+        //         mapping changes memory type.
+        unsafe {
+            msr::IA32_PAT::write(crate::mem::paging::CacheMode::PAT_LAYOUT);
+        }
+    }
+
     // Safety: This function is only called once, prior to FS/GS base being in use.
     unsafe {
         crate::arch::x86_64::structures::gdt::load();
@@ -21,6 +21,42 @@ impl CR3 {
         asm!("mov cr3, {}", in(reg) (address.get().get() as u64) | flags.bits(), options(nostack));
     }
 
+    /// Writes `address` to CR3, tagging the address space with `pcid`. If `no_flush` is set, the
+    /// TLB entries already tagged with `pcid` are kept.
+    ///
+    /// Safety
+    ///
+    /// - `CR4.PCIDE` must be set.
+    /// - If `no_flush` is set, the entries tagged with `pcid` must be valid for the new address space.
+    #[inline]
+    pub unsafe fn write_pcid(address: Address<Frame>, pcid: u16, no_flush: bool) {
+        const NO_FLUSH_BIT: u64 = 1 << 63;
+
+        debug_assert!(pcid < 0x1000, "PCID is out of range: {pcid:#X}");
+
+        let value = (address.get().get() as u64)
+            | u64::from(pcid)
+            | if no_flush { NO_FLUSH_BIT } else { 0 };
+
+        // Safety: Caller is required to ensure the PCID entries are valid for the new address space.
+        unsafe {
+            asm!("mov cr3, {}", in(reg) value, options(nostack));
+        }
+    }
+
+    /// Reads the PCID of the current address space. This is only meaningful if `CR4.PCIDE` is set.
+    #[inline]
+    pub fn read_pcid() -> u16 {
+        let value: u64;
+
+        // Safety: Reading CR3 has no side effects.
+        unsafe {
+            asm!("mov {}, cr3", out(reg) value, options(nostack, nomem));
+        }
+
+        u16::try_from(value & 0xFFF).unwrap()
+    }
+
     pub fn read() -> (Address<Frame>, CR3Flags) {
         let value: u64;
 
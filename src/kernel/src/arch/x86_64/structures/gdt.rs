@@ -97,20 +97,9 @@ pub const UCODE_SELECTOR: SegmentSelector = SegmentSelector::new(4, PrivilegeLev
 pub unsafe fn load() {
     use core::arch::asm;
 
-    let gdt_dtptr = crate::arch::x86_64::structures::DescriptorTablePointer {
-        limit: u16::try_from(core::mem::size_of_val(&GDT) - 1).unwrap(),
-        base: GDT.as_ptr().addr().try_into().unwrap(),
-    };
-
-    // Safety: The GDT is properly formed, and the descriptor table pointer is
-    //         set to the GDT's memory location, with the requisite limit set
-    //         correctly (size in bytes, less 1).
+    // Safety: The GDT is properly formed, and static.
     unsafe {
-        asm!(
-            "lgdt [{}]",
-            in(reg) &raw const gdt_dtptr,
-            options(readonly, nostack, preserves_flags)
-        );
+        lgdt(&GDT);
     }
 
     // Safety: This is special since we cannot directly move to CS; x86 requires the instruction
@@ -166,6 +155,69 @@ pub unsafe fn load() {
     }
 }
 
+/// ## Safety
+///
+/// `table` must be a properly formed GDT, which outlives its use by the processor.
+unsafe fn lgdt<T>(table: &[T]) {
+    let gdt_dtptr = crate::arch::x86_64::structures::DescriptorTablePointer {
+        limit: u16::try_from(core::mem::size_of_val(table) - 1).unwrap(),
+        base: table.as_ptr().addr().try_into().unwrap(),
+    };
+
+    // Safety: The descriptor table pointer is set to the GDT's memory location, with the
+    //         requisite limit set correctly (size in bytes, less 1).
+    unsafe {
+        core::arch::asm!(
+            "lgdt [{}]",
+            in(reg) &raw const gdt_dtptr,
+            options(readonly, nostack, preserves_flags)
+        );
+    }
+}
+
+/// Loads the task register from the system segment `descriptor`.
+///
+/// The processor caches the segment when the task register is loaded, and never reads its descriptor
+/// again in long mode. So rather than giving every hardware thread its own GDT, the descriptor is
+/// loaded from a temporary copy of the GDT, and the shared GDT is restored afterwards.
+///
+/// ## Safety
+///
+/// `descriptor` must describe a valid task state segment, which is used by no other hardware thread.
+pub unsafe fn load_tss(descriptor: [u64; 2]) {
+    // The TSS descriptor directly follows the GDT's own (with an RPL of 0).
+    let tss_selector = u16::try_from(GDT.len()).unwrap() << 3;
+
+    // The kernel segments keep their indexes, so interrupts taken on the temporary GDT still work.
+    let mut temp_gdt = [0u64; GDT.len() + 2];
+    for (entry, segment) in temp_gdt.iter_mut().zip(GDT.iter()) {
+        *entry = segment.bits();
+    }
+    temp_gdt[GDT.len()..].copy_from_slice(&descriptor);
+
+    crate::interrupts::without(|| {
+        // Safety: The temporary GDT is a copy of the GDT, with the TSS descriptor appended, and is
+        //         replaced before it goes out of scope.
+        unsafe {
+            lgdt(&temp_gdt);
+        }
+
+        // Safety: Caller is required to ensure the descriptor describes a valid task state segment.
+        unsafe {
+            core::arch::asm!(
+                "ltr {:x}",
+                in(reg) tss_selector,
+                options(nostack, preserves_flags)
+            );
+        }
+
+        // Safety: The GDT is properly formed, and static.
+        unsafe {
+            lgdt(&GDT);
+        }
+    });
+}
+
 /// Specifies which element to load into a segment from
 /// descriptor tables (i.e., is a index to LDT or GDT table
 /// with some additional flags).
@@ -208,6 +208,8 @@ unsafe extern "sysv64" fn irq_handler(
             crate::cpu::state::with_scheduler(|scheduler| scheduler.interrupt_task(isf, regs))
         }
 
+        Ok(Vector::TlbShootdown) => crate::mem::tlb::service_pending(),
+
         Ok(Vector::Syscall) => {
             let vector = regs.rax;
             let arg0 = regs.rdi;
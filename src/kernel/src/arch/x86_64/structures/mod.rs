@@ -1,7 +1,7 @@
 pub mod gdt;
 pub mod idt;
 // pub mod ioapic;
-// pub mod tss;
+pub mod tss;
 
 /// A struct describing a pointer to a descriptor table (GDT / IDT).
 /// This is in a format suitable for giving to 'lgdt' or 'lidt'.
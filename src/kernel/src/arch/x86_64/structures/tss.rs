@@ -1,48 +1,84 @@
 #![allow(clippy::module_name_repetitions)]
 
-use super::gdt;
+use crate::arch::x86_64::structures::idt::StackTableIndex;
+use bit_field::BitField;
+use core::ptr::NonNull;
 
+/// In long mode, the task state segment holds no task state, only the stack pointers the processor
+/// switches to when an interrupt is taken.
+#[repr(C, packed(4))]
+#[derive(Debug, Clone, Copy)]
+pub struct TaskStateSegment {
+    _reserved0: u32,
+    /// Stack pointers loaded when an interrupt changes the privilege level to ring 0, 1, or 2.
+    pub privilege_stack_table: [u64; 3],
+    _reserved1: u64,
+    /// Stack pointers loaded for interrupts whose IDT entry sets a [`StackTableIndex`].
+    pub interrupt_stack_table: [u64; 7],
+    _reserved2: u64,
+    _reserved3: u16,
+    /// Offset of the I/O permission bitmap from the start of the segment.
+    pub iomap_base: u16,
+}
+
+impl TaskStateSegment {
+    /// Creates a segment with no stacks, and no I/O permission bitmap.
+    pub const fn new() -> Self {
+        Self {
+            _reserved0: 0,
+            privilege_stack_table: [0; 3],
+            _reserved1: 0,
+            interrupt_stack_table: [0; 7],
+            _reserved2: 0,
+            _reserved3: 0,
+            #[allow(clippy::cast_possible_truncation)]
+            iomap_base: core::mem::size_of::<Self>() as u16,
+        }
+    }
 
-pub fn ptr_as_descriptor(tss_ptr: core::ptr::NonNull<TaskStateSegment>) -> gdt::Descriptor {
-    use bit_field::BitField;
+    /// Sets the stack the processor switches to for interrupts using `index`.
+    pub fn set_interrupt_stack(&mut self, index: StackTableIndex, stack_top: NonNull<u8>) {
+        self.interrupt_stack_table[usize::from(index as u16)] = stack_top.addr().get() as u64;
+    }
 
-    let tss_ptr_u64 = tss_ptr.addr().get() as u64;
+    /// Builds the (two entry) system segment descriptor referencing `tss`.
+    fn descriptor(tss: &'static Self) -> [u64; 2] {
+        let tss_ptr_u64 = core::ptr::from_ref(tss).addr() as u64;
 
-    let mut low = gdt::DescriptorFlags::PRESENT.bits();
-    // base
-    low.set_bits(16..40, tss_ptr_u64.get_bits(0..24));
-    low.set_bits(56..64, tss_ptr_u64.get_bits(24..32));
-    // limit (the `-1` is needed since the bound is inclusive, not exclusive)
-    low.set_bits(0..16, (core::mem::size_of::<TaskStateSegment>() - 1) as u64);
-    // type (0b1001 = available 64-bit tss)
-    low.set_bits(40..44, 0b1001);
+        let mut low = 0;
+        // limit (the `-1` is needed since the bound is inclusive, not exclusive)
+        low.set_bits(0..16, (core::mem::size_of::<Self>() - 1) as u64);
+        // base
+        low.set_bits(16..40, tss_ptr_u64.get_bits(0..24));
+        low.set_bits(56..64, tss_ptr_u64.get_bits(24..32));
+        // type (0b1001 = available 64-bit tss)
+        low.set_bits(40..44, 0b1001);
+        // present
+        low.set_bit(47, true);
 
-    // high 32 bits of base
-    let mut high = 0;
-    high.set_bits(0..32, tss_ptr_u64.get_bits(32..64));
+        // high 32 bits of base
+        let mut high = 0;
+        high.set_bits(0..32, tss_ptr_u64.get_bits(32..64));
 
-    gdt::Descriptor::SystemSegment(low, high)
+        [low, high]
+    }
 }
 
+impl Default for TaskStateSegment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Loads `tss` as the current hardware thread's task state segment.
+///
 /// ## Safety
 ///
-/// * Descriptor must be valid as the core's task state segment.
-/// * Caller must ensure loading a new TSS will not result in undefined behaviour.
-pub unsafe fn load(descriptor: gdt::Descriptor) {
-    crate::interrupts::without(|| {
-        // Store current GDT pointer to restore later.
-        let cur_gdt = gdt::sgdt();
-        // Create temporary kernel GDT to avoid a GPF on switching to it.
-        let mut temp_gdt = gdt::GlobalDescriptorTable::new();
-        temp_gdt.add_entry(gdt::Descriptor::kernel_code_segment());
-        temp_gdt.add_entry(gdt::Descriptor::kernel_data_segment());
-        let tss_selector = temp_gdt.add_entry(descriptor);
-
-        // Load temp GDT ...
-        temp_gdt.load_unsafe();
-        // ... load TSS from temporary GDT ...
-        load_tss(tss_selector);
-        // ... and restore cached GDT.
-        super::gdt::lgdt(&cur_gdt);
-    });
+/// * Every stack pointer in `tss` must be the top of a valid stack (or unused by any IDT entry).
+/// * The task state segment must not already be loaded by this or any other hardware thread.
+pub unsafe fn load(tss: &'static TaskStateSegment) {
+    // Safety: Caller is required to ensure the segment's stacks are valid.
+    unsafe {
+        super::gdt::load_tss(TaskStateSegment::descriptor(tss));
+    }
 }
@@ -1,5 +1,19 @@
 pub mod state;
 
+use core::{
+    num::NonZeroUsize,
+    ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Maximum number of hardware threads which are brought online (including the boot thread), which
+/// bounds the per-hardware thread tables that mustn't allocate while they're locked.
+pub const MAX_HWTHREADS: usize = 256;
+
+/// Count of application processors which have completed their early init, and no
+/// longer reference any bootloader-provided memory.
+static HWTHREADS_ONLINE: AtomicUsize = AtomicUsize::new(0);
+
 pub fn get_id() -> u32 {
     #[cfg(target_arch = "x86_64")]
     {
@@ -20,39 +34,127 @@ pub unsafe fn configure() {
     }
 }
 
-pub fn start_mp(mp_request: &limine::request::MpRequest) {
+/// Starts all additional hardware threads reported by the bootloader, returning how many were started.
+pub fn start_mp(mp_request: &limine::request::MpRequest) -> usize {
     let Some(response) = mp_request.get_response() else {
         warn!("Bootloader did not provide response to multiprocessing request.");
-        return;
+        return 0;
     };
 
+    if !crate::params::use_multiprocessing() {
+        debug!("Multiprocessing is disabled; skipping additional cores.");
+        return 0;
+    }
+
     debug!("Detecting and starting additional cores.");
 
-    for cpu in response.cpus().iter().filter(|cpu| {
-        // Make sure we skip the boot thread (we're using it right now!).
-        cpu.lapic_id != response.bsp_lapic_id()
-    }) {
+    // The boot thread is already running, so it counts towards the limit.
+    let max_aps = crate::params::max_hwthreads()
+        .map_or(MAX_HWTHREADS, NonZeroUsize::get)
+        .min(MAX_HWTHREADS)
+        - 1;
+
+    let mut started = 0;
+    for cpu in response
+        .cpus()
+        .iter()
+        .filter(|cpu| {
+            // Make sure we skip the boot thread (we're using it right now!).
+            cpu.lapic_id != response.bsp_lapic_id()
+        })
+        .take(max_aps)
+    {
         trace!(
             "Starting hardware thread: ID ID#{} LAPIC#{}",
             cpu.id, cpu.lapic_id
         );
 
         extern "C" fn _mp_entry(_: &limine::mp::Cpu) -> ! {
-            // Safety: Function is run only once for this hardware thread.
-            unsafe {
-                configure();
+            extern "sysv64" fn _mp_online(_: *mut ()) -> ! {
+                // Safety: The hardware thread is now off of its bootloader-provided stack.
+                unsafe {
+                    crate::mem::kaslr::load_kernel_tables();
+                }
+
+                HWTHREADS_ONLINE.fetch_add(1, Ordering::Release);
+
+                // Safety: Hardware thread still in init phase.
+                unsafe { run() }
             }
 
-            // Safety: All currently referenced memory should also be mapped in the kernel page tables.
-            crate::mem::with_kmapper(|kmapper| unsafe {
-                kmapper.swap_into();
+            crate::timeline::phase("cpu::configure_ap", || {
+                // Safety: Function is run only once for this hardware thread.
+                unsafe {
+                    configure();
+                }
+
+                // Safety: All currently referenced memory is either mapped in the kernel page tables, or is
+                //         within the bootloader's HHDM.
+                unsafe {
+                    crate::mem::kaslr::load_boot_tables();
+                }
             });
 
-            // Safety: Hardware thread still in init phase.
-            unsafe { run() }
+            // Safety: The bootloader-provided stack is reclaimable memory, so it must be switched off
+            //         of before this hardware thread reports itself as online.
+            unsafe { switch_stack(_mp_online, core::ptr::null_mut()) }
         }
 
         cpu.goto_address.write(_mp_entry);
+        started += 1;
+    }
+
+    started
+}
+
+/// Number of hardware threads which are online, including the boot thread.
+pub fn hwthreads_online() -> usize {
+    HWTHREADS_ONLINE.load(Ordering::Acquire) + 1
+}
+
+/// Spins until `count` application processors have come online.
+pub fn wait_for_hwthreads(count: usize) {
+    debug!("Waiting for {count} hardware threads to come online...");
+
+    while HWTHREADS_ONLINE.load(Ordering::Acquire) < count {
+        core::hint::spin_loop();
+    }
+
+    debug!("All hardware threads are online.");
+}
+
+/// Allocates a new kernel stack of `size` bytes, returning a pointer to its top. The stack is preceded
+/// by a guard page, so overflowing it faults.
+pub(crate) fn allocate_stack(size: usize) -> NonNull<u8> {
+    let page_count = NonZeroUsize::new(size / libsys::page_size()).unwrap();
+    let stack = crate::mem::vmalloc::allocate(page_count).expect("failed to allocate kernel stack");
+
+    // Safety: Pointer is offset exactly to the end of the allocation.
+    NonNull::new(unsafe { stack.as_mut_ptr().add(stack.len()) }).unwrap()
+}
+
+/// Moves the current hardware thread onto a newly allocated kernel stack, and calls `entry` with `arg`.
+///
+/// ## Safety
+///
+/// Nothing on the current stack may be referenced after the switch.
+pub unsafe fn switch_stack<T>(entry: extern "sysv64" fn(*mut T) -> !, arg: *mut T) -> ! {
+    let stack_top = allocate_stack(state::stack_size());
+
+    // Safety: Caller is required to ensure the current stack is no longer needed.
+    unsafe {
+        core::arch::asm!(
+            "
+            mov rsp, {}
+            xor rbp, rbp
+
+            call {}
+            ",
+            in(reg) stack_top.as_ptr(),
+            in(reg) entry as usize,
+            in("rdi") arg.cast::<u8>(),
+            options(noreturn)
+        )
     }
 }
 
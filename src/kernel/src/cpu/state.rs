@@ -8,19 +8,34 @@ pub const US_WAIT: u32 = 10000;
 pub const US_FREQ_FACTOR: u32 = US_PER_SEC / US_WAIT;
 
 pub const STACK_SIZE: usize = 0x10000;
+pub const LOW_MEMORY_STACK_SIZE: usize = 0x8000;
+/// Size of the stacks exceptions which may occur on a bad kernel stack (e.g. a double fault) switch to.
+pub const EXCEPTION_STACK_SIZE: usize = 0x8000;
+
+/// Size of newly allocated kernel stacks, which is reduced in low-memory mode.
+pub fn stack_size() -> usize {
+    if crate::params::use_low_memory() {
+        LOW_MEMORY_STACK_SIZE
+    } else {
+        STACK_SIZE
+    }
+}
 
 #[repr(C)]
 struct State {
     core_id: u32,
     scheduler: InterruptCell<Scheduler>,
 
-    // #[cfg(target_arch = "x86_64")]
-    // tss: Box<crate::arch::x86_64::structures::tss::TaskStateSegment>,
     #[cfg(target_arch = "x86_64")]
     apic: apic::Apic,
 
     timer_interval: Option<NonZeroU64>,
 
+    frame_cache: crate::mem::pmm::FrameCache,
+    slab_magazines: slab_alloc::Magazines,
+    shootdown: crate::mem::tlb::ShootdownState,
+    pcids: crate::mem::pcid::Allocator,
+
     catch_exception: AtomicBool,
     exception: UnsafeCell<Option<Exception>>,
 }
@@ -40,42 +55,35 @@ pub enum ExceptionCatcher {
 /// This function invariantly assumes it will only be called once.
 #[allow(clippy::too_many_lines)]
 pub unsafe fn init(timer_frequency: u16) {
-    // #[cfg(target_arch = "x86_64")]
-    // let tss = {
-    //     use crate::arch::x86_64::structures::{
-    //         idt::{DB_STACK_TABLE_INDEX, DF_STACK_TABLE_INDEX, MC_STACK_TABLE_INDEX, NM_STACK_TABLE_INDEX},
-    //         tss,
-    //     };
-    //     use core::num::NonZeroUsize;
-    //     use ia32utils::VirtAddr;
-
-    //     fn allocate_tss_stack() -> VirtAddr {
-    //         use crate::mem::Stack;
-
-    //         const TSS_STACK_SIZE: NonZeroUsize = NonZeroUsize::new(0x16000).unwrap();
-
-    //         VirtAddr::from_ptr(Box::leak(Box::new(Stack::<{ TSS_STACK_SIZE.get() }>::new())).as_ptr_range().end)
-    //     }
-
-    //     let mut tss = Box::new(tss::TaskStateSegment::new());
-    //     // TODO guard pages for these stacks
-    //     tss.privilege_stack_table[0] = allocate_tss_stack();
-    //     tss.interrupt_stack_table[DB_STACK_TABLE_INDEX.into()] = allocate_tss_stack();
-    //     tss.interrupt_stack_table[NM_STACK_TABLE_INDEX.into()] = allocate_tss_stack();
-    //     tss.interrupt_stack_table[DF_STACK_TABLE_INDEX.into()] = allocate_tss_stack();
-    //     tss.interrupt_stack_table[MC_STACK_TABLE_INDEX.into()] = allocate_tss_stack();
+    #[cfg(target_arch = "x86_64")]
+    {
+        use crate::arch::x86_64::structures::{
+            idt::StackTableIndex,
+            tss::{self, TaskStateSegment},
+        };
 
-    //     tss::load(tss::ptr_as_descriptor(NonNull::new(&mut *tss).unwrap()));
+        let mut tss = Box::new(TaskStateSegment::new());
+        tss.privilege_stack_table[0] = super::allocate_stack(stack_size()).addr().get() as u64;
+        for index in [
+            StackTableIndex::Debug,
+            StackTableIndex::NonMaskableInterrupt,
+            StackTableIndex::DoubleFault,
+            StackTableIndex::MachineCheck,
+        ] {
+            tss.set_interrupt_stack(index, super::allocate_stack(EXCEPTION_STACK_SIZE));
+        }
 
-    //     tss
-    // };
+        // Safety: Every stack in the segment was just allocated, and the segment is leaked, so it's
+        //         never used by another hardware thread.
+        unsafe {
+            tss::load(Box::leak(tss));
+        }
+    }
 
     let mut state = Box::new(State {
         core_id: crate::cpu::get_id(),
         scheduler: InterruptCell::new(Scheduler::new(false)),
 
-        // #[cfg(target_arch = "x86_64")]
-        // tss,
         #[cfg(target_arch = "x86_64")]
         apic: apic::Apic::new(Some(|address: usize| {
             core::ptr::with_exposed_provenance_mut(crate::mem::Hhdm::offset().get() + address)
@@ -84,6 +92,14 @@ pub unsafe fn init(timer_frequency: u16) {
 
         timer_interval: None,
 
+        frame_cache: crate::mem::pmm::FrameCache::new(),
+        slab_magazines: slab_alloc::Magazines::new(),
+        shootdown: crate::mem::tlb::ShootdownState::new(
+            crate::cpu::get_id(),
+            crate::mem::PagingRegister::read().frame(),
+        ),
+        pcids: crate::mem::pcid::Allocator::new(),
+
         catch_exception: AtomicBool::new(false),
         exception: UnsafeCell::new(None),
     });
@@ -179,7 +195,14 @@ pub unsafe fn init(timer_frequency: u16) {
         state.timer_interval = NonZeroU64::new(timer_interval);
     }
 
-    let state_address = Box::into_raw(state).addr();
+    let state_ptr = Box::into_raw(state);
+
+    // Safety: State is leaked, and is never deallocated.
+    crate::mem::pmm::FrameCache::register(unsafe { &(*state_ptr).frame_cache });
+    // Safety: See above.
+    crate::mem::tlb::ShootdownState::register(unsafe { &(*state_ptr).shootdown });
+
+    let state_address = state_ptr.addr();
 
     #[cfg(target_arch = "x86_64")]
     // Safety: [`IA32_KERNEL_GS_BASE`] should be currently in use.
@@ -188,10 +211,19 @@ pub unsafe fn init(timer_frequency: u16) {
     }
 }
 
-fn get_ptr() -> NonNull<State> {
+fn try_get_ptr() -> Option<NonNull<State>> {
     let kernel_gs_usize =
         usize::try_from(crate::arch::x86_64::registers::msr::IA32_KERNEL_GS_BASE::read()).unwrap();
-    NonNull::new(kernel_gs_usize as *mut State).expect("state register is empty")
+    NonNull::new(kernel_gs_usize as *mut State)
+}
+
+fn get_ptr() -> NonNull<State> {
+    try_get_ptr().expect("state register is empty")
+}
+
+fn try_get() -> Option<&'static State> {
+    // Safety: If the pointer is non-null, the kernel guarantees it will be initialized.
+    try_get_ptr().map(|state| unsafe { state.as_ref() })
 }
 
 fn get() -> &'static State {
@@ -225,6 +257,52 @@ pub unsafe fn begin_scheduling() {
     }
 }
 
+/// Frame cache of the current hardware thread, if its core-local state has been initialized.
+pub fn frame_cache() -> Option<&'static crate::mem::pmm::FrameCache> {
+    try_get().map(|state| &state.frame_cache)
+}
+
+/// Slab allocator magazines of the current hardware thread, if its core-local state has been initialized.
+pub fn slab_magazines() -> Option<&'static slab_alloc::Magazines> {
+    try_get().map(|state| &state.slab_magazines)
+}
+
+/// TLB shootdown state of the current hardware thread, if its core-local state has been initialized.
+pub fn shootdown_state() -> Option<&'static crate::mem::tlb::ShootdownState> {
+    try_get().map(|state| &state.shootdown)
+}
+
+/// Sends a fixed interrupt with the given vector to the hardware thread with `apic_id`.
+///
+/// # Safety
+///
+/// - The target hardware thread must expect to handle the interrupt.
+pub unsafe fn send_ipi(apic_id: u32, vector: crate::interrupts::Vector) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        let vector = u8::try_from(vector as u64).unwrap();
+        let command =
+            apic::InterruptCommand::new(vector, apic_id, apic::DeliveryMode::Fixed, false, true);
+
+        // Safety: Caller is required to ensure the target expects the interrupt.
+        unsafe {
+            get().apic.send_int_cmd(command);
+        }
+    }
+}
+
+/// Provides the PCID allocator of the current hardware thread, if its core-local state has been
+/// initialized.
+pub fn with_pcid_allocator<O>(
+    func: impl FnOnce(&mut crate::mem::pcid::Allocator) -> O,
+) -> Option<O> {
+    try_get_ptr().map(|mut state| {
+        // Safety: If the pointer is non-null, the kernel guarantees it will be initialized, and the
+        //         allocator is only ever accessed by its own hardware thread, with interrupts disabled.
+        crate::interrupts::without(|| func(unsafe { &mut state.as_mut().pcids }))
+    })
+}
+
 pub fn with_scheduler<O>(func: impl FnOnce(&mut crate::task::Scheduler) -> O) -> O {
     get_mut().scheduler.with_mut(func)
 }
@@ -1,30 +1,118 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::{marker::PhantomData, ops::Range};
 use libsys::{Address, Frame, Physical, Virtual};
 use limine::{
-    memory_map,
     mp::RequestFlags,
     request::{
         BootloaderInfoRequest, ExecutableAddressRequest, ExecutableCmdlineRequest,
-        ExecutableFileRequest, HhdmRequest, MemoryMapRequest, MpRequest, RsdpRequest,
+        ExecutableFileRequest, HhdmRequest, MemoryMapRequest, ModuleRequest, MpRequest,
+        RsdpRequest,
     },
 };
 
 use crate::mem::pmm::PhysicalMemoryManager;
 
+/// All Limine feature requests. These are only reachable through [`BootPhase`], which
+/// ensures they are not used after bootloader memory is reclaimed.
+mod requests {
+    use super::*;
+
+    pub static BOOT_INFO_REQUEST: BootloaderInfoRequest = BootloaderInfoRequest::new();
+    pub static KERNEL_FILE_REQUEST: ExecutableFileRequest = ExecutableFileRequest::new();
+    pub static KERNEL_CMDLINE_REQUEST: ExecutableCmdlineRequest = ExecutableCmdlineRequest::new();
+    pub static KERNEL_ADDR_REQUEST: ExecutableAddressRequest = ExecutableAddressRequest::new();
+    pub static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
+    pub static MEMORY_MAP_REQUEST: MemoryMapRequest = MemoryMapRequest::new();
+    pub static RSDP_ADDRESS_REQUEST: RsdpRequest = RsdpRequest::new();
+    pub static MP_REQUEST: MpRequest = MpRequest::new().with_flags(RequestFlags::X2APIC);
+    pub static MODULES_REQUEST: ModuleRequest = ModuleRequest::new();
+}
+
+/// Token proving the kernel is still in its boot phase (i.e. bootloader-reclaimable
+/// memory has not yet been reclaimed).
+///
+/// Limine requests (and so their responses) can only be borrowed from this token, and
+/// the token is consumed by [`finalize_init`]. The borrow checker thus guarantees no
+/// bootloader response is touched after its memory has been reclaimed.
+pub struct BootPhase {
+    // Ensures the token is `!Send` and `!Sync`, so it can't escape the boot thread.
+    _marker: PhantomData<*mut ()>,
+}
+
+impl BootPhase {
+    /// ## Safety
+    ///
+    /// Must be called only once, on the boot thread, before bootloader memory is reclaimed.
+    unsafe fn new() -> Self {
+        Self {
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn bootloader_info(&self) -> &BootloaderInfoRequest {
+        &requests::BOOT_INFO_REQUEST
+    }
+
+    pub fn kernel_file(&self) -> &ExecutableFileRequest {
+        &requests::KERNEL_FILE_REQUEST
+    }
+
+    pub fn kernel_cmdline(&self) -> &ExecutableCmdlineRequest {
+        &requests::KERNEL_CMDLINE_REQUEST
+    }
+
+    pub fn kernel_address(&self) -> &ExecutableAddressRequest {
+        &requests::KERNEL_ADDR_REQUEST
+    }
+
+    pub fn hhdm(&self) -> &HhdmRequest {
+        &requests::HHDM_REQUEST
+    }
+
+    pub fn memory_map(&self) -> &MemoryMapRequest {
+        &requests::MEMORY_MAP_REQUEST
+    }
+
+    pub fn rsdp_address(&self) -> &RsdpRequest {
+        &requests::RSDP_ADDRESS_REQUEST
+    }
+
+    pub fn mp(&self) -> &MpRequest {
+        &requests::MP_REQUEST
+    }
+
+    pub fn modules(&self) -> &ModuleRequest {
+        &requests::MODULES_REQUEST
+    }
+}
+
+impl Drop for BootPhase {
+    fn drop(&mut self) {
+        debug!("Boot phase has ended; bootloader responses are no longer accessible.");
+    }
+}
+
+/// Randomizes the kernel's layout (see [`crate::mem::kaslr`]), returning the address [`init`] was moved to.
+pub extern "C" fn randomize_layout() -> usize {
+    // Safety: This is only called by the kernel entry point, with the bootloader's page tables loaded.
+    let kernel_slide = unsafe {
+        crate::mem::kaslr::randomize(
+            &requests::KERNEL_CMDLINE_REQUEST,
+            &requests::HHDM_REQUEST,
+            &requests::KERNEL_ADDR_REQUEST,
+            &requests::MEMORY_MAP_REQUEST,
+        )
+    };
+
+    (init as usize) + kernel_slide
+}
+
 #[allow(clippy::too_many_lines)]
 pub extern "C" fn init() -> ! {
     // This function is absolutely massive, and that's intentional. All of the code
     // within this function should be absolutely, definitely run ONLY ONCE. Writing
     // the code sequentially within one function easily ensures that will be the case.
 
-    // All limine feature requests (ensures they are not used after bootloader memory is reclaimed)
-    static BOOT_INFO_REQUEST: BootloaderInfoRequest = BootloaderInfoRequest::new();
-    static KERNEL_FILE_REQUEST: ExecutableFileRequest = ExecutableFileRequest::new();
-    static KERNEL_CMDLINE_REQUEST: ExecutableCmdlineRequest = ExecutableCmdlineRequest::new();
-    static KERNEL_ADDR_REQUEST: ExecutableAddressRequest = ExecutableAddressRequest::new();
-    static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
-    static MEMORY_MAP_REQUEST: MemoryMapRequest = MemoryMapRequest::new();
-    static RSDP_ADDRESS_REQUEST: RsdpRequest = RsdpRequest::new();
-    static MP_REQUEST: MpRequest = MpRequest::new().with_flags(RequestFlags::X2APIC);
     // Enable logging first, so we can get feedback on the entire init process.
     if crate::logging::UartLogger::init().is_err() {
         // Safety: Logging subsystem must be enabled to run / debug OS.
@@ -33,13 +121,18 @@ pub extern "C" fn init() -> ! {
         }
     }
 
-    // Safety: Function is run only once for this hardware thread.
-    unsafe {
-        #[cfg(target_arch = "x86_64")]
-        crate::arch::x86_64::configure_hwthread();
-    }
+    crate::timeline::phase("arch::configure_hwthread", || {
+        // Safety: Function is run only once for this hardware thread.
+        unsafe {
+            #[cfg(target_arch = "x86_64")]
+            crate::arch::x86_64::configure_hwthread();
+        }
+    });
+
+    // Safety: This is the kernel entry point, so bootloader memory is yet to be reclaimed.
+    let boot = unsafe { BootPhase::new() };
 
-    if let Some(boot_info) = BOOT_INFO_REQUEST.get_response() {
+    if let Some(boot_info) = boot.bootloader_info().get_response() {
         info!(
             "Bootloader Info     {} v{} (rev {})",
             boot_info.name(),
@@ -50,15 +143,50 @@ pub extern "C" fn init() -> ! {
         info!("Bootloader Info     UNKNOWN");
     }
 
-    crate::params::parse(&KERNEL_CMDLINE_REQUEST);
-    crate::panic::symbols::parse(&KERNEL_FILE_REQUEST);
-    crate::mem::Hhdm::init(&HHDM_REQUEST);
-    crate::mem::pmm::PhysicalMemoryManager::init(&MEMORY_MAP_REQUEST);
+    crate::timeline::phase("params::parse", || {
+        crate::params::parse(boot.kernel_cmdline())
+    });
 
-    crate::arch::x86_64::instructions::breakpoint();
+    if crate::mem::kaslr::is_enabled() {
+        debug!("Kernel slid by {:#X}", crate::mem::kaslr::kernel_slide());
+    } else {
+        debug!("Kernel address space layout randomization is disabled.");
+    }
+
+    crate::timeline::phase("symbols::parse", || {
+        crate::panic::symbols::parse(boot.kernel_file());
+    });
+    crate::timeline::phase("Hhdm::init", crate::mem::Hhdm::init);
+    crate::timeline::phase("PhysicalMemoryManager::init", || {
+        crate::mem::pmm::PhysicalMemoryManager::init(boot.memory_map());
+    });
+
+    // Shrinkers are run in order of registration, so the cheapest to lose come first.
+    crate::mem::reclaim::register_shrinker("pmm::frame_caches", |_| {
+        crate::mem::pmm::PhysicalMemoryManager::drain_frame_caches()
+    });
+    crate::mem::reclaim::register_shrinker("slab_alloc::magazines", |_| {
+        crate::mem::flush_slab_magazines()
+    });
+    crate::mem::reclaim::register_shrinker("heap_debug::quarantine", |_| {
+        crate::mem::drain_heap_quarantine()
+    });
+    crate::mem::reclaim::register_shrinker("panic::symbols", |_| {
+        crate::panic::symbols::drop_symbol_info()
+    });
+
+    // Heap allocations are possible now, so copy out the bootloader info for the boot report.
+    if let Some(boot_info) = boot.bootloader_info().get_response() {
+        crate::timeline::set_bootloader_info(
+            boot_info.name(),
+            boot_info.version(),
+            boot_info.revision(),
+        );
+    }
 
     // Set up various variables and structures for init to use.
-    let kernel_file = KERNEL_FILE_REQUEST
+    let kernel_file = boot
+        .kernel_file()
         .get_response()
         .map(limine::response::ExecutableFileResponse::file)
         .expect("no response to kernel file request");
@@ -69,7 +197,8 @@ pub extern "C" fn init() -> ! {
     let kernel_elf = elf::ElfBytes::<elf::endian::AnyEndian>::minimal_parse(kernel_file_mem)
         .expect("failed to parse kernel file into ELF binary");
     let (kernel_addr_phys, kernel_addr_virt) = {
-        let kernel_addr_response = KERNEL_ADDR_REQUEST
+        let kernel_addr_response = boot
+            .kernel_address()
             .get_response()
             .expect("no kernel address response");
         (
@@ -81,157 +210,177 @@ pub extern "C" fn init() -> ! {
     };
 
     /* SETUP KERNEL MEMORY */
-    // {
-    //     use crate::mem::{
-    //         hhdm,
-    //         paging::{TableDepth, TableEntryFlags},
-    //     };
-    //     use libsys::page_size;
-    //     use limine::memory_map::EntryType;
-
-    //     fn map_hhdm_range(
-    //         mapper: &mut crate::mem::mapper::Mapper,
-    //         mut range: core::ops::Range<usize>,
-    //         flags: TableEntryFlags,
-    //         lock_frames: bool,
-    //     ) {
-    //         let huge_page_depth = TableDepth::new(1).unwrap();
-
-    //         trace!("HHDM Map  {range:#X?}  {flags:?}   lock {lock_frames}");
-
-    //         while !range.is_empty() {
-    //             if range.len() > huge_page_depth.align()
-    //                 && range.start.trailing_zeros() >= huge_page_depth.align().trailing_zeros()
-    //             {
-    //                 // Map a huge page
-
-    //                 let frame = Address::new(range.start).unwrap();
-    //                 let page = hhdm::get().offset(frame).unwrap();
-    //                 range.advance_by(huge_page_depth.align()).unwrap();
-
-    //                 mapper
-    //                     .map(
-    //                         page,
-    //                         huge_page_depth,
-    //                         frame,
-    //                         lock_frames,
-    //                         flags | TableEntryFlags::HUGE,
-    //                     )
-    //                     .expect("failed to map range")
-    //             } else {
-    //                 // Map a standard page
-
-    //                 let frame = Address::new(range.start).unwrap();
-    //                 let page = hhdm::get().offset(frame).unwrap();
-    //                 range.advance_by(page_size()).unwrap();
-
-    //                 mapper
-    //                     .map(page, TableDepth::min(), frame, lock_frames, flags)
-    //                     .expect("failed to map range");
-    //             }
-    //         }
-    //     }
-
-    //     debug!("Preparing kernel memory system.");
-
-    //     /* load and map segments */
-    //     debug!("Mapping the higher-half direct map.");
-    //     crate::mem::with_kmapper(|kmapper| {
-    //         let mmap_entries = &mut memory_map.iter().map(|entry| {
-    //             let entry_start = usize::try_from(entry.base).unwrap();
-    //             let entry_end = usize::try_from(entry.base + entry.length).unwrap();
-
-    //             (entry_start..entry_end, entry.entry_type)
-    //         });
-
-    //         let mut last_end = 0;
-    //         while let Some((mut entry_range, entry_ty)) = mmap_entries.next() {
-    //             // collapse sequential matching entries
-    //             if let Some((end_range, _)) = mmap_entries
-    //                 .take_while(|(range, ty)| entry_range.end == range.start && entry_ty.eq(ty))
-    //                 .last()
-    //             {
-    //                 entry_range.end = end_range.end;
-    //             }
-
-    //             if entry_range.start > last_end {
-    //                 map_hhdm_range(
-    //                     kmapper,
-    //                     last_end..entry_range.start,
-    //                     TableEntryFlags::RW,
-    //                     true,
-    //                 );
-    //             }
-
-    //             last_end = entry_range.end;
-
-    //             let mmap_args = match entry_ty {
-    //                 EntryType::USABLE => Some((TableEntryFlags::RW, false)),
-
-    //                 EntryType::ACPI_NVS
-    //                 | EntryType::ACPI_RECLAIMABLE
-    //                 | EntryType::BOOTLOADER_RECLAIMABLE
-    //                 | EntryType::FRAMEBUFFER => Some((TableEntryFlags::RW, true)),
-
-    //                 EntryType::RESERVED | EntryType::EXECUTABLE_AND_MODULES => {
-    //                     Some((TableEntryFlags::RO, true))
-    //                 }
-
-    //                 EntryType::BAD_MEMORY => None,
-
-    //                 _ => unreachable!(),
-    //             };
-
-    //             if let Some((flags, lock_frames)) = mmap_args {
-    //                 map_hhdm_range(kmapper, entry_range, flags, lock_frames);
-    //             } else {
-    //                 trace!("HHDM Map (!! BAD MEMORY !!) @{entry_range:#X?}");
-    //             }
-    //         }
-
-    //         /* load kernel segments */
-    //         kernel_elf
-    //             .segments()
-    //             .expect("kernel file has no segments")
-    //             .into_iter()
-    //             .filter(|ph| ph.p_type == elf::abi::PT_LOAD)
-    //             .for_each(|phdr| {
-    //                 unsafe extern "C" {
-    //                     static KERNEL_BASE: libkernel::LinkerSymbol;
-    //                 }
-
-    //                 debug!("{phdr:X?}");
-
-    //                 // Safety: `KERNEL_BASE` is a linker symbol to an in-executable memory location, so it is guaranteed to be valid (and is never written to).
-    //                 let base_offset =
-    //                     usize::try_from(phdr.p_vaddr).unwrap() - unsafe { KERNEL_BASE.as_usize() };
-    //                 let base_offset_end = base_offset + usize::try_from(phdr.p_memsz).unwrap();
-    //                 let flags = crate::mem::paging::TableEntryFlags::from(
-    //                     crate::task::segment_to_mmap_permissions(phdr.p_flags),
-    //                 );
-
-    //                 (base_offset..base_offset_end)
-    //                     .step_by(page_size())
-    //                     // Attempt to map the page to the frame.
-    //                     .for_each(|offset| {
-    //                         let phys_addr = Address::new(kernel_addr_phys.get() + offset).unwrap();
-    //                         let virt_addr = Address::new(kernel_addr_virt.get() + offset).unwrap();
-
-    //                         trace!("Map  {virt_addr:X?} -> {phys_addr:X?}   {flags:?}");
-    //                         kmapper
-    //                             .map(virt_addr, TableDepth::min(), phys_addr, true, flags)
-    //                             .expect("failed to map kernel memory region");
-    //                     });
-    //             });
-
-    //         debug!("Switching to kernel page tables...");
-    //         // Safety: Kernel mappings should be identical to the bootloader mappings.
-    //         unsafe {
-    //             kmapper.swap_into();
-    //         }
-    //         debug!("Kernel has finalized control of page tables.");
-    //     });
-    // }
+    {
+        let _phase = crate::timeline::Phase::begin("init::kernel_memory");
+
+        use crate::mem::{
+            Hhdm, MIN_HHDM_END,
+            mapper::Mapper,
+            paging::{CacheMode, TableDepth, TableEntryFlags},
+        };
+        use libsys::{Page, page_shift, page_size};
+        use limine::memory_map::EntryType;
+
+        fn map_hhdm_range(
+            mapper: &mut Mapper,
+            range: core::ops::Range<usize>,
+            flags: TableEntryFlags,
+            cache_mode: CacheMode,
+        ) {
+            trace!("HHDM Map  {range:#X?}  {flags:?}  {cache_mode:?}");
+
+            // The mapper uses the largest pages that both the physical and virtual addresses are aligned to.
+            mapper
+                .map_range(
+                    Address::<Page>::new(Hhdm::offset().get() + range.start).unwrap(),
+                    Address::<Frame>::new(range.start).unwrap(),
+                    range.len() / page_size(),
+                    flags,
+                    cache_mode,
+                )
+                .expect("failed to map higher-half direct map range");
+        }
+
+        debug!("Preparing kernel memory system.");
+
+        let memory_map = boot
+            .memory_map()
+            .get_response()
+            .expect("no response to memory map request")
+            .entries();
+
+        crate::mem::with_kmapper(|kmapper| {
+            /* map the higher-half direct map */
+            debug!("Mapping the higher-half direct map.");
+
+            let mut last_end = 0;
+            for entry in memory_map {
+                let entry_start = usize::try_from(entry.base).unwrap();
+                let entry_end = usize::try_from(entry.base + entry.length).unwrap();
+
+                // Page-align the entry, ensuring it doesn't overlap the previously mapped entry.
+                let entry_start =
+                    usize::max(libsys::align_down(entry_start, page_shift()), last_end);
+                let entry_end = libsys::align_up_div(entry_end, page_shift()) * page_size();
+
+                if entry_start >= entry_end {
+                    continue;
+                }
+
+                // Map any holes in the memory map (these are typically MMIO).
+                if entry_start > last_end {
+                    map_hhdm_range(
+                        kmapper,
+                        last_end..entry_start,
+                        TableEntryFlags::RW,
+                        CacheMode::Uncached,
+                    );
+                }
+
+                last_end = entry_end;
+
+                let (flags, cache_mode) = match entry.entry_type {
+                    EntryType::USABLE
+                    | EntryType::BOOTLOADER_RECLAIMABLE
+                    | EntryType::ACPI_RECLAIMABLE
+                    | EntryType::ACPI_NVS => (TableEntryFlags::RW, CacheMode::WriteBack),
+
+                    // Framebuffer writes are combined, as they're otherwise very slow.
+                    EntryType::FRAMEBUFFER => (TableEntryFlags::RW, CacheMode::WriteCombining),
+
+                    // Reserved regions may contain device registers, which must not be cached.
+                    EntryType::RESERVED => (TableEntryFlags::RW, CacheMode::Uncached),
+
+                    EntryType::EXECUTABLE_AND_MODULES => {
+                        (TableEntryFlags::RO, CacheMode::WriteBack)
+                    }
+
+                    EntryType::BAD_MEMORY => {
+                        trace!(
+                            "HHDM Map (!! BAD MEMORY !!) @{:#X?}",
+                            entry_start..entry_end
+                        );
+                        continue;
+                    }
+
+                    _ => unreachable!(),
+                };
+
+                map_hhdm_range(kmapper, entry_start..entry_end, flags, cache_mode);
+            }
+
+            if last_end < MIN_HHDM_END {
+                map_hhdm_range(
+                    kmapper,
+                    last_end..MIN_HHDM_END,
+                    TableEntryFlags::RW,
+                    CacheMode::Uncached,
+                );
+            }
+
+            /* map the kernel segments */
+            debug!("Mapping the kernel executable segments.");
+
+            // Only the slid kernel is mapped, so its original address is unmapped once these tables are loaded.
+            let kernel_base = kernel_addr_virt.get() + crate::mem::kaslr::kernel_slide();
+
+            kernel_elf
+                .segments()
+                .expect("kernel file has no segments")
+                .iter()
+                .filter(|phdr| phdr.p_type == elf::abi::PT_LOAD)
+                .for_each(|phdr| {
+                    trace!("{phdr:X?}");
+
+                    let segment_offset =
+                        usize::try_from(phdr.p_vaddr).unwrap() - kernel_addr_virt.get();
+                    let segment_size = usize::try_from(phdr.p_memsz).unwrap();
+
+                    let offset_start = libsys::align_down(segment_offset, page_shift());
+                    let offset_end =
+                        libsys::align_up_div(segment_offset + segment_size, page_shift())
+                            * page_size();
+
+                    // Text is RX, read-only data is RO, and all writable data is RW+NX (W^X).
+                    let flags = TableEntryFlags::from(crate::task::segment_to_mmap_permissions(
+                        phdr.p_flags,
+                    )) | TableEntryFlags::GLOBAL;
+
+                    for offset in (offset_start..offset_end).step_by(page_size()) {
+                        let phys_addr =
+                            Address::<Frame>::new(kernel_addr_phys.get() + offset).unwrap();
+                        let virt_addr = Address::<Page>::new(kernel_base + offset).unwrap();
+
+                        trace!("Map  {virt_addr:X?} -> {phys_addr:X?}   {flags:?}");
+
+                        kmapper
+                            .map(
+                                virt_addr,
+                                TableDepth::min(),
+                                phys_addr,
+                                false,
+                                flags,
+                                CacheMode::WriteBack,
+                            )
+                            .expect("failed to map kernel memory region");
+                    }
+                });
+        });
+
+        debug!("Switching to kernel page tables...");
+        // Safety: The kernel is running from its slid mapping, and everything else it references is either
+        //         in the kernel's HHDM, or in the bootloader's (which the boot page tables keep mapped).
+        unsafe {
+            crate::mem::kaslr::load_boot_tables();
+        }
+        debug!("Kernel has finalized control of page tables.");
+
+        // Safety: The kernel page tables were just loaded, and application processors load them
+        //         as soon as they're configured.
+        unsafe {
+            crate::mem::vmalloc::set_available();
+        }
+    }
 
     /* PARSE ACPI TABLES */
     // {
@@ -250,126 +399,213 @@ pub extern "C" fn init() -> ! {
 
     // crate::mem::io::pci::init_devices().unwrap();
 
-    // load_drivers();
+    crate::timeline::phase("init::load_drivers", || load_drivers(&boot));
 
-    crate::cpu::start_mp(&MP_REQUEST);
+    let hwthread_count =
+        crate::timeline::phase("cpu::start_mp", || crate::cpu::start_mp(boot.mp()));
+    // Application processors run on bootloader-provided stacks & page tables until
+    // they check in, so they must all be online before bootloader memory is reclaimed.
+    crate::timeline::phase("cpu::wait_for_hwthreads", || {
+        crate::cpu::wait_for_hwthreads(hwthread_count);
+    });
 
-    // Drop into a finalizing function to lose all references
-    // to Limine bootloader requests/responses (they will be
-    // deallocated during reclamation of bootloader memory).
-    // finalize_init(memory_map)
-    todo!()
+    finalize_init(boot)
 }
 
 /// Finalizes the kernel init process. After entering this function, all bootloader
 /// reclaimable memory will be freed, and bootloader info/data will be inaccessible.
-fn finalize_init(memory_map: &[&memory_map::Entry]) -> ! {
-    debug!("Reclaiming bootloader memory...");
+fn finalize_init(boot: BootPhase) -> ! {
+    extern "sysv64" fn reclaim_and_run(reclaimable: *mut Vec<Range<usize>>) -> ! {
+        // Safety: Pointer was leaked from a `Box` by `finalize_init`.
+        let reclaimable = unsafe { Box::from_raw(reclaimable) };
+
+        // Safety: Every hardware thread is off of its bootloader-provided stack, and the boot phase is over,
+        //         so nothing within the bootloader's HHDM is referenced. Application processors loaded the
+        //         kernel page tables before coming online.
+        unsafe {
+            crate::mem::kaslr::load_kernel_tables();
+            crate::mem::kaslr::free_boot_tables();
+        }
+
+        crate::timeline::phase("init::reclaim_bootloader_memory", || {
+            debug!("Reclaiming bootloader memory...");
 
-    memory_map
+            reclaimable
+                .iter()
+                .flat_map(|range| range.clone().step_by(libsys::page_size()))
+                .map(|address| Address::<Frame>::new(address).unwrap())
+                .for_each(|frame| PhysicalMemoryManager::free_frame(frame).unwrap());
+
+            debug!("Bootloader memory reclaimed.");
+        });
+
+        if crate::params::drop_symbol_info() {
+            crate::panic::symbols::drop_symbol_info();
+        }
+
+        drop(reclaimable);
+
+        crate::timeline::report();
+        crate::mem::log_slab_stats();
+
+        if crate::params::selftest() {
+            crate::selftest::run();
+        }
+
+        // Safety: We've reached the end of the kernel init phase.
+        unsafe { crate::cpu::run() }
+    }
+
+    // The memory map itself resides in bootloader-reclaimable memory, so copy out the regions to reclaim.
+    let reclaimable = boot
+        .memory_map()
+        .get_response()
+        .expect("no response to memory map request")
+        .entries()
         .iter()
         .filter(|entry| entry.entry_type == limine::memory_map::EntryType::BOOTLOADER_RECLAIMABLE)
-        .flat_map(|entry| {
+        .map(|entry| {
             let entry_start = usize::try_from(entry.base).unwrap();
             let entry_end = usize::try_from(entry.base + entry.length).unwrap();
 
-            (entry_start..entry_end).step_by(libsys::page_size())
+            entry_start..entry_end
         })
-        .map(|address| Address::<Frame>::new(address).unwrap())
-        .for_each(|frame| PhysicalMemoryManager::free_frame(frame).unwrap());
+        .collect::<Vec<_>>();
+
+    // Consume the boot phase token, so bootloader responses are inaccessible from here on.
+    drop(boot);
+
+    // Safety: The boot stack is itself bootloader-reclaimable memory, so we must switch off of
+    //         it before reclaiming. Nothing on the current stack is referenced after the switch.
+    unsafe { crate::cpu::switch_stack(reclaim_and_run, Box::into_raw(Box::new(reclaimable))) }
+}
+
+#[derive(Debug, Error)]
+pub enum DriverError {
+    #[error("failed to read drivers archive")]
+    Archive(#[from] crate::tar::Error),
+
+    #[error("failed to parse driver ELF")]
+    Elf(#[from] elf::ParseError),
+
+    #[error("driver ELF has no program segments")]
+    NoSegments,
+
+    #[error("driver ELF has no section headers")]
+    NoSectionHeaders,
+
+    #[error("driver ELF contains an unsupported relocation type: {0:#X}")]
+    UnsupportedRelocation(u32),
+
+    #[error("driver ELF relocation value overflowed")]
+    RelocationOverflow,
+}
+
+/// Unpacks each driver ELF from the bootloader-provided "drivers" module, and pushes
+/// a task for each into the global task queue.
+fn load_drivers(boot: &BootPhase) {
+    use crate::tar::Archive;
+
+    debug!("Unpacking kernel drivers...");
+
+    let Some(modules) = boot.modules().get_response() else {
+        warn!("Bootloader provided no modules; skipping driver loading.");
+        return;
+    };
+
+    let Some(drivers_module) = modules
+        .modules()
+        .iter()
+        .find(|module| module.path().ends_with(b"drivers"))
+    else {
+        warn!("Bootloader provided no drivers module; skipping driver loading.");
+        return;
+    };
+
+    // Safety: Bootloader guarantees the address and size of the module will be correct.
+    let archive_data = unsafe {
+        core::slice::from_raw_parts(
+            drivers_module.addr(),
+            drivers_module.size().try_into().unwrap(),
+        )
+    };
+
+    for entry in Archive::new(archive_data).entries() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(error) => {
+                error!("Failed to read drivers archive: {error}");
+                break;
+            }
+        };
+
+        debug!("Loading driver: {}", entry.name());
+
+        match load_driver(entry.data()) {
+            Ok(task) => {
+                trace!("Driver loaded; pushing task: {:?}", task.id());
 
-    debug!("Bootloader memory reclaimed.");
+                crate::task::PROCESSES.lock().push_back(task);
+            }
 
-    // Safety: We've reached the end of the kernel init phase.
-    unsafe { crate::cpu::run() }
+            Err(error) => error!("Failed to load driver {:?}: {error}", entry.name()),
+        }
+    }
 }
 
-// fn load_drivers() {
-//     use crate::task::{AddressSpace, Priority, Task};
-//     use elf::endian::AnyEndian;
-
-//     #[limine::limine_tag]
-//     static LIMINE_MODULES: limine::ModuleRequest = limine::ModuleRequest::new(crate::init::boot::LIMINE_REV);
-
-//     debug!("Unpacking kernel drivers...");
-
-//     let Some(modules) = LIMINE_MODULES.get_response() else {
-//         warn!("Bootloader provided no modules; skipping driver loading.");
-//         return;
-//     };
-
-//     let modules = modules.modules();
-//     trace!("Found modules: {:X?}", modules);
-
-//     let Some(drivers_module) = modules.iter().find(|module| module.path().ends_with("drivers")) else {
-//         panic!("no drivers module found")
-//     };
-
-//     let archive = tar_no_std::TarArchiveRef::new(drivers_module.data());
-//     archive
-//         .entries()
-//         .filter_map(|entry| {
-//             debug!("Attempting to parse driver blob: {}", entry.filename());
-
-//             match elf::ElfBytes::<AnyEndian>::minimal_parse(entry.data()) {
-//                 Ok(elf) => Some((entry, elf)),
-//                 Err(err) => {
-//                     error!("Failed to parse driver blob into ELF: {:?}", err);
-//                     None
-//                 }
-//             }
-//         })
-//         .for_each(|(entry, elf)| {
-//             // Get and copy the ELF segments into a small box.
-//             let Some(segments_copy) = elf.segments().map(|segments| segments.into_iter().collect()) else {
-//                 error!("ELF has no segments.");
-//                 return;
-//             };
-
-//             // Safety: In-place transmutation of initialized bytes for the purpose of copying safely.
-//             // let (_, archive_data, _) = unsafe { entry.data().align_to::<MaybeUninit<u8>>() };
-//             trace!("Allocating ELF data into memory...");
-//             let elf_data = alloc::boxed::Box::from(entry.data());
-//             trace!("ELF data allocated into memory.");
-
-//             let Ok((Some(shdrs), Some(_))) = elf.section_headers_with_strtab() else {
-//                 panic!("Error retrieving ELF relocation metadata.")
-//             };
-
-//             let load_offset = crate::task::MIN_LOAD_OFFSET;
-
-//             trace!("Processing relocations localized to fault page.");
-//             let mut relas = alloc::vec::Vec::with_capacity(shdrs.len());
-
-//             shdrs
-//                 .iter()
-//                 .filter(|shdr| shdr.sh_type == elf::abi::SHT_RELA)
-//                 .flat_map(|shdr| elf.section_data_as_relas(&shdr).unwrap())
-//                 .for_each(|rela| {
-//                     use crate::task::ElfRela;
-
-//                     match rela.r_type {
-//                         elf::abi::R_X86_64_RELATIVE => relas.push(ElfRela {
-//                             address: Address::new(usize::try_from(rela.r_offset).unwrap()).unwrap(),
-//                             value: load_offset + usize::try_from(rela.r_addend).unwrap(),
-//                         }),
-
-//                         _ => unimplemented!(),
-//                     }
-//                 });
-
-//             trace!("Finished processing relocations, pushing task.");
-
-//             let task = Task::new(
-//                 Priority::Normal,
-//                 AddressSpace::new_userspace(),
-//                 load_offset,
-//                 elf.ehdr,
-//                 segments_copy,
-//                 relas,
-//                 crate::task::ElfData::Memory(elf_data),
-//             );
-
-//             crate::task::PROCESSES.lock().push_back(task);
-//         });
-// }
+/// Parses a driver ELF blob into a new userspace task.
+fn load_driver(data: &[u8]) -> Result<crate::task::Task, DriverError> {
+    use crate::task::{AddressSpace, ElfData, ElfRela, MIN_LOAD_OFFSET, Priority, Task};
+    use elf::{ElfBytes, endian::AnyEndian};
+
+    let elf = ElfBytes::<AnyEndian>::minimal_parse(data)?;
+
+    // Get and copy the ELF segments into a small box.
+    let segments = elf
+        .segments()
+        .ok_or(DriverError::NoSegments)?
+        .iter()
+        .collect::<Box<[_]>>();
+
+    let shdrs = elf.section_headers().ok_or(DriverError::NoSectionHeaders)?;
+
+    let load_offset = MIN_LOAD_OFFSET;
+
+    trace!("Processing relocations...");
+    let mut relas = Vec::new();
+    for shdr in shdrs
+        .iter()
+        .filter(|shdr| shdr.sh_type == elf::abi::SHT_RELA)
+    {
+        for rela in elf.section_data_as_relas(&shdr)? {
+            match rela.r_type {
+                elf::abi::R_X86_64_NONE => {}
+
+                elf::abi::R_X86_64_RELATIVE => relas.push(ElfRela {
+                    address: Address::new(usize::try_from(rela.r_offset).unwrap())
+                        .ok_or(DriverError::RelocationOverflow)?,
+                    value: isize::try_from(rela.r_addend)
+                        .ok()
+                        .and_then(|addend| load_offset.checked_add_signed(addend))
+                        .ok_or(DriverError::RelocationOverflow)?,
+                }),
+
+                r_type => return Err(DriverError::UnsupportedRelocation(r_type)),
+            }
+        }
+    }
+
+    trace!("Copying ELF data into memory...");
+    // Large copies are placed in the kernel virtual area, so they needn't be physically contiguous.
+    let elf_data = Box::from(data);
+
+    Ok(Task::new(
+        Priority::Normal,
+        AddressSpace::new_userspace(),
+        load_offset,
+        elf.ehdr,
+        segments,
+        relas,
+        ElfData::Memory(elf_data),
+    ))
+}
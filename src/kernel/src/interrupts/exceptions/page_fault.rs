@@ -8,6 +8,9 @@ pub enum Error {
 
     #[error("failed to deman map memory")]
     Task(#[from] crate::task::Error),
+
+    #[error("accessed an unmapped page of the kernel virtual area (e.g. a guard page) at {0:X?}")]
+    KernelVirtual(Address<Virtual>),
 }
 
 /// ## Safety
@@ -17,6 +20,11 @@ pub enum Error {
 #[doc(hidden)]
 #[inline(never)]
 pub unsafe fn handler(fault_address: Address<Virtual>) -> Result<(), Error> {
+    // Every allocation in the kernel virtual area is mapped up-front, so this is always an overrun.
+    if crate::mem::vmalloc::contains(fault_address) {
+        return Err(Error::KernelVirtual(fault_address));
+    }
+
     crate::cpu::state::with_scheduler(|scheduler| {
         scheduler
             .task_mut()
@@ -28,3 +36,26 @@ pub unsafe fn handler(fault_address: Address<Virtual>) -> Result<(), Error> {
 
     Ok(())
 }
+
+crate::kernel_test! {
+    fn page_fault_reports_guard_page() {
+        use crate::cpu::state::EXCEPTION_STACK_SIZE;
+
+        let stack_top = crate::cpu::allocate_stack(EXCEPTION_STACK_SIZE);
+        // Safety: The stack is `EXCEPTION_STACK_SIZE` bytes.
+        let stack_bottom = unsafe { stack_top.sub(EXCEPTION_STACK_SIZE) };
+        // Pushing past the bottom of the stack writes to its guard page.
+        let overflow_address =
+            Address::<Virtual>::new(stack_bottom.addr().get() - size_of::<u64>()).unwrap();
+
+        // Safety: Faults within the kernel virtual area are reported before any task is touched.
+        let result = unsafe { handler(overflow_address) };
+
+        // Safety: The stack was never used.
+        unsafe { crate::mem::vmalloc::free(stack_bottom) }.map_err(|err| alloc::format!("{err}"))?;
+
+        crate::ktest_assert_eq!(result, Err(Error::KernelVirtual(overflow_address)));
+
+        Ok(())
+    }
+}
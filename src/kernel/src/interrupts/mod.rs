@@ -49,7 +49,8 @@ pub enum Vector {
     Timer = 0x30,
     Thermal = 0x32,
     Performance = 0x33,
-    /* 0x34..=0x3B free for use */
+    TlbShootdown = 0x34,
+    /* 0x35..=0x3B free for use */
     Error = 0x3C,
     LINT0 = 0x3D,
     LINT1 = 0x3E,
@@ -1,4 +1,5 @@
 use crate::{arch::x86_64::structures::idt::InterruptStackFrame, task::Registers};
+use libkernel::mem::MEMORY_STATS_VECTOR;
 use libsys::syscall::{Error, Result, Success, Vector};
 
 #[allow(clippy::too_many_arguments)]
@@ -18,6 +19,8 @@ pub fn process(
     );
 
     let result = match Vector::try_from(vector) {
+        Err(_) if vector == MEMORY_STATS_VECTOR => process_memory_stats(arg0, arg1),
+
         Err(err) => {
             warn!("Unhandled system call vector: {err:X?}");
             Err(Error::InvalidVector)
@@ -45,20 +48,22 @@ pub fn process(
     result
 }
 
-fn process_klog(level: log::Level, str_ptr_arg: usize, str_len: usize) -> Result {
-    let str_ptr = str_ptr_arg as *mut u8;
-
-    // TODO abstract this into a function
+/// Ensures the memory in `start..(start + len)` is mapped into the active task's address
+/// space (and if `writable`, that it can be written to).
+fn demand_map_user_range(start: usize, len: usize, writable: bool) -> Result {
     crate::cpu::state::with_scheduler(|scheduler| {
-        use crate::task::Error as TaskError;
+        use crate::{mem::paging::TableEntryFlags, task::Error as TaskError};
         use libsys::{Address, page_size};
 
-        let str_start = str_ptr.addr();
-        let str_end = str_start + str_len;
+        let end = start.checked_add(len).ok_or(Error::UnmappedMemory)?;
+        if end > crate::task::DEFAULT_USERSPACE_SIZE.get() {
+            return Err(Error::UnmappedMemory);
+        }
 
         let task = scheduler.task_mut().ok_or(Error::NoActiveTask)?;
-        for address in (str_start..str_end)
+        for address in (start..end)
             .step_by(page_size() / 2)
+            .chain(end.checked_sub(1).filter(|_| len > 0))
             .map(Address::new_truncate)
         {
             match task.demand_map(address) {
@@ -69,10 +74,27 @@ fn process_klog(level: log::Level, str_ptr_arg: usize, str_len: usize) -> Result
                     return Err(Error::UnmappedMemory);
                 }
             }
+
+            if writable {
+                let flags = task
+                    .address_space()
+                    .get_flags(Address::new_truncate(address.get()))
+                    .map_err(|_| Error::UnmappedMemory)?;
+
+                if !flags.contains(TableEntryFlags::WRITABLE) {
+                    return Err(Error::UnmappedMemory);
+                }
+            }
         }
 
         Ok(Success::Ok)
-    })?;
+    })
+}
+
+fn process_klog(level: log::Level, str_ptr_arg: usize, str_len: usize) -> Result {
+    let str_ptr = str_ptr_arg as *mut u8;
+
+    demand_map_user_range(str_ptr.addr(), str_len, false)?;
 
     // Safety: TODO
     let str_slice = unsafe { core::slice::from_raw_parts(str_ptr, str_len) };
@@ -82,3 +104,38 @@ fn process_klog(level: log::Level, str_ptr_arg: usize, str_len: usize) -> Result
 
     Ok(Success::Ok)
 }
+
+fn process_memory_stats(buffer_ptr_arg: usize, buffer_len: usize) -> Result {
+    use crate::mem::pmm::{FrameKind, PhysicalMemoryManager};
+
+    let to_u64 = |value: usize| u64::try_from(value).unwrap();
+
+    let stats = PhysicalMemoryManager::memory_stats();
+    let user_stats = libkernel::mem::MemoryStats {
+        frame_size: to_u64(libsys::page_size()),
+        total_frames: to_u64(stats.total_frames),
+        free_frames: to_u64(stats.free_frames),
+        used_frames: to_u64(stats.used_frames),
+        reserved_frames: to_u64(stats.reserved_frames),
+        kernel_frames: to_u64(stats.frames_of(FrameKind::Kernel)),
+        kernel_heap_frames: to_u64(stats.frames_of(FrameKind::KernelHeap)),
+        page_table_frames: to_u64(stats.frames_of(FrameKind::PageTable)),
+        user_frames: to_u64(stats.frames_of(FrameKind::UserAnon)),
+        dma_frames: to_u64(stats.frames_of(FrameKind::Dma)),
+        mmio_frames: to_u64(stats.frames_of(FrameKind::Mmio)),
+        framebuffer_frames: to_u64(stats.frames_of(FrameKind::Framebuffer)),
+        ..libkernel::mem::MemoryStats::new()
+    };
+
+    // Copy as much of the struct as the caller has room for (see `MemoryStats`'s versioning).
+    let stats_bytes = user_stats.as_bytes();
+    let copy_len = usize::min(buffer_len, stats_bytes.len());
+    demand_map_user_range(buffer_ptr_arg, copy_len, true)?;
+
+    // Safety: The buffer has been checked to lie within the task's address space, and to be writable.
+    unsafe {
+        core::ptr::copy_nonoverlapping(stats_bytes.as_ptr(), buffer_ptr_arg as *mut u8, copy_len);
+    }
+
+    Ok(Success::Ok)
+}
@@ -73,8 +73,11 @@ mod mem;
 mod panic;
 mod params;
 mod rand;
+mod selftest;
+mod tar;
 mod task;
 mod time;
+mod timeline;
 
 #[macro_use]
 extern crate bitflags;
@@ -104,15 +107,17 @@ static STACK_SIZE_REQUEST: limine::request::StackSizeRequest =
 #[doc(hidden)]
 #[allow(clippy::too_many_lines)]
 unsafe extern "C" fn _entry() -> ! {
-    // Safety: We've just entered the kernel, so no state can be disrupted.
+    // Safety: We've just entered the kernel, so no state can be disrupted. The layout is randomized first,
+    //         then `init` is called wherever the kernel executable was moved to.
     unsafe {
         core::arch::asm!(
             "
             xor rbp, rbp
 
             call {}
+            call rax
             ",
-            sym init::init,
+            sym init::randomize_layout,
             options(noreturn)
         )
     }
@@ -1,70 +1,302 @@
-use crate::mem::{hhdm::Hhdm, pmm::PhysicalMemoryManager};
-use core::{alloc::Layout, num::NonZeroUsize};
+use crate::{
+    interrupts::InterruptCell,
+    mem::{
+        heap_debug,
+        hhdm::Hhdm,
+        pmm::{FrameKind, PhysicalMemoryManager},
+        vmalloc,
+    },
+};
+use alloc::string::String;
+use core::{
+    alloc::{AllocError, Allocator, Layout},
+    fmt::Write,
+    num::{NonZeroU32, NonZeroUsize},
+    ptr::NonNull,
+};
 use libsys::{Address, page_shift, page_size};
+use slab_alloc::{CLASS_COUNT, ClassStats, SlabAllocator};
 
-#[global_allocator]
-static GLOBAL_ALLOCATOR: KernelAllocator = KernelAllocator;
+/// Allocations spanning at least this many frames are made in the kernel virtual area once it's
+/// available, as physically contiguous runs of frames become scarce as memory fragments.
+const VMALLOC_THRESHOLD: usize = 8;
 
-pub struct KernelAllocator;
+/// Minimum size of the slabs small allocations are carved from.
+const SLAB_SIZE: NonZeroUsize = NonZeroUsize::new(page_size()).unwrap();
 
-// Safety: Implemented with Correct:tm: logic.
-unsafe impl core::alloc::GlobalAlloc for KernelAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        assert!(layout.align() <= page_size());
+/// Allocates layouts of up to [`slab_alloc::MAX_BLOCK_SIZE`] bytes from slabs, and anything else as whole frames.
+///
+/// Once a hardware thread's core-local state is initialized, its small allocations go through its slab
+/// magazines, which only lock a size class to refill or flush them. The magazines are only touched with
+/// interrupts disabled (within `InterruptCell::with`), so an interrupt handler never uses them while
+/// they're in use. They are used re-entrantly when reclaiming memory for a new slab frees heap memory,
+/// which `SlabAllocator` allows for by never borrowing them across a call into the frame allocator.
+static SLAB_ALLOCATOR: InterruptCell<SlabAllocator<FrameAllocator>> =
+    InterruptCell::new(SlabAllocator::new_in(SLAB_SIZE, FrameAllocator));
 
-        let frame_count = libsys::align_up_div(layout.size(), page_shift());
+/// Snapshot of the counters of each slab size class.
+pub fn slab_stats() -> [ClassStats; CLASS_COUNT] {
+    SLAB_ALLOCATOR.with(SlabAllocator::stats)
+}
 
-        let alloc_result = {
-            match frame_count {
-                0 => unreachable!(
-                    "Did not expect `0` from: `libsys::align_up_div({}, {})`",
-                    layout.size(),
-                    page_shift()
-                ),
+/// Returns the blocks cached in the current hardware thread's slab magazines to their size classes,
+/// returning the number of frames freed from slabs this leaves empty.
+///
+/// # Remark
+///
+/// Magazines are unsynchronized, so other hardware threads' magazines are left as they are.
+pub fn flush_slab_magazines() -> usize {
+    SLAB_ALLOCATOR.with(|slab_allocator| {
+        crate::cpu::state::slab_magazines().map_or(0, |magazines| {
+            // Safety: Interrupts are disabled, and the magazines belong to this hardware thread.
+            unsafe { slab_allocator.flush_local(magazines) / page_size() }
+        })
+    })
+}
 
-                1 => PhysicalMemoryManager::next_frame(),
+/// Prints a table of the counters of each slab size class to the log.
+pub fn log_slab_stats() {
+    let mut table = String::new();
 
-                frame_count => PhysicalMemoryManager::next_frames(
-                    // Safety: `frame_count` is already checked to be `0`.
-                    unsafe { NonZeroUsize::new_unchecked(frame_count) },
-                    None,
-                ),
-            }
+    writeln!(table, "Slab Allocator").unwrap();
+    writeln!(
+        table,
+        "  {: >8}{: >12}{: >12}{: >10}{: >14}{: >14}",
+        "BLOCK", "ACTIVE", "HIGH WATER", "SLABS", "ALLOCS", "FREES"
+    )
+    .unwrap();
+
+    for stats in slab_stats() {
+        writeln!(
+            table,
+            "  {: >8}{: >12}{: >12}{: >10}{: >14}{: >14}",
+            stats.block_size,
+            stats.active_objects,
+            stats.high_water,
+            stats.slabs,
+            stats.allocs,
+            stats.frees
+        )
+        .unwrap();
+    }
+
+    info!("{table}");
+}
+
+#[global_allocator]
+static GLOBAL_ALLOCATOR: KernelAllocator = KernelAllocator;
+
+/// Allocates physically contiguous runs of frames (accessed through the HHDM), aligned to at least
+/// the page size.
+pub struct FrameAllocator;
+
+// Safety: Frames are only handed out once by the physical memory manager, until they're freed.
+unsafe impl Allocator for FrameAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let frame_count = libsys::align_up_div(layout.size().max(1), page_shift());
+        let align_bits = if layout.align() > page_size() {
+            Some(
+                u32::try_from(layout.align())
+                    .ok()
+                    .and_then(NonZeroU32::new)
+                    .ok_or(AllocError)?,
+            )
+        } else {
+            None
+        };
+
+        let alloc_result = match (frame_count, align_bits) {
+            (1, None) => PhysicalMemoryManager::next_frame(FrameKind::KernelHeap),
+
+            (frame_count, align_bits) => PhysicalMemoryManager::next_frames(
+                NonZeroUsize::new(frame_count).unwrap(),
+                align_bits,
+                FrameKind::KernelHeap,
+            ),
         };
 
         match alloc_result {
             Ok(frame_address) => {
                 trace!("Allocation: {frame_address:?}:{frame_count}");
 
-                core::ptr::without_provenance_mut(Hhdm::offset().get() + frame_address.get().get())
+                let ptr = core::ptr::without_provenance_mut(
+                    Hhdm::offset().get() + frame_address.get().get(),
+                );
+
+                Ok(NonNull::slice_from_raw_parts(
+                    NonNull::new(ptr).unwrap(),
+                    frame_count * page_size(),
+                ))
             }
 
-            Err(crate::mem::pmm::Error::NoneFree) => core::ptr::null_mut(),
+            Err(crate::mem::pmm::Error::NoneFree) => Err(AllocError),
 
             Err(error) => panic!("unresolvable allocation error: {error:?}"),
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        assert!(layout.align() <= page_size());
-
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
         // Calculate the physical (rather than virtual) memory offset of the pointer.
-        let phys_offset = ptr.addr() - Hhdm::offset().get();
-        let phys_offset_aligned = libsys::align_down(phys_offset, page_shift());
-        let frame_address = Address::new(phys_offset_aligned).unwrap();
+        let phys_offset = ptr.addr().get() - Hhdm::offset().get();
+        let frame_address = Address::<libsys::Frame>::new(phys_offset).unwrap();
 
-        if layout.size() <= page_size() {
-            PhysicalMemoryManager::free_frame(frame_address).ok();
+        let frame_count = libsys::align_up_div(layout.size().max(1), page_shift());
+        let frames_start = frame_address.index();
+        let frames_end = frames_start + frame_count;
+
+        (frames_start..frames_end)
+            .map(Address::from_index)
+            .map(Option::unwrap)
+            .try_for_each(PhysicalMemoryManager::free_frame)
+            .expect("failed while freeing frames");
+    }
+}
+
+pub struct KernelAllocator;
+
+// Safety: Implemented with Correct:tm: logic.
+unsafe impl core::alloc::GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if heap_debug::is_enabled() {
+            heap_debug::allocate(layout)
+        } else {
+            allocate(layout)
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if heap_debug::is_enabled() {
+            // Safety: Caller is required to ensure the pointer was returned by `alloc` with this layout, and is
+            //         no longer used.
+            unsafe { heap_debug::deallocate(ptr, layout) };
         } else {
-            let frame_count = libsys::align_up_div(layout.size(), page_shift());
-            let frames_start = frame_address.index();
-            let frames_end = frames_start + frame_count;
-
-            (frames_start..frames_end)
-                .map(Address::from_index)
-                .map(Option::unwrap)
-                .try_for_each(PhysicalMemoryManager::free_frame)
-                .expect("failed while freeing frames");
+            // Safety: See above.
+            unsafe { deallocate(ptr, layout) };
         }
     }
 }
+
+/// Allocates `layout` from the kernel virtual area or the slab allocator.
+pub(super) fn allocate(layout: Layout) -> *mut u8 {
+    let frame_count = libsys::align_up_div(layout.size(), page_shift());
+
+    if frame_count >= VMALLOC_THRESHOLD && layout.align() <= page_size() && vmalloc::is_available()
+    {
+        // Safety: `frame_count` is at least `VMALLOC_THRESHOLD`.
+        let page_count = unsafe { NonZeroUsize::new_unchecked(frame_count) };
+
+        return match vmalloc::allocate(page_count) {
+            Ok(allocation) => {
+                trace!("Virtual allocation: {allocation:?}");
+
+                allocation.as_mut_ptr()
+            }
+
+            Err(error) => {
+                trace!("Virtual allocation failed: {error}");
+
+                core::ptr::null_mut()
+            }
+        };
+    }
+
+    SLAB_ALLOCATOR
+        .with(|slab_allocator| match crate::cpu::state::slab_magazines() {
+            // Safety: Interrupts are disabled, and the magazines belong to this hardware thread.
+            Some(magazines) => unsafe { slab_allocator.allocate_local(magazines, layout) },
+            None => slab_allocator.allocate(layout),
+        })
+        .map_or(core::ptr::null_mut(), NonNull::as_mut_ptr)
+}
+
+/// ## Safety
+///
+/// `ptr` must have been returned by [`allocate`] with `layout`, and is no longer used.
+pub(super) unsafe fn deallocate(ptr: *mut u8, layout: Layout) {
+    if vmalloc::contains(Address::from_ptr(ptr)) {
+        // Safety: Caller is required to ensure the pointer was returned by `allocate`, and is no longer used.
+        unsafe { vmalloc::free(NonNull::new(ptr).unwrap()) }
+            .expect("failed to free virtual allocation");
+
+        return;
+    }
+
+    let ptr = NonNull::new(ptr).unwrap();
+    SLAB_ALLOCATOR.with(|slab_allocator| match crate::cpu::state::slab_magazines() {
+        // Safety: Caller is required to ensure the pointer was returned by `allocate` with this layout, and is
+        //         no longer used. Interrupts are disabled, and the magazines belong to this hardware thread.
+        Some(magazines) => unsafe { slab_allocator.deallocate_local(magazines, ptr, layout) },
+        // Safety: Caller is required to ensure the pointer was returned by `allocate` with this layout, and is
+        //         no longer used.
+        None => unsafe { slab_allocator.deallocate(ptr, layout) },
+    });
+}
+
+crate::kernel_test! {
+    fn slab_small_allocations() {
+        // Allocations are made directly, as heap debugging mode would pad them with redzones.
+        let layout = Layout::new::<usize>();
+        let mut allocations = alloc::vec::Vec::with_capacity(256);
+
+        let free_frames = PhysicalMemoryManager::free_frames();
+        // Far more small allocations than would fit in the frames they'd previously have used.
+        for index in 0..256usize {
+            let ptr = NonNull::new(allocate(layout)).ok_or("allocation failed")?.cast::<usize>();
+            // Safety: The allocation is valid for writes of `layout`.
+            unsafe { ptr.write(index) };
+            allocations.push(ptr);
+        }
+        crate::ktest_assert!(PhysicalMemoryManager::free_frames() + 16 > free_frames);
+        // Safety: Each allocation was written above.
+        crate::ktest_assert!(
+            allocations.iter().enumerate().all(|(index, ptr)| unsafe { ptr.read() } == index)
+        );
+
+        for ptr in allocations {
+            // Safety: The allocation was made with this layout, and is no longer used.
+            unsafe { deallocate(ptr.as_ptr().cast(), layout) };
+        }
+
+        Ok(())
+    }
+}
+
+crate::kernel_test! {
+    fn slab_stats_count_allocations() {
+        // Allocations are made directly, as heap debugging mode would move them to larger classes, and
+        // defer their frees.
+        let layout = Layout::new::<[u8; 128]>();
+        let class_of = |stats: &[ClassStats]| {
+            stats.iter().find(|stats| stats.block_size == 128).copied().unwrap()
+        };
+
+        let before = class_of(&slab_stats());
+        let allocations = (0..8).map(|_| allocate(layout)).collect::<alloc::vec::Vec<_>>();
+        let during = class_of(&slab_stats());
+        for &ptr in &allocations {
+            // Safety: The allocation was made with this layout, and is no longer used.
+            unsafe { deallocate(ptr, layout) };
+        }
+        let after = class_of(&slab_stats());
+
+        crate::ktest_assert!(allocations.iter().all(|ptr| !ptr.is_null()));
+        crate::ktest_assert!(during.allocs >= before.allocs + 8);
+        crate::ktest_assert!(during.high_water >= during.active_objects);
+        crate::ktest_assert!(after.frees >= before.frees + 8);
+
+        Ok(())
+    }
+}
+
+crate::kernel_test! {
+    fn frame_allocator_large_alignment() {
+        let layout = Layout::from_size_align(page_size(), 0x20_0000).unwrap();
+        let allocation = FrameAllocator.allocate(layout).map_err(|_| "allocation failed")?;
+        crate::ktest_assert_eq!(allocation.as_non_null_ptr().addr().get() % layout.align(), 0);
+
+        // Safety: The allocation was just made with this layout.
+        unsafe { FrameAllocator.deallocate(allocation.as_non_null_ptr(), layout) };
+
+        Ok(())
+    }
+}
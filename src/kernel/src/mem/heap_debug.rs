@@ -0,0 +1,585 @@
+//! Heap debugging mode, which checks the kernel allocators' callers for heap corruption.
+//!
+//! Each allocation is laid out as:
+//!
+//! ```text
+//! | header | front redzone | prefix | data | rear redzone |
+//! ```
+//!
+//! The header records the allocation's layout and call site, and the prefix leads back to it from the
+//! data pointer. Redzones are filled with a pattern that's checked when the allocation is freed, which
+//! catches writes out of bounds, and `dealloc` checks the layout it's given against the one recorded.
+//!
+//! Freed data is poisoned and held in a quarantine, so its header remembers the free (and a double free
+//! can be detected) until enough later frees push it out. The poison is checked once it leaves the
+//! quarantine, which catches writes made after the free. The quarantine is bounded by both the number
+//! and the total size of the allocations it holds, and is drained when memory is reclaimed.
+//!
+//! A violation is reported with the call sites of the allocation and (prior) free, followed by a panic.
+//!
+//! The mode is enabled by default in debug builds, and with the `heapdebug` command line argument
+//! otherwise (see [`crate::params`]).
+
+use crate::interrupts::InterruptCell;
+use core::{alloc::Layout, ptr::NonNull};
+use libsys::Address;
+use spin::{Mutex, Once};
+
+/// Number of return addresses recorded for each allocation and free.
+const TRACE_DEPTH: usize = 6;
+
+/// Minimum size of the redzones either side of an allocation's data.
+const REDZONE_SIZE: usize = 16;
+const REDZONE_BYTE: u8 = 0xFB;
+const POISON_BYTE: u8 = 0xDF;
+
+/// Most freed allocations held back from the allocators.
+const QUARANTINE_LEN: usize = 512;
+/// Most bytes of freed allocations (including their headers and redzones) held back from the allocators.
+const QUARANTINE_SIZE: usize = 0x4_0000;
+
+/// Mixed into the prefix, so a data pointer which isn't the start of an allocation is unlikely to lead
+/// back to a header.
+const PREFIX_MAGIC: usize = 0x4845_4150_4442_4721;
+
+const STATE_ALLOCATED: u64 = 0xA110_CA7E_D0D0_CAFE;
+const STATE_FREED: u64 = 0xF4EE_D0D0_DEAD_BEEF;
+
+type Trace = [usize; TRACE_DEPTH];
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    state: u64,
+    size: usize,
+    align: usize,
+    alloc_trace: Trace,
+    free_trace: Trace,
+}
+
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+enum Violation {
+    #[error("pointer isn't the start of an allocation, or its front redzone was overwritten")]
+    InvalidPointer,
+
+    #[error("allocation was already freed")]
+    DoubleFree,
+
+    #[error("allocation was freed with size {size} and alignment {align}")]
+    LayoutMismatch { size: usize, align: usize },
+
+    #[error("front redzone was overwritten")]
+    FrontRedzone,
+
+    #[error("rear redzone was overwritten")]
+    RearRedzone,
+
+    #[error("allocation was written to after it was freed")]
+    UseAfterFree,
+}
+
+/// Ring of the most recently freed allocations, with the size of each.
+struct Quarantine {
+    allocations: [Option<(NonNull<Header>, usize)>; QUARANTINE_LEN],
+    /// Index of the oldest allocation.
+    oldest: usize,
+    len: usize,
+    size: usize,
+}
+
+// Safety: Type has no thread-local references.
+unsafe impl Send for Quarantine {}
+
+impl Quarantine {
+    const fn new() -> Self {
+        Self {
+            allocations: [None; QUARANTINE_LEN],
+            oldest: 0,
+            len: 0,
+            size: 0,
+        }
+    }
+
+    /// Quarantines the allocation of `header` (which is `size` bytes in all), returning the oldest
+    /// allocation if the quarantine is full.
+    fn push(&mut self, header: NonNull<Header>, size: usize) -> Option<NonNull<Header>> {
+        let oldest = if self.len == QUARANTINE_LEN {
+            self.pop_oldest()
+        } else {
+            None
+        };
+
+        self.allocations[(self.oldest + self.len) % QUARANTINE_LEN] = Some((header, size));
+        self.len += 1;
+        self.size += size;
+
+        oldest
+    }
+
+    fn pop_oldest(&mut self) -> Option<NonNull<Header>> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let (header, size) = self.allocations[self.oldest].take().unwrap();
+        self.oldest = (self.oldest + 1) % QUARANTINE_LEN;
+        self.len -= 1;
+        self.size -= size;
+
+        Some(header)
+    }
+
+    /// Takes the oldest allocation for which `predicate` holds, keeping the order of the rest.
+    fn pop_oldest_where(
+        &mut self,
+        predicate: impl Fn(NonNull<Header>) -> bool,
+    ) -> Option<NonNull<Header>> {
+        let slot = |offset| (self.oldest + offset) % QUARANTINE_LEN;
+
+        let offset =
+            (0..self.len).find(|&offset| predicate(self.allocations[slot(offset)].unwrap().0))?;
+        let (header, size) = self.allocations[slot(offset)].take().unwrap();
+
+        // Close the gap by moving the newer allocations down.
+        for offset in offset..(self.len - 1) {
+            self.allocations[slot(offset)] = self.allocations[slot(offset + 1)].take();
+        }
+
+        self.len -= 1;
+        self.size -= size;
+
+        Some(header)
+    }
+
+    /// Takes the oldest allocation, if the quarantine holds more than [`QUARANTINE_SIZE`] bytes.
+    fn pop_oversized(&mut self) -> Option<NonNull<Header>> {
+        if self.size > QUARANTINE_SIZE {
+            self.pop_oldest()
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether heap debugging is enabled. This is latched by the first allocation, as allocations made in
+/// one mode can't be freed in the other.
+static ENABLED: Once<bool> = Once::new();
+
+static QUARANTINE: InterruptCell<Mutex<Quarantine>> =
+    InterruptCell::new(Mutex::new(Quarantine::new()));
+
+pub(super) fn is_enabled() -> bool {
+    *ENABLED.call_once(crate::params::heap_debug)
+}
+
+/// Smallest offset of the data from the start of an allocation, which leaves room for the header, a
+/// whole front redzone, and the prefix.
+const MIN_DATA_OFFSET: usize = size_of::<Header>() + REDZONE_SIZE + size_of::<usize>();
+
+/// Offset of the data from the start of an allocation with the given alignment.
+const fn data_offset(align: usize) -> usize {
+    MIN_DATA_OFFSET.next_multiple_of(align)
+}
+
+/// Layout of the whole allocation which holds the data of `layout`.
+fn debug_layout(layout: Layout) -> Option<Layout> {
+    let size = data_offset(layout.align())
+        .checked_add(layout.size())?
+        .checked_add(REDZONE_SIZE)?;
+
+    Layout::from_size_align(size, layout.align().max(align_of::<Header>())).ok()
+}
+
+/// Writes the header, prefix and redzones of an allocation at `base`, returning its data pointer.
+///
+/// ## Safety
+///
+/// `base` must be valid for writes of, and aligned to, `debug_layout(layout)`.
+unsafe fn init_allocation(base: NonNull<u8>, layout: Layout, alloc_trace: Trace) -> NonNull<u8> {
+    let data_offset = data_offset(layout.align());
+
+    // Safety: Caller is required to ensure the whole allocation is valid for writes. The prefix is
+    //         aligned, as the data offset is a multiple of the header's alignment.
+    unsafe {
+        base.cast::<Header>().write(Header {
+            state: STATE_ALLOCATED,
+            size: layout.size(),
+            align: layout.align(),
+            alloc_trace,
+            free_trace: [0; TRACE_DEPTH],
+        });
+
+        let data = base.add(data_offset);
+        let front_redzone = base.add(size_of::<Header>());
+        front_redzone.write_bytes(
+            REDZONE_BYTE,
+            data_offset - size_of::<Header>() - size_of::<usize>(),
+        );
+        data.cast::<usize>()
+            .sub(1)
+            .write(base.addr().get() ^ PREFIX_MAGIC);
+        data.add(layout.size())
+            .write_bytes(REDZONE_BYTE, REDZONE_SIZE);
+
+        data
+    }
+}
+
+/// Finds the header of the allocation `data` points to, and checks it's live, was allocated with
+/// `layout`, and its redzones are intact.
+///
+/// ## Safety
+///
+/// `data` must have been returned by [`allocate`] (though it may have been freed since).
+unsafe fn check_allocation(
+    data: NonNull<u8>,
+    layout: Layout,
+) -> Result<NonNull<Header>, (Violation, Option<Header>)> {
+    // Safety: Caller is required to ensure the pointer was returned by `allocate`, so it follows a prefix.
+    let prefix = unsafe { data.cast::<usize>().sub(1).read_unaligned() };
+    let base_addr = prefix ^ PREFIX_MAGIC;
+    let offset = data.addr().get().wrapping_sub(base_addr);
+
+    if base_addr % align_of::<Header>() != 0
+        || !(MIN_DATA_OFFSET..=libsys::page_size().max(layout.align())).contains(&offset)
+    {
+        return Err((Violation::InvalidPointer, None));
+    }
+
+    // Safety: The prefix leads back to the allocation's base (and so its header).
+    let header_ptr = unsafe { data.sub(offset) }.cast::<Header>();
+    // Safety: See above.
+    let header = unsafe { header_ptr.read() };
+
+    match header.state {
+        STATE_ALLOCATED => {}
+        STATE_FREED => return Err((Violation::DoubleFree, Some(header))),
+        _ => return Err((Violation::InvalidPointer, None)),
+    }
+
+    if !header.align.is_power_of_two() || data_offset(header.align) != offset {
+        return Err((Violation::InvalidPointer, None));
+    }
+
+    if header.size != layout.size() || header.align != layout.align() {
+        return Err((
+            Violation::LayoutMismatch {
+                size: layout.size(),
+                align: layout.align(),
+            },
+            Some(header),
+        ));
+    }
+
+    // Safety: The redzones lie within the allocation, which the header's layout describes.
+    let (front_redzone, rear_redzone) = unsafe {
+        (
+            core::slice::from_raw_parts(
+                header_ptr.add(1).cast::<u8>().as_ptr(),
+                offset - size_of::<Header>() - size_of::<usize>(),
+            ),
+            core::slice::from_raw_parts(data.add(header.size).as_ptr(), REDZONE_SIZE),
+        )
+    };
+
+    if !front_redzone.iter().all(|&byte| byte == REDZONE_BYTE) {
+        Err((Violation::FrontRedzone, Some(header)))
+    } else if !rear_redzone.iter().all(|&byte| byte == REDZONE_BYTE) {
+        Err((Violation::RearRedzone, Some(header)))
+    } else {
+        Ok(header_ptr)
+    }
+}
+
+/// Records the free of the allocation of `header_ptr`, and poisons its data.
+///
+/// ## Safety
+///
+/// `header_ptr` must have been returned by [`check_allocation`].
+unsafe fn mark_freed(mut header_ptr: NonNull<Header>, free_trace: Trace) {
+    // Safety: Caller is required to ensure the header is valid.
+    let header = unsafe { header_ptr.as_mut() };
+    header.state = STATE_FREED;
+    header.free_trace = free_trace;
+
+    // Safety: The data lies within the allocation, which the header's layout describes.
+    unsafe {
+        header_ptr
+            .cast::<u8>()
+            .add(data_offset(header.align))
+            .write_bytes(POISON_BYTE, header.size);
+    }
+}
+
+/// Whether the allocation of `header_ptr` is still freed, and its data still poisoned.
+///
+/// ## Safety
+///
+/// `header_ptr` must have been passed to [`mark_freed`].
+unsafe fn is_poisoned(header_ptr: NonNull<Header>) -> bool {
+    // Safety: Caller is required to ensure the header is valid.
+    let header = unsafe { header_ptr.as_ref() };
+
+    // Safety: The data lies within the allocation, which the header's layout describes.
+    let data = unsafe {
+        core::slice::from_raw_parts(
+            header_ptr
+                .cast::<u8>()
+                .add(data_offset(header.align))
+                .as_ptr(),
+            header.size,
+        )
+    };
+
+    header.state == STATE_FREED && data.iter().all(|&byte| byte == POISON_BYTE)
+}
+
+fn report(violation: Violation, data: NonNull<u8>, header: Option<&Header>) -> ! {
+    fn print_trace(trace: &Trace) {
+        crate::panic::print_trace(
+            trace
+                .iter()
+                .take_while(|&&address| address != 0)
+                .map(|&address| Address::new_truncate(address)),
+        );
+    }
+
+    error!("HEAP VIOLATION at {data:p}: {violation}");
+
+    if let Some(header) = header {
+        error!(
+            "Allocated with size {} and alignment {}, at:",
+            header.size, header.align
+        );
+        print_trace(&header.alloc_trace);
+
+        if header.state == STATE_FREED {
+            error!("Freed at:");
+            print_trace(&header.free_trace);
+        }
+    }
+
+    // The panic's stack trace shows where the violation was detected.
+    panic!("heap violation: {violation}")
+}
+
+/// Allocates `layout` (surrounded by redzones) from the kernel allocators.
+pub(super) fn allocate(layout: Layout) -> *mut u8 {
+    let Some(debug_layout) = debug_layout(layout) else {
+        return core::ptr::null_mut();
+    };
+
+    let mut alloc_trace = [0; TRACE_DEPTH];
+    crate::panic::capture_trace(&mut alloc_trace);
+
+    match NonNull::new(super::global_alloc::allocate(debug_layout)) {
+        // Safety: The allocation was just made with the debug layout.
+        Some(base) => unsafe { init_allocation(base, layout, alloc_trace) }.as_ptr(),
+        None => core::ptr::null_mut(),
+    }
+}
+
+/// Checks the poison of an allocation leaving the quarantine, and returns it to the kernel allocators.
+///
+/// ## Safety
+///
+/// `header_ptr` must have been quarantined, and is no longer referenced by the quarantine.
+unsafe fn release(header_ptr: NonNull<Header>) {
+    // Safety: Quarantined allocations were marked freed.
+    let header = unsafe { header_ptr.read() };
+    // Safety: The data lies within the allocation, which the header's layout describes.
+    let data = unsafe { header_ptr.cast::<u8>().add(data_offset(header.align)) };
+
+    // Safety: Quarantined allocations were marked freed.
+    if !unsafe { is_poisoned(header_ptr) } {
+        report(Violation::UseAfterFree, data, Some(&header));
+    }
+
+    let layout = Layout::from_size_align(header.size, header.align).unwrap();
+    // Safety: The allocation was made with the debug layout, and is no longer referenced by the quarantine.
+    unsafe {
+        super::global_alloc::deallocate(header_ptr.cast(), debug_layout(layout).unwrap());
+    }
+}
+
+/// Checks and quarantines the allocation `ptr` points to, returning the oldest quarantined allocations to
+/// the kernel allocators.
+///
+/// ## Safety
+///
+/// `ptr` must have been returned by [`allocate`], and is no longer used.
+pub(super) unsafe fn deallocate(ptr: *mut u8, layout: Layout) {
+    let data = NonNull::new(ptr).unwrap();
+
+    let mut free_trace = [0; TRACE_DEPTH];
+    crate::panic::capture_trace(&mut free_trace);
+
+    // The allocation is checked and marked freed under the quarantine's lock, so concurrent frees of the
+    // same allocation are caught.
+    let released = QUARANTINE.with(|quarantine| {
+        let mut quarantine = quarantine.lock();
+
+        // Safety: Caller is required to ensure the pointer was returned by `allocate`.
+        let header_ptr = unsafe { check_allocation(data, layout) }?;
+        // Safety: The allocation was just checked.
+        unsafe { mark_freed(header_ptr, free_trace) };
+
+        Ok(quarantine.push(header_ptr, debug_layout(layout).unwrap().size()))
+    });
+
+    match released {
+        // Safety: The allocation was just pushed out of the quarantine.
+        Ok(Some(header_ptr)) => unsafe { release(header_ptr) },
+        Ok(None) => {}
+        Err((violation, header)) => report(violation, data, header.as_ref()),
+    }
+
+    while let Some(header_ptr) = QUARANTINE.with(|quarantine| quarantine.lock().pop_oversized()) {
+        // Safety: The allocation was just taken from the quarantine.
+        unsafe { release(header_ptr) };
+    }
+}
+
+/// Returns the quarantined allocations to the kernel allocators, returning the number of frames this
+/// frees (which is only an estimate, as other hardware threads may allocate or free frames meanwhile).
+///
+/// # Remark
+///
+/// Shrinkers may run while the kernel mapper is locked, and freeing a kernel virtual allocation locks it,
+/// so allocations within the kernel virtual area are left in the quarantine.
+pub fn drain_heap_quarantine() -> usize {
+    let free_frames = crate::mem::pmm::PhysicalMemoryManager::free_frames();
+
+    let is_releasable = |header_ptr: NonNull<Header>| {
+        !crate::mem::vmalloc::contains(Address::new_truncate(header_ptr.addr().get()))
+    };
+
+    while let Some(header_ptr) =
+        QUARANTINE.with(|quarantine| quarantine.lock().pop_oldest_where(is_releasable))
+    {
+        // Safety: The allocation was just taken from the quarantine.
+        unsafe { release(header_ptr) };
+    }
+
+    crate::mem::pmm::PhysicalMemoryManager::free_frames().saturating_sub(free_frames)
+}
+
+crate::kernel_test! {
+    fn heap_debug_detects_violations() {
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let debug_layout = debug_layout(layout).ok_or("debug layout overflowed")?;
+
+        let mut buffer = alloc::vec![0u64; debug_layout.size().div_ceil(size_of::<u64>())];
+        let base = NonNull::new(buffer.as_mut_ptr()).unwrap().cast::<u8>();
+
+        // Safety: The buffer holds the whole allocation, and is aligned to its header.
+        let data = unsafe { init_allocation(base, layout, [0; TRACE_DEPTH]) };
+        let check = |layout| {
+            // Safety: The data pointer was returned by `init_allocation`.
+            unsafe { check_allocation(data, layout) }.map_err(|(violation, _)| violation)
+        };
+
+        let header_ptr = check(layout).map_err(|_| "allocation is invalid")?;
+        crate::ktest_assert_eq!(header_ptr.cast::<u8>(), base);
+        // An underrun of `REDZONE_SIZE` bytes (past the prefix) is still within the front redzone.
+        // Safety: The front redzone is within the buffer.
+        let front_redzone_start = unsafe { data.sub(size_of::<usize>() + REDZONE_SIZE) };
+        // Safety: See above.
+        unsafe { front_redzone_start.write(0) };
+        crate::ktest_assert_eq!(check(layout).err(), Some(Violation::FrontRedzone));
+        // Safety: See above.
+        unsafe { front_redzone_start.write(REDZONE_BYTE) };
+
+        crate::ktest_assert_eq!(
+            check(Layout::from_size_align(32, 8).unwrap()).err(),
+            Some(Violation::LayoutMismatch { size: 32, align: 8 })
+        );
+
+        // Safety: The rear redzone is within the buffer.
+        unsafe { data.add(layout.size()).write(0) };
+        crate::ktest_assert_eq!(check(layout).err(), Some(Violation::RearRedzone));
+        // Safety: See above.
+        unsafe { data.add(layout.size()).write(REDZONE_BYTE) };
+
+        // Safety: The allocation was checked above.
+        unsafe { mark_freed(header_ptr, [0; TRACE_DEPTH]) };
+        crate::ktest_assert_eq!(check(layout).err(), Some(Violation::DoubleFree));
+
+        // Safety: The allocation was marked freed above.
+        crate::ktest_assert!(unsafe { is_poisoned(header_ptr) });
+        // Safety: The data is within the buffer.
+        unsafe { data.write(0) };
+        // Safety: See above.
+        crate::ktest_assert!(!unsafe { is_poisoned(header_ptr) });
+
+        Ok(())
+    }
+}
+
+crate::kernel_test! {
+    fn heap_debug_quarantines_frees() {
+        let layout = Layout::new::<[u8; 128]>();
+        // The redzones move the allocation to a larger size class.
+        let debug_size = debug_layout(layout).ok_or("debug layout overflowed")?.size();
+        let class_of = |stats: &[slab_alloc::ClassStats]| {
+            stats.iter().find(|stats| stats.block_size >= debug_size).copied().unwrap()
+        };
+
+        let before = class_of(&super::slab_stats());
+        let allocations = (0..8).map(|_| allocate(layout)).collect::<alloc::vec::Vec<_>>();
+        let during = class_of(&super::slab_stats());
+
+        crate::ktest_assert!(allocations.iter().all(|ptr| !ptr.is_null()));
+        crate::ktest_assert!(during.block_size > layout.size());
+        crate::ktest_assert!(during.allocs >= before.allocs + 8);
+
+        for &ptr in &allocations {
+            // Safety: The allocation was made with this layout, and is no longer used.
+            unsafe { deallocate(ptr, layout) };
+        }
+
+        // Freed allocations are held in the quarantine, poisoned, rather than returned to the allocators.
+        crate::ktest_assert!(allocations.iter().all(|&ptr| {
+            // Safety: The allocation is quarantined, so its header is still valid.
+            unsafe { is_poisoned(NonNull::new(ptr.sub(data_offset(layout.align()))).unwrap().cast()) }
+        }));
+
+        Ok(())
+    }
+}
+
+crate::kernel_test! {
+    fn heap_debug_quarantine_bounds() {
+        let mut quarantine = alloc::boxed::Box::new(Quarantine::new());
+        // The headers are never read by the quarantine itself.
+        let headers = (1..=QUARANTINE_LEN + 1)
+            .map(|index| NonNull::without_provenance(core::num::NonZeroUsize::new(index).unwrap()))
+            .collect::<alloc::vec::Vec<_>>();
+
+        // Bounded by count...
+        for &header in &headers[..QUARANTINE_LEN] {
+            crate::ktest_assert_eq!(quarantine.push(header, 1), None);
+        }
+        crate::ktest_assert_eq!(quarantine.push(headers[QUARANTINE_LEN], 1), Some(headers[0]));
+        crate::ktest_assert_eq!(quarantine.pop_oversized(), None);
+
+        // ... and by size.
+        while quarantine.pop_oldest().is_some() {}
+        crate::ktest_assert_eq!(quarantine.push(headers[0], QUARANTINE_SIZE / 2), None);
+        crate::ktest_assert_eq!(quarantine.push(headers[1], QUARANTINE_SIZE / 2), None);
+        crate::ktest_assert_eq!(quarantine.pop_oversized(), None);
+        crate::ktest_assert_eq!(quarantine.push(headers[2], 1), None);
+        crate::ktest_assert_eq!(quarantine.pop_oversized(), Some(headers[0]));
+        crate::ktest_assert_eq!(quarantine.pop_oversized(), None);
+
+        // Allocations can be taken from the middle, without reordering the rest.
+        crate::ktest_assert_eq!(quarantine.pop_oldest_where(|header| header == headers[2]), Some(headers[2]));
+        crate::ktest_assert_eq!(quarantine.pop_oldest_where(|header| header == headers[2]), None);
+        crate::ktest_assert_eq!(quarantine.push(headers[3], 1), None);
+        crate::ktest_assert_eq!(quarantine.pop_oldest(), Some(headers[1]));
+        crate::ktest_assert_eq!(quarantine.pop_oldest(), Some(headers[3]));
+        crate::ktest_assert_eq!(quarantine.pop_oldest(), None);
+
+        Ok(())
+    }
+}
@@ -1,5 +1,9 @@
 use core::num::NonZero;
 
+/// The HHDM is always mapped to cover at least the first 4 GiB of physical memory,
+/// as firmware and devices commonly place MMIO regions there without a memory map entry.
+pub const MIN_HHDM_END: usize = 0x1_0000_0000;
+
 static HHDM: spin::Once<Hhdm> = spin::Once::new();
 
 #[repr(transparent)]
@@ -7,17 +11,15 @@ static HHDM: spin::Once<Hhdm> = spin::Once::new();
 pub struct Hhdm(NonZero<usize>);
 
 impl Hhdm {
-    pub fn init(hhdm_request: &limine::request::HhdmRequest) {
+    pub fn init() {
         HHDM.call_once(|| {
-            // Zero-based memory offset of the start of the HHDM.
-            let hhdm_offset = hhdm_request
-                .get_response()
-                .expect("bootloader did not provide response to higher-half direct map request")
-                .offset();
+            // Zero-based memory offset of the start of the HHDM, which may have been moved from where the
+            // bootloader placed it.
+            let hhdm_offset = crate::mem::kaslr::hhdm_offset();
 
             debug!("HHDM @ {hhdm_offset:#X}");
 
-            Hhdm(NonZero::new(usize::try_from(hhdm_offset).unwrap()).unwrap())
+            Hhdm(NonZero::new(hhdm_offset).unwrap())
         });
     }
 
@@ -0,0 +1,456 @@
+//! Kernel address space layout randomization.
+//!
+//! Limine loads the kernel at its link address (`KASLR=no`), so the kernel randomizes its own layout
+//! before anything else runs:
+//! - The kernel executable is aliased at a random 2 MiB-aligned slide, and its relative relocations are
+//!   reapplied for that slide.
+//! - The HHDM is aliased at a random run of free root table entries.
+//! - The kernel virtual area (which holds kernel stacks and large heap allocations) begins at a random
+//!   offset within its root table entry.
+//!
+//! Each alias is made by copying page table entries within the bootloader's page tables, so nothing has
+//! to be allocated to make it. The kernel page tables only map the randomized layout, though the
+//! bootloader's HHDM stays reachable (see [`load_boot_tables`]) until bootloader memory is reclaimed.
+//!
+//! Randomization is disabled by the `nokaslr` command line argument, which is checked here directly, as
+//! this runs long before the command line is parsed.
+
+use crate::mem::{
+    mapper::Mapper,
+    paging::{PageTableEntry, TableDepth},
+    pmm::PhysicalMemoryManager,
+};
+use core::ops::Range;
+use libsys::{Address, Frame, Virtual, table_index_size};
+use limine::request::{
+    ExecutableAddressRequest, ExecutableCmdlineRequest, HhdmRequest, MemoryMapRequest,
+};
+use spin::{Mutex, Once};
+
+/// Link address of the kernel executable. This must match `KERNEL_BASE` in the linker script.
+const KERNEL_BASE: usize = 0xFFFF_FFFF_8000_0000;
+
+const R_X86_64_NONE: u64 = 0;
+const R_X86_64_RELATIVE: u64 = 8;
+
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    enabled: bool,
+    kernel_slide: usize,
+    hhdm_offset: usize,
+    boot_hhdm_offset: usize,
+    /// Number of root table entries the HHDM spans.
+    hhdm_entries: usize,
+    vmalloc_offset: usize,
+}
+
+static LAYOUT: Once<Layout> = Once::new();
+
+/// Page tables loaded until bootloader memory is reclaimed, if the HHDM was moved.
+static BOOT_MAPPER: Mutex<Option<Mapper>> = Mutex::new(None);
+
+/// An `Elf64_Rela` entry, as found in the kernel's dynamic relocation table.
+#[repr(C)]
+struct Rela {
+    offset: u64,
+    info: u64,
+    addend: i64,
+}
+
+impl Rela {
+    const fn kind(&self) -> u64 {
+        self.info & 0xFFFF_FFFF
+    }
+}
+
+unsafe extern "C" {
+    static __rela_start: Rela;
+    static __rela_end: Rela;
+    static __executable_end: u8;
+}
+
+fn layout() -> &'static Layout {
+    LAYOUT.get().expect("kernel layout has not been randomized")
+}
+
+/// Whether the layout was randomized (i.e. `nokaslr` wasn't passed).
+pub fn is_enabled() -> bool {
+    layout().enabled
+}
+
+/// Offset of the kernel executable from the address the bootloader loaded it at.
+pub fn kernel_slide() -> usize {
+    layout().kernel_slide
+}
+
+/// Virtual address the kernel's HHDM begins at.
+pub fn hhdm_offset() -> usize {
+    layout().hhdm_offset
+}
+
+/// Offset of the kernel virtual area from the start of its root table entry.
+pub fn vmalloc_offset() -> usize {
+    layout().vmalloc_offset
+}
+
+/// Moves a pointer into the bootloader's HHDM (e.g. from a bootloader response) into the kernel's HHDM,
+/// so it remains valid after bootloader memory is reclaimed.
+pub fn rebase_boot_pointer<T>(ptr: *const T) -> *const T {
+    let layout = layout();
+
+    ptr.map_addr(|address| address - layout.boot_hhdm_offset + layout.hhdm_offset)
+}
+
+/// Randomizes the kernel's layout, returning the slide applied to the kernel executable. The kernel is
+/// mapped both at its original address and at the slide afterwards, though only code running at the slide
+/// will remain mapped once the kernel page tables are loaded.
+///
+/// ## Safety
+///
+/// - Must be called only once, by the kernel entry point, with the bootloader's page tables loaded.
+/// - No absolute address within the kernel executable may have been stored yet.
+pub unsafe fn randomize(
+    cmdline_request: &ExecutableCmdlineRequest,
+    hhdm_request: &HhdmRequest,
+    kernel_address_request: &ExecutableAddressRequest,
+    memory_map_request: &MemoryMapRequest,
+) -> usize {
+    let boot_hhdm_offset = usize::try_from(
+        hhdm_request
+            .get_response()
+            .expect("bootloader did not provide response to higher-half direct map request")
+            .offset(),
+    )
+    .unwrap();
+
+    let enabled = !cmdline_request
+        .get_response()
+        .and_then(|response| response.cmdline().to_str().ok())
+        .is_some_and(|cmdline| crate::params::arguments(cmdline).any(|(key, _)| key == "nokaslr"));
+
+    let layout = LAYOUT.call_once(|| {
+        let mut layout = Layout {
+            enabled,
+            kernel_slide: 0,
+            hhdm_offset: boot_hhdm_offset,
+            boot_hhdm_offset,
+            hhdm_entries: 0,
+            vmalloc_offset: 0,
+        };
+
+        if enabled {
+            // Safety: Caller is required to ensure the bootloader's page tables are loaded, and that nothing
+            //         has stored an absolute kernel address yet.
+            unsafe {
+                randomize_hhdm(&mut layout, memory_map_request);
+                randomize_kernel(&mut layout, kernel_address_request);
+            }
+
+            let entry_size = TableDepth::max().next().align();
+            let granularity = TableDepth::new(2).unwrap().align();
+            // The kernel virtual area keeps at least half of its root table entry.
+            layout.vmalloc_offset = random_index((entry_size / 2) / granularity) * granularity;
+        }
+
+        layout
+    });
+
+    layout.kernel_slide
+}
+
+/// Random index within `0..count`.
+fn random_index(count: usize) -> usize {
+    usize::try_from(crate::rand::prng::next_u64() % u64::try_from(count).unwrap()).unwrap()
+}
+
+/// Picks a random start for a run of `len` indexes within `within`, for which `is_free` holds for each index.
+fn random_run(within: Range<usize>, len: usize, is_free: impl Fn(usize) -> bool) -> Option<usize> {
+    let last_start = within.end.checked_sub(len)?;
+    let starts =
+        || (within.start..=last_start).filter(|&start| (start..(start + len)).all(&is_free));
+
+    match starts().count() {
+        0 => None,
+        count => starts().nth(random_index(count)),
+    }
+}
+
+/// Start address of the higher half root table entry at `index`.
+fn root_entry_address(index: usize) -> usize {
+    let entry_size = TableDepth::max().next().align();
+    // Addresses in the higher half are sign-extended from the root table's span.
+    let sign_extension = !((entry_size * table_index_size()) - 1);
+
+    sign_extension | (index * entry_size)
+}
+
+/// ## Safety
+///
+/// `frame` must be a page table within the bootloader's HHDM.
+unsafe fn boot_table(
+    boot_hhdm_offset: usize,
+    frame: Address<Frame>,
+) -> &'static mut [PageTableEntry] {
+    // Safety: Caller is required to ensure the frame is a page table within the bootloader's HHDM.
+    unsafe {
+        core::slice::from_raw_parts_mut(
+            core::ptr::with_exposed_provenance_mut(boot_hhdm_offset + frame.get().get()),
+            table_index_size(),
+        )
+    }
+}
+
+/// Walks the loaded page tables to the table at `depth` which maps `address`.
+///
+/// ## Safety
+///
+/// The bootloader's page tables must be loaded.
+unsafe fn boot_table_of(
+    boot_hhdm_offset: usize,
+    address: Address<Virtual>,
+    depth: TableDepth,
+) -> Option<&'static mut [PageTableEntry]> {
+    let mut table_depth = TableDepth::max();
+    // Safety: Caller is required to ensure the bootloader's page tables are loaded.
+    let mut table =
+        unsafe { boot_table(boot_hhdm_offset, crate::mem::PagingRegister::read().frame()) };
+
+    while table_depth > depth {
+        let entry = table[table_depth.index_of(address)?];
+        if !entry.is_present() || entry.is_huge() {
+            return None;
+        }
+
+        // Safety: Present non-leaf entries point to page tables, which the bootloader maps in its HHDM.
+        table = unsafe { boot_table(boot_hhdm_offset, entry.get_frame()) };
+        table_depth = table_depth.next();
+    }
+
+    Some(table)
+}
+
+/// Aliases the bootloader's HHDM at a random run of free higher half root entries.
+///
+/// ## Safety
+///
+/// The bootloader's page tables must be loaded.
+unsafe fn randomize_hhdm(layout: &mut Layout, memory_map_request: &MemoryMapRequest) {
+    let entry_size = TableDepth::max().next().align();
+    let hhdm_end = memory_map_request
+        .get_response()
+        .expect("no response to memory map request")
+        .entries()
+        .iter()
+        .map(|entry| usize::try_from(entry.base + entry.length).unwrap())
+        .fold(crate::mem::MIN_HHDM_END, usize::max);
+
+    let boot_hhdm_address = Address::new(layout.boot_hhdm_offset).unwrap();
+    let boot_index = TableDepth::max().index_of(boot_hhdm_address).unwrap();
+    let entry_offset = layout.boot_hhdm_offset % entry_size;
+    let entry_count = (entry_offset + hhdm_end).div_ceil(entry_size);
+
+    // Safety: Caller is required to ensure the bootloader's page tables are loaded.
+    let root_table = unsafe {
+        boot_table(
+            layout.boot_hhdm_offset,
+            crate::mem::PagingRegister::read().frame(),
+        )
+    };
+
+    // The last two root entries hold the kernel virtual area and the kernel executable.
+    let candidates = (table_index_size() / 2)..(table_index_size() - 2);
+    let Some(index) = random_run(candidates, entry_count, |index| {
+        !root_table[index].is_present()
+    }) else {
+        return;
+    };
+
+    for offset in 0..entry_count {
+        root_table[index + offset] = root_table[boot_index + offset];
+    }
+
+    layout.hhdm_offset = root_entry_address(index) + entry_offset;
+    layout.hhdm_entries = entry_count;
+}
+
+/// Aliases the kernel executable at a random slide, and reapplies its relocations for that slide.
+///
+/// ## Safety
+///
+/// - The bootloader's page tables must be loaded.
+/// - No absolute address within the kernel executable may have been stored yet.
+unsafe fn randomize_kernel(layout: &mut Layout, kernel_address_request: &ExecutableAddressRequest) {
+    let kernel_address = kernel_address_request
+        .get_response()
+        .expect("no kernel address response");
+    let kernel_phys = usize::try_from(kernel_address.physical_base()).unwrap();
+    let kernel_virt = usize::try_from(kernel_address.virtual_base()).unwrap();
+    let kernel_end = (&raw const __executable_end).addr();
+
+    let relas = {
+        let start = &raw const __rela_start;
+        let end = &raw const __rela_end;
+
+        // Safety: The linker script places `__rela_start` and `__rela_end` at either end of the
+        //         dynamic relocation table.
+        unsafe {
+            core::slice::from_raw_parts(start, usize::try_from(end.offset_from(start)).unwrap())
+        }
+    };
+
+    // Static PIE executables should only contain relative relocations, but the kernel can't be moved if not.
+    if relas
+        .iter()
+        .any(|rela| !matches!(rela.kind(), R_X86_64_NONE | R_X86_64_RELATIVE))
+    {
+        return;
+    }
+
+    // The slide is made in whole directory entries, so only those entries need to be copied.
+    let slide_align = TableDepth::new(1).unwrap().align();
+    let directory_depth = TableDepth::new(2).unwrap();
+    let kernel_address = Address::new(kernel_virt).unwrap();
+    let kernel_index = directory_depth.index_of(kernel_address).unwrap();
+    let entry_count =
+        ((kernel_virt % slide_align) + (kernel_end - kernel_virt)).div_ceil(slide_align);
+
+    // Safety: Caller is required to ensure the bootloader's page tables are loaded.
+    let Some(directory) =
+        (unsafe { boot_table_of(layout.boot_hhdm_offset, kernel_address, directory_depth) })
+    else {
+        return;
+    };
+
+    // Only sliding upwards keeps the kernel within the top 2 GiB, which its code model requires.
+    let candidates = (kernel_index + entry_count)..table_index_size();
+    let Some(index) = random_run(candidates, entry_count, |index| {
+        !directory[index].is_present()
+    }) else {
+        return;
+    };
+
+    for offset in 0..entry_count {
+        directory[index + offset] = directory[kernel_index + offset];
+    }
+
+    let kernel_slide = (index - kernel_index) * slide_align;
+    let load_offset = kernel_virt.wrapping_sub(KERNEL_BASE);
+
+    for rela in relas.iter().filter(|rela| rela.kind() == R_X86_64_RELATIVE) {
+        let offset = usize::try_from(rela.offset).unwrap() - KERNEL_BASE;
+        let value = usize::try_from(rela.addend.cast_unsigned())
+            .unwrap()
+            .wrapping_add(load_offset)
+            .wrapping_add(kernel_slide);
+
+        // Relocations may be in read-only segments, so they're written through the (writable) HHDM.
+        let target = core::ptr::with_exposed_provenance_mut::<usize>(
+            layout.boot_hhdm_offset + kernel_phys + offset,
+        );
+
+        // Safety: Relocation offsets are within the kernel executable, which is physically contiguous.
+        unsafe {
+            target.write_unaligned(value);
+        }
+    }
+
+    layout.kernel_slide = kernel_slide;
+}
+
+/// Loads page tables which map the bootloader's HHDM alongside the kernel's own mappings. Bootloader
+/// responses (and the bootloader-provided stacks) are within its HHDM, so these are used until bootloader
+/// memory is reclaimed (see [`load_kernel_tables`]).
+///
+/// ## Safety
+///
+/// All currently referenced memory must be mapped in the kernel page tables, or be within the bootloader's
+/// HHDM.
+pub unsafe fn load_boot_tables() {
+    let layout = layout();
+
+    if layout.hhdm_offset == layout.boot_hhdm_offset {
+        // Safety: Caller is required to ensure all referenced memory is mapped in the kernel page tables.
+        unsafe { load_kernel_tables() };
+
+        return;
+    }
+
+    let mut boot_mapper = BOOT_MAPPER.lock();
+    let boot_mapper = boot_mapper.get_or_insert_with(|| {
+        let root_frame =
+            crate::mem::copy_kernel_page_table().expect("failed to allocate boot page tables");
+        // Safety: Frame was just allocated as a page table, and is within the HHDM.
+        let root_table = unsafe {
+            core::slice::from_raw_parts_mut(
+                core::ptr::with_exposed_provenance_mut::<PageTableEntry>(
+                    crate::mem::Hhdm::offset().get() + root_frame.get().get(),
+                ),
+                table_index_size(),
+            )
+        };
+
+        let boot_index = TableDepth::max()
+            .index_of(Address::new(layout.boot_hhdm_offset).unwrap())
+            .unwrap();
+        let index = TableDepth::max()
+            .index_of(Address::new(layout.hhdm_offset).unwrap())
+            .unwrap();
+
+        // The HHDMs are the same distance into their root entries, so they can share the same tables.
+        crate::mem::with_kmapper(|kmapper| {
+            root_table[boot_index..(boot_index + layout.hhdm_entries)]
+                .copy_from_slice(&kmapper.view_page_table()[index..(index + layout.hhdm_entries)]);
+        });
+
+        // Safety: Root frame is a valid root table, and it's only referenced by this mapper.
+        unsafe { Mapper::new_unsafe(TableDepth::max(), root_frame) }
+    });
+
+    // Safety: Caller is required to ensure all referenced memory is mapped in the boot page tables.
+    unsafe {
+        boot_mapper.swap_into();
+    }
+}
+
+/// Loads the kernel page tables on the current hardware thread.
+///
+/// ## Safety
+///
+/// All currently referenced memory must be mapped in the kernel page tables (so nothing within the
+/// bootloader's HHDM may be referenced).
+pub unsafe fn load_kernel_tables() {
+    crate::mem::with_kmapper(|kmapper| {
+        // Safety: Caller is required to ensure all referenced memory is mapped in the kernel page tables.
+        unsafe {
+            kmapper.swap_into();
+        }
+    });
+}
+
+/// Frees the page tables created by [`load_boot_tables`].
+///
+/// ## Safety
+///
+/// Every hardware thread must have loaded the kernel page tables (see [`load_kernel_tables`]).
+pub unsafe fn free_boot_tables() {
+    if let Some(boot_mapper) = BOOT_MAPPER.lock().take() {
+        PhysicalMemoryManager::free_frame(boot_mapper.root_frame())
+            .expect("failed to free boot page tables");
+    }
+}
+
+crate::kernel_test! {
+    fn kaslr_layout() {
+        crate::ktest_assert_eq!(kernel_slide() % TableDepth::new(1).unwrap().align(), 0);
+        crate::ktest_assert_eq!(crate::mem::Hhdm::offset().get(), hhdm_offset());
+        crate::ktest_assert!(crate::mem::vmalloc::area().start % TableDepth::new(2).unwrap().align() == 0);
+
+        // Symbol lookups must undo the slide (though the symbol info may have been dropped).
+        let address = Address::<Virtual>::new(kaslr_layout as usize).unwrap();
+        if let Some(found) = crate::panic::symbols::with_name(address, |name| name.contains("kaslr_layout")) {
+            crate::ktest_assert!(found);
+        }
+
+        Ok(())
+    }
+}
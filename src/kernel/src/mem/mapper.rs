@@ -1,218 +1,159 @@
 use crate::mem::{
     Hhdm,
     paging::{self, Error, Result, TableDepth},
+    pcid,
     pmm::{self, PhysicalMemoryManager},
 };
-use libkernel::mem::{Mut, Ref};
+use core::{
+    num::{NonZeroU32, NonZeroUsize},
+    ptr::NonNull,
+};
 use libsys::{Address, Frame, Page};
 
-pub struct Mapper {
-    depth: TableDepth,
-    root_frame: Address<Frame>,
-    entry: paging::PageTableEntry,
+/// Physical memory as the kernel sees it: frames are reached through the HHDM, and allocated from
+/// the [`PhysicalMemoryManager`]. Each address space has its own, as it carries the address space's
+/// PCID context (which TLB shootdowns are tracked by).
+pub struct KernelMemory {
+    context: pcid::Context,
 }
 
-// Safety: Type has no thread-local references.
-unsafe impl Send for Mapper {}
-
-impl Mapper {
-    /// Attempts to construct a new page manager. Returns `None` if the `pmm::get()` could not provide a root frame.
-    pub fn new(depth: TableDepth) -> Option<Self> {
-        let root_frame = PhysicalMemoryManager::next_frame().ok()?;
-        trace!("New mapper root frame: {:X?}", root_frame);
-
-        // Safety: `root_frame` is a physical address to a page-sized allocation, which is then offset to the HHDM.
-        unsafe {
-            core::ptr::write_bytes(
-                core::ptr::with_exposed_provenance_mut::<u8>(
-                    Hhdm::offset().get() + root_frame.get().get(),
-                ),
-                0u8,
-                libsys::page_size(),
-            );
-        }
-
-        Some(Self {
-            depth,
-            root_frame,
-            entry: paging::PageTableEntry::new(root_frame, paging::TableEntryFlags::PRESENT),
-        })
-    }
-
-    /// Safety
-    ///
-    /// - The root frame must point to a valid top-level page table.
-    /// - There must only exist one copy of provided page table tree at any time.
-    pub unsafe fn new_unsafe(depth: TableDepth, root_frame: Address<Frame>) -> Self {
+impl KernelMemory {
+    fn new() -> Self {
         Self {
-            depth,
-            root_frame,
-            entry: paging::PageTableEntry::new(root_frame, paging::TableEntryFlags::PRESENT),
+            context: pcid::Context::new(),
         }
     }
+}
 
-    const fn root_table(&self) -> paging::PageTable<Ref> {
-        // Safety: `Self` requires that the entry be valid.
-        unsafe { paging::PageTable::<Ref>::new(self.depth, &self.entry) }
-    }
+// Safety: Frames are reached through the HHDM, which maps all of physical memory, and are allocated
+//         from the physical memory manager.
+unsafe impl paging::PhysicalMemory for KernelMemory {
+    type FrameKind = pmm::FrameKind;
 
-    fn root_table_mut(&mut self) -> paging::PageTable<Mut> {
-        // Safety: `Self` requires that the entry be valid.
-        unsafe { paging::PageTable::<Mut>::new(self.depth, &mut self.entry) }
+    fn frame_ptr(&self, frame: Address<Frame>) -> NonNull<u8> {
+        NonNull::new(core::ptr::with_exposed_provenance_mut(
+            Hhdm::offset().get() + frame.get().get(),
+        ))
+        .unwrap()
     }
 
-    /* MAP / UNMAP */
-
-    /// Maps the specified page to the specified frame index.
-    pub fn map(
-        &mut self,
-        page: Address<Page>,
-        depth: TableDepth,
-        frame: Address<Frame>,
-        lock_frame: bool,
-        attributes: paging::TableEntryFlags,
-    ) -> Result<()> {
-        if lock_frame {
-            // If the acquisition of the frame fails, return an error.
-            PhysicalMemoryManager::lock_frame(frame).map_err(|err| match err {
-                pmm::Error::OutOfBounds => Error::FrameBounds,
-
-                // TODO we should be more specific about the error received
-                _ => Error::AllocError,
-            })?;
-        }
-
-        // If acquisition of the frame is successful, attempt to map the page to the frame index.
-        let result = self
-            .root_table_mut()
-            // Safety: Frame does not contain any data.
-            .with_entry_create(page, depth, |entry| {
-                if depth > TableDepth::min() {
-                    debug_assert!(
-                        attributes.contains(paging::TableEntryFlags::HUGE),
-                        "attributes missing huge bit for huge mapping"
-                    );
-                }
-
-                *entry = paging::PageTableEntry::new(frame, attributes);
-
-                #[cfg(target_arch = "x86_64")]
-                crate::arch::x86_64::instructions::tlb::invlpg(page);
-            });
-
-        result
+    fn allocate_table(&self) -> Option<Address<Frame>> {
+        PhysicalMemoryManager::next_frame(pmm::FrameKind::PageTable)
+            .inspect_err(|err| trace!("Page table allocation error: {err:?}"))
+            .ok()
     }
 
-    /// Unmaps the given page, optionally freeing the frame the page points to within the given [`FrameManager`].
-    ///
-    /// Safety
-    ///
-    /// Caller must ensure calling this function does not cause memory corruption.
-    pub unsafe fn unmap(
-        &mut self,
-        page: Address<Page>,
-        to_depth: Option<TableDepth>,
-        free_frame: bool,
-    ) -> Result<()> {
-        self.root_table_mut()
-            .with_entry_mut(page, to_depth, |entry| {
-                // Safety: We've got an explicit directive from the caller to unmap this page, so the caller must ensure that's a valid operation.
-                unsafe {
-                    entry.set_attributes(
-                        paging::TableEntryFlags::PRESENT,
-                        paging::FlagsModify::Remove,
-                    )
-                };
-
-                let frame = entry.get_frame();
-                // Safety: See above.
-                unsafe { entry.set_frame(Address::new_truncate(0)) };
-
-                if free_frame {
-                    PhysicalMemoryManager::free_frame(frame).unwrap();
-                }
-
-                // Invalidate the page in the TLB.
-                #[cfg(target_arch = "x86_64")]
-                crate::arch::x86_64::instructions::tlb::invlpg(page);
-            })
+    fn allocate_frames(
+        &self,
+        count: NonZeroUsize,
+        kind: Self::FrameKind,
+    ) -> Option<Address<Frame>> {
+        let align = count.get().next_power_of_two() * libsys::page_size();
+
+        PhysicalMemoryManager::next_frames(
+            count,
+            NonZeroU32::new(u32::try_from(align).unwrap()),
+            kind,
+        )
+        .inspect_err(|err| trace!("Frame allocation error: {err:?}"))
+        .ok()
     }
 
-    pub fn auto_map(&mut self, page: Address<Page>, flags: paging::TableEntryFlags) -> Result<()> {
-        match PhysicalMemoryManager::next_frame() {
-            Ok(frame) => self.map(page, TableDepth::min(), frame, false, flags),
-            Err(err) => {
-                trace!("Auto alloc pmm::get() error: {:?}", err);
-                Err(Error::AllocError)
-            }
-        }
+    fn free_frame(&self, frame: Address<Frame>) {
+        PhysicalMemoryManager::free_frame(frame).unwrap();
     }
 
-    /* STATE QUERYING */
+    fn lock_frame(&self, frame: Address<Frame>) -> Result<()> {
+        PhysicalMemoryManager::lock_frame(frame, pmm::FrameKind::Kernel).map_err(|err| match err {
+            pmm::Error::OutOfBounds => Error::FrameBounds,
 
-    pub fn is_mapped(&self, page: Address<Page>, depth: Option<TableDepth>) -> bool {
-        self.root_table().with_entry(page, depth, |_| ()).is_ok()
+            // TODO we should be more specific about the error received
+            _ => Error::AllocError,
+        })
     }
 
-    pub fn is_mapped_to(&self, page: Address<Page>, frame: Address<Frame>) -> bool {
-        self.root_table()
-            .with_entry(page, None, |entry| entry.get_frame() == frame)
-            .unwrap_or(false)
+    /// Invalidates the pages in the TLBs of every hardware thread which may have the address space loaded.
+    fn invalidate(&self, root_frame: Address<Frame>, page: Address<Page>, page_count: usize) {
+        crate::mem::tlb::shootdown(root_frame, &self.context, page, page_count);
     }
 
-    pub fn get_mapped_to(&self, page: Address<Page>) -> Option<Address<Frame>> {
-        self.root_table()
-            .with_entry(page, None, |entry| entry.get_frame())
-            .ok()
+    fn has_1gib_pages(&self) -> bool {
+        use crate::arch::x86_64::cpuid;
+
+        cpuid::EXT_FUNCTION_INFO
+            .as_ref()
+            .is_some_and(cpuid::ExtendedProcessorFeatureIdentifiers::has_1gib_pages)
     }
+}
 
-    /* STATE CHANGING */
+/// Page table manager for an address space, backed by [`KernelMemory`].
+pub struct Mapper(paging::Mapper<KernelMemory>);
 
-    pub fn get_page_attributes(&self, page: Address<Page>) -> Option<paging::TableEntryFlags> {
-        self.root_table()
-            .with_entry(page, None, |entry| entry.get_attributes())
-            .ok()
+// Safety: Type has no thread-local references.
+unsafe impl Send for Mapper {}
+
+impl Mapper {
+    /// Attempts to construct a new page manager. Returns `None` if the `pmm::get()` could not provide a root frame.
+    pub fn new(depth: TableDepth) -> Option<Self> {
+        let mapper = paging::Mapper::new(depth, KernelMemory::new())?;
+        trace!("New mapper root frame: {:X?}", mapper.root_frame());
+
+        Some(Self(mapper))
     }
 
-    pub unsafe fn set_page_attributes(
-        &mut self,
-        page: Address<Page>,
-        depth: Option<TableDepth>,
-        attributes: paging::TableEntryFlags,
-        modify_mode: paging::FlagsModify,
-    ) -> Result<()> {
-        self.root_table_mut().with_entry_mut(page, depth, |entry| {
-            entry.set_attributes(attributes, modify_mode);
-
-            #[cfg(target_arch = "x86_64")]
-            crate::arch::x86_64::instructions::tlb::invlpg(page);
-        })
+    /// Safety
+    ///
+    /// - The root frame must point to a valid top-level page table.
+    /// - There must only exist one copy of provided page table tree at any time.
+    pub unsafe fn new_unsafe(depth: TableDepth, root_frame: Address<Frame>) -> Self {
+        // Safety: Caller is required to ensure the root frame is a valid, unaliased top-level page table.
+        Self(unsafe { paging::Mapper::new_unsafe(depth, root_frame, KernelMemory::new()) })
     }
 
     /// Safety
     ///
     /// Caller must ensure that switching the currently active address space will not cause undefined behaviour.
     pub unsafe fn swap_into(&self) {
-        trace!("Swapping address space to: {:X?}", self.root_frame);
+        let root_frame = self.root_frame();
+        trace!("Swapping address space to: {:X?}", root_frame);
+
+        // The root must be published before the PCID is assigned, so that any concurrent shootdown
+        // either targets this hardware thread, or has already invalidated the context.
+        if let Some(shootdown_state) = crate::cpu::state::shootdown_state() {
+            shootdown_state.set_active_root(root_frame);
+        }
+
+        let pcid = crate::cpu::state::with_pcid_allocator(|allocator| {
+            allocator
+                .is_enabled()
+                .then(|| allocator.assign(&self.memory().context))
+        })
+        .flatten();
 
         #[cfg(target_arch = "x86_64")]
-        crate::arch::x86_64::registers::control::CR3::write(
-            self.root_frame,
-            crate::arch::x86_64::registers::control::CR3Flags::empty(),
-        );
+        {
+            use crate::arch::x86_64::registers::control::{CR3, CR3Flags};
+
+            match pcid {
+                // Safety: PCIDs are enabled, and the allocator decides whether the tagged entries are still valid.
+                Some((pcid, flush)) => unsafe { CR3::write_pcid(root_frame, pcid, !flush) },
+                // Safety: Caller is required to ensure the switch is valid.
+                None => unsafe { CR3::write(root_frame, CR3Flags::empty()) },
+            }
+        }
     }
+}
+
+impl core::ops::Deref for Mapper {
+    type Target = paging::Mapper<KernelMemory>;
 
-    pub const fn root_frame(&self) -> Address<Frame> {
-        self.root_frame
+    fn deref(&self) -> &Self::Target {
+        &self.0
     }
+}
 
-    pub fn view_page_table(&self) -> &[paging::PageTableEntry; libsys::table_index_size()] {
-        // Safety: Root frame is guaranteed to be valid within the HHDM.
-        let table_ptr =
-            core::ptr::with_exposed_provenance(Hhdm::offset().get() + self.root_frame.get().get());
-        // Safety: Root frame is guaranteed to be valid for PTEs for the length of the table index size.
-        let table = unsafe { core::slice::from_raw_parts(table_ptr, libsys::table_index_size()) };
-        // Safety: Table was created to match the size required by return type.
-        unsafe { table.try_into().unwrap_unchecked() }
+impl core::ops::DerefMut for Mapper {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
     }
 }
@@ -1,12 +1,20 @@
 mod global_alloc;
+pub use global_alloc::{flush_slab_magazines, log_slab_stats, slab_stats};
+mod heap_debug;
+pub use heap_debug::drain_heap_quarantine;
 
 mod hhdm;
 pub use hhdm::*;
 
 // pub mod io;
+pub mod kaslr;
 pub mod mapper;
 pub mod paging;
+pub mod pcid;
 pub mod pmm;
+pub mod reclaim;
+pub mod tlb;
+pub mod vmalloc;
 
 use self::mapper::Mapper;
 use crate::{interrupts::InterruptCell, mem::pmm::PhysicalMemoryManager};
@@ -38,32 +46,88 @@ impl<const SIZE: usize> core::ops::Deref for Stack<SIZE> {
     }
 }
 
-pub fn with_kmapper<T>(func: impl FnOnce(&mut Mapper) -> T) -> T {
-    static KERNEL_MAPPER: Lazy<InterruptCell<Mutex<Mapper>>> = Lazy::new(|| {
-        debug!("Creating kernel-space address mapper.");
+static KERNEL_MAPPER: Lazy<InterruptCell<Mutex<Mapper>>> = Lazy::new(|| {
+    debug!("Creating kernel-space address mapper.");
 
-        InterruptCell::new(Mutex::new(Mapper::new(paging::TableDepth::max()).unwrap()))
-    });
+    let mut mapper = Mapper::new(paging::TableDepth::max()).unwrap();
+    mapper
+        .preallocate_higher_half()
+        .expect("failed to allocate kernel page tables");
 
+    InterruptCell::new(Mutex::new(mapper))
+});
+
+pub fn with_kmapper<T>(func: impl FnOnce(&mut Mapper) -> T) -> T {
     KERNEL_MAPPER.with(|mapper| {
-        let mut mapper = mapper.lock();
+        // The holder of the lock may be waiting on this hardware thread to acknowledge a TLB
+        // shootdown, which it can't do with interrupts disabled, so poll for one while waiting.
+        let mut mapper = loop {
+            if let Some(mapper) = mapper.try_lock() {
+                break mapper;
+            }
+
+            tlb::service_pending();
+            core::hint::spin_loop();
+        };
+
         func(&mut mapper)
     })
 }
 
+/// Like [`with_kmapper`], but returns `None` rather than waiting if the kernel mapper is locked.
+pub fn try_with_kmapper<T>(func: impl FnOnce(&mut Mapper) -> T) -> Option<T> {
+    KERNEL_MAPPER.with(|mapper| {
+        let mut mapper = mapper.try_lock()?;
+        Some(func(&mut mapper))
+    })
+}
+
+/// Allocates a new root page table, with an empty lower half, and a higher half which shares the
+/// kernel's page tables.
 pub fn copy_kernel_page_table() -> Result<Address<Frame>, pmm::Error> {
-    let table_frame = PhysicalMemoryManager::next_frame()?;
+    let table_frame = PhysicalMemoryManager::next_frame(pmm::FrameKind::PageTable)?;
     let table_ptr =
         core::ptr::with_exposed_provenance_mut(Hhdm::offset().get() + table_frame.get().get());
 
     // Safety: Frame is provided by allocator, and so guaranteed to be within the HHDM, and is frame-sized.
     let new_table = unsafe { core::slice::from_raw_parts_mut(table_ptr, table_index_size()) };
     new_table.fill(paging::PageTableEntry::empty());
-    with_kmapper(|kmapper| new_table.copy_from_slice(kmapper.view_page_table()));
+
+    // Every higher half root entry is allocated when the kernel mapper is created, so kernel
+    // mappings made later on are visible to every address space.
+    let higher_half = (table_index_size() / 2)..;
+    with_kmapper(|kmapper| {
+        new_table[higher_half.clone()].copy_from_slice(&kmapper.view_page_table()[higher_half]);
+    });
 
     Ok(table_frame)
 }
 
+crate::kernel_test! {
+    fn kernel_page_table_shared() {
+        let table_frame = copy_kernel_page_table().map_err(|err| alloc::format!("{err}"))?;
+        let table_ptr =
+            core::ptr::with_exposed_provenance(Hhdm::offset().get() + table_frame.get().get());
+        // Safety: Frame was just allocated as a page table, and is within the HHDM.
+        let table: &[paging::PageTableEntry] =
+            unsafe { core::slice::from_raw_parts(table_ptr, table_index_size()) };
+        let (lower_half, higher_half) = table.split_at(table_index_size() / 2);
+
+        crate::ktest_assert!(lower_half.iter().all(|entry| !entry.is_present()));
+        crate::ktest_assert!(higher_half.iter().all(|entry| entry.is_present()));
+        crate::ktest_assert!(with_kmapper(|kmapper| {
+            kmapper.view_page_table()[(table_index_size() / 2)..]
+                .iter()
+                .zip(higher_half)
+                .all(|(kernel_entry, entry)| kernel_entry.get_frame() == entry.get_frame())
+        }));
+
+        PhysicalMemoryManager::free_frame(table_frame).map_err(|err| alloc::format!("{err}"))?;
+
+        Ok(())
+    }
+}
+
 #[cfg(target_arch = "x86_64")]
 pub struct PagingRegister(
     pub Address<Frame>,
@@ -0,0 +1,4 @@
+//! The kernel's page table types, provided by the [`paging`](::paging) crate. Address spaces manage them
+//! through [`crate::mem::mapper::Mapper`], which reaches physical memory through the HHDM.
+
+pub use ::paging::*;
@@ -0,0 +1,170 @@
+//! Process-context identifiers (PCIDs).
+//!
+//! Each hardware thread tags the TLB entries of the address spaces it loads with a PCID, so
+//! switching back to a recently used address space doesn't require flushing the TLB. PCIDs are
+//! assigned per-hardware thread, in order; once they're exhausted, the allocator begins a new
+//! generation, which flushes the TLB and implicitly revokes every PCID of the previous one.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of PCIDs assigned by each hardware thread (PCID 0 is never assigned, and so always
+/// flushes the TLB when loaded).
+const PCID_COUNT: usize = 64;
+
+static NEXT_CONTEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// TLB state of an address space, which is shared by every hardware thread.
+#[derive(Debug)]
+pub struct Context {
+    /// Uniquely identifies the address space, even if its root frame is later reused.
+    id: u64,
+    /// Incremented whenever the address space's mappings change, so that hardware threads which
+    /// have it cached under a PCID (but don't currently have it loaded) know to flush it.
+    generation: AtomicU64,
+}
+
+impl Context {
+    pub fn new() -> Self {
+        Self {
+            id: NEXT_CONTEXT_ID.fetch_add(1, Ordering::Relaxed),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Marks the TLB entries cached for this address space as stale on every hardware thread.
+    pub fn invalidate(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    context_id: u64,
+    context_generation: u64,
+    generation: u64,
+}
+
+/// Assigns PCIDs to address spaces on a single hardware thread.
+pub struct Allocator {
+    enabled: bool,
+    generation: u64,
+    next: usize,
+    slots: [Slot; PCID_COUNT],
+}
+
+impl Allocator {
+    pub fn new() -> Self {
+        #[cfg(target_arch = "x86_64")]
+        let enabled = {
+            use crate::arch::x86_64::registers::control::{CR4, CR4Flags};
+
+            CR4::read().contains(CR4Flags::PCIDE)
+        };
+
+        Self {
+            enabled,
+            generation: 1,
+            next: 0,
+            slots: [Slot {
+                context_id: 0,
+                context_generation: 0,
+                generation: 0,
+            }; PCID_COUNT],
+        }
+    }
+
+    /// Whether PCIDs are enabled on this hardware thread.
+    pub const fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// PCIDs assigned in the current generation, under which TLB entries may still be cached.
+    pub fn assigned(&self) -> core::ops::Range<u16> {
+        1..Self::pcid_of(self.next)
+    }
+
+    /// Assigns a PCID to `context`, returning it along with whether the TLB entries already tagged
+    /// with it must be flushed.
+    pub fn assign(&mut self, context: &Context) -> (u16, bool) {
+        if !self.enabled {
+            return (0, true);
+        }
+
+        let context_generation = context.generation.load(Ordering::SeqCst);
+
+        if let Some((index, slot)) =
+            self.slots.iter_mut().enumerate().find(|(_, slot)| {
+                slot.generation == self.generation && slot.context_id == context.id
+            })
+        {
+            // Entries cached from a previous generation of the address space must be flushed.
+            let flush = slot.context_generation != context_generation;
+            slot.context_generation = context_generation;
+
+            return (Self::pcid_of(index), flush);
+        }
+
+        if self.next == PCID_COUNT {
+            // Every PCID has been assigned, so revoke them all at once.
+            self.generation += 1;
+            self.next = 0;
+
+            #[cfg(target_arch = "x86_64")]
+            crate::arch::x86_64::instructions::tlb::flush_all();
+        }
+
+        let index = self.next;
+        self.next += 1;
+        self.slots[index] = Slot {
+            context_id: context.id,
+            context_generation,
+            generation: self.generation,
+        };
+
+        // The PCID may have been used by another address space in an earlier generation.
+        (Self::pcid_of(index), true)
+    }
+
+    fn pcid_of(index: usize) -> u16 {
+        u16::try_from(index + 1).unwrap()
+    }
+}
+
+impl Default for Allocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+crate::kernel_test! {
+    fn pcid_assign_and_recycle() {
+        let mut allocator = Allocator::new();
+        if !allocator.is_enabled() {
+            return Ok(());
+        }
+
+        let context = Context::new();
+        let (pcid, flush) = allocator.assign(&context);
+        crate::ktest_assert!(pcid != 0 && flush);
+        crate::ktest_assert!(allocator.assigned().eq([pcid]));
+        crate::ktest_assert_eq!(allocator.assign(&context), (pcid, false));
+
+        // Changing the address space's mappings invalidates its cached entries.
+        context.invalidate();
+        crate::ktest_assert_eq!(allocator.assign(&context), (pcid, true));
+
+        // Exhausting the PCIDs begins a new generation, revoking the context's PCID.
+        for _ in 0..PCID_COUNT {
+            allocator.assign(&Context::new());
+        }
+        crate::ktest_assert!(allocator.assign(&context).1);
+
+        Ok(())
+    }
+}
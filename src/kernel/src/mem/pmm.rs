@@ -1,15 +1,41 @@
-use crate::{interrupts::InterruptCell, mem::Hhdm};
-use bitvec::slice::BitSlice;
+use crate::{
+    interrupts::InterruptCell,
+    mem::{Hhdm, reclaim::Watermarks},
+};
 use core::{
     mem::MaybeUninit,
     num::{NonZeroU32, NonZeroUsize},
-    sync::atomic::AtomicUsize,
+    sync::atomic::{AtomicU8, AtomicU32, AtomicUsize, Ordering},
 };
 use libsys::{Address, Frame, page_mask, page_shift, page_size};
-use spin::RwLock;
+use spin::Mutex;
 
 static PMM: spin::Once<PhysicalMemoryManager> = spin::Once::new();
 
+/// Largest block order managed by the allocator, i.e. blocks of `2^MAX_ORDER` frames
+/// (1 GiB with 4 KiB frames).
+pub const MAX_ORDER: usize = 18;
+
+/// Number of frames a per-core frame cache can hold.
+const CACHE_CAPACITY: usize = 64;
+/// Number of frames moved between a per-core frame cache and the global allocator at once.
+const CACHE_BATCH: usize = 16;
+/// Per-core frame cache size above which a batch of frames is returned to the global allocator.
+const CACHE_HIGH_WATERMARK: usize = 48;
+/// [`CACHE_BATCH`] in low-memory mode.
+const LOW_MEMORY_CACHE_BATCH: usize = 4;
+/// [`CACHE_HIGH_WATERMARK`] in low-memory mode.
+const LOW_MEMORY_CACHE_HIGH_WATERMARK: usize = 12;
+
+/// Number of frames the shrinkers should reclaim, set once the free frames fall below the
+/// low watermark. Reclaim is deferred until the allocator's lock has been released.
+static RECLAIM_TARGET: AtomicUsize = AtomicUsize::new(0);
+
+/// Marks a frame which is not the head of a free block.
+const NOT_HEAD: u8 = u8::MAX;
+/// Marks the end of a free list.
+const NIL: usize = usize::MAX;
+
 #[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
 pub enum Error {
     #[error("the physical memory manager is out of free frames")]
@@ -28,18 +54,538 @@ pub enum Error {
     NotLocked,
 }
 
-type FrameTable = RwLock<&'static mut BitSlice<AtomicUsize>>;
+/// What a frame is being used for.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, num_enum::TryFromPrimitive)]
+pub enum FrameKind {
+    /// Frame is free, or held in a per-core frame cache.
+    Free,
+    /// Frame is not usable memory, or is in use by the bootloader or the frame table.
+    Reserved,
+    /// General kernel use.
+    Kernel,
+    KernelHeap,
+    PageTable,
+    UserAnon,
+    Dma,
+    Mmio,
+    Framebuffer,
+}
+
+impl FrameKind {
+    pub const ALL: [Self; 9] = [
+        Self::Free,
+        Self::Reserved,
+        Self::Kernel,
+        Self::KernelHeap,
+        Self::PageTable,
+        Self::UserAnon,
+        Self::Dma,
+        Self::Mmio,
+        Self::Framebuffer,
+    ];
+}
+
+bitflags! {
+    #[repr(transparent)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct FrameFlags: u8 {
+        /// Frame must not be reclaimed or relocated.
+        const PINNED = 1 << 0;
+        /// Frame is mapped copy-on-write by each of its referents.
+        const COPY_ON_WRITE = 1 << 1;
+    }
+}
+
+/// Per-frame state, tracking the frame's reference count & usage.
+struct FrameDescriptor {
+    refcount: AtomicU32,
+    kind: AtomicU8,
+    flags: AtomicU8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameInfo {
+    pub refcount: u32,
+    pub kind: FrameKind,
+    pub flags: FrameFlags,
+}
+
+/// Physical memory zones, for allocations constrained by what a device can address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// Memory below 1 MiB (e.g. for real-mode trampolines or legacy DMA).
+    Low1M,
+    /// Memory below 4 GiB (e.g. for devices capable of only 32-bit DMA).
+    Dma32,
+    /// All other memory.
+    Normal,
+}
+
+impl Zone {
+    pub const ALL: [Self; 3] = [Self::Low1M, Self::Dma32, Self::Normal];
+
+    /// Index of the first frame above the zone.
+    fn end_index(self) -> usize {
+        match self {
+            Zone::Low1M => 0x10_0000 >> page_shift().get(),
+            Zone::Dma32 => 0x1_0000_0000 >> page_shift().get(),
+            Zone::Normal => usize::MAX,
+        }
+    }
+
+    fn of(index: usize) -> Self {
+        Self::ALL
+            .into_iter()
+            .find(|zone| index < zone.end_index())
+            .unwrap()
+    }
+
+    /// Zones an allocation constrained to this zone may be satisfied from, in order of preference.
+    ///
+    /// Unconstrained allocations never fall back to [`Zone::Low1M`], as it's tiny and
+    /// best kept for the allocations which truly require it.
+    const fn fallbacks(self) -> &'static [Self] {
+        match self {
+            Zone::Low1M => &[Zone::Low1M],
+            Zone::Dma32 => &[Zone::Dma32, Zone::Low1M],
+            Zone::Normal => &[Zone::Normal, Zone::Dma32],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZoneStats {
+    pub zone: Zone,
+    /// Frames of usable memory within the zone.
+    pub managed_frames: usize,
+    pub free_frames: usize,
+}
+
+/// Free list links, stored in the first bytes of the head frame of each free block.
+#[repr(C)]
+struct FreeLink {
+    prev: usize,
+    next: usize,
+}
+
+/// Binary buddy allocator over the frames of physical memory.
+///
+/// Every free block of order `k` is `2^k` frames long, and aligned to its own size. The
+/// only per-frame state is the order of the free block a frame is the head of (if any),
+/// so whether a frame is free is determined by searching for a free block containing it.
+///
+/// Each [`Zone`] has its own free lists, and blocks are never coalesced across zones.
+struct BuddyAllocator {
+    heads: &'static mut [u8],
+    free_lists: [[usize; MAX_ORDER + 1]; Zone::ALL.len()],
+    free_counts: [usize; Zone::ALL.len()],
+    managed_counts: [usize; Zone::ALL.len()],
+    watermarks: Watermarks,
+    /// Whether the free frames have fallen below the low watermark, and not yet
+    /// recovered to the high watermark.
+    under_pressure: bool,
+}
+
+impl BuddyAllocator {
+    fn free_frames(&self) -> usize {
+        self.free_counts.iter().sum()
+    }
+
+    /// Requests a reclaim upon the free frames falling below the low watermark.
+    fn check_low_watermark(&mut self) {
+        let free_frames = self.free_frames();
+
+        if !self.under_pressure && free_frames < self.watermarks.low {
+            self.under_pressure = true;
+            RECLAIM_TARGET.store(self.watermarks.high - free_frames, Ordering::Relaxed);
+        }
+    }
+
+    fn link(index: usize) -> *mut FreeLink {
+        core::ptr::with_exposed_provenance_mut(Hhdm::offset().get() + (index << page_shift().get()))
+    }
+
+    fn push(&mut self, index: usize, order: usize) {
+        let free_list = &mut self.free_lists[Zone::of(index) as usize][order];
+        let next = *free_list;
+
+        // Safety: Frames of a free block are unused, so the head frame can hold the list links.
+        unsafe {
+            Self::link(index).write(FreeLink { prev: NIL, next });
+
+            if next != NIL {
+                (*Self::link(next)).prev = index;
+            }
+        }
+
+        *free_list = index;
+        self.heads[index] = u8::try_from(order).unwrap();
+    }
+
+    fn remove(&mut self, index: usize, order: usize) {
+        // Safety: `index` is the head of a free block, so it holds valid list links.
+        let FreeLink { prev, next } = unsafe { Self::link(index).read() };
+
+        if prev == NIL {
+            self.free_lists[Zone::of(index) as usize][order] = next;
+        } else {
+            // Safety: `prev` is the head of a free block in the same list.
+            unsafe { (*Self::link(prev)).next = next };
+        }
+
+        if next != NIL {
+            // Safety: `next` is the head of a free block in the same list.
+            unsafe { (*Self::link(next)).prev = prev };
+        }
+
+        self.heads[index] = NOT_HEAD;
+    }
+
+    /// Finds the free block containing the frame at `index`, returning its head & order.
+    fn find_free_block(&self, index: usize) -> Option<(usize, usize)> {
+        (0..=MAX_ORDER)
+            .map(|order| (index & !((1 << order) - 1), order))
+            .find(|&(head, order)| usize::from(self.heads[head]) == order)
+    }
+
+    /// Frees a locked block of `2^order` frames, coalescing it with its free buddies.
+    ///
+    /// Panics if the block overlaps a free block, as freeing it again would corrupt the free lists.
+    fn free_block(&mut self, mut index: usize, mut order: usize) {
+        assert!(
+            self.find_free_block(index).is_none()
+                && self.heads[index..(index + (1 << order))]
+                    .iter()
+                    .all(|&head| head == NOT_HEAD),
+            "frame {index:#X} (order {order}) was freed while already free"
+        );
+
+        let zone = Zone::of(index);
+        self.free_counts[zone as usize] += 1 << order;
+
+        while order < MAX_ORDER {
+            let buddy = index ^ (1 << order);
+            if buddy >= self.heads.len()
+                || usize::from(self.heads[buddy]) != order
+                || Zone::of(buddy) != zone
+            {
+                break;
+            }
+
+            self.remove(buddy, order);
+            index = usize::min(index, buddy);
+            order += 1;
+        }
+
+        self.push(index, order);
+
+        if self.under_pressure && self.free_frames() >= self.watermarks.high {
+            self.under_pressure = false;
+        }
+    }
+
+    /// Frees the locked frames in `start..end`, as the largest aligned blocks possible
+    /// (without crossing zone boundaries).
+    fn free_range(&mut self, mut start: usize, end: usize) {
+        while start < end {
+            let block_end = usize::min(end, Zone::of(start).end_index());
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|&order| {
+                    (start & ((1 << order) - 1)) == 0 && (start + (1 << order)) <= block_end
+                })
+                .unwrap();
+
+            self.free_block(start, order);
+            start += 1 << order;
+        }
+    }
+
+    /// Allocates a block of `2^order` frames from `zone` (or one of its fallbacks).
+    fn allocate(&mut self, order: usize, zone: Zone) -> Option<usize> {
+        zone.fallbacks()
+            .iter()
+            .find_map(|&zone| self.allocate_from(order, zone))
+    }
+
+    /// Allocates a block of `2^order` frames from exactly `zone`, splitting a larger block if need be.
+    fn allocate_from(&mut self, order: usize, zone: Zone) -> Option<usize> {
+        let (index, mut block_order) = (order..=MAX_ORDER)
+            .map(|order| (self.free_lists[zone as usize][order], order))
+            .find(|&(index, _)| index != NIL)?;
+
+        self.remove(index, block_order);
+
+        // Return the upper halves of the block to the free lists until it is the requested size.
+        while block_order > order {
+            block_order -= 1;
+            self.push(index + (1 << block_order), block_order);
+        }
+
+        self.free_counts[zone as usize] -= 1 << order;
+        self.check_low_watermark();
+
+        Some(index)
+    }
+
+    /// Locks the single free frame at `index`, splitting the free block which contains it.
+    fn lock(&mut self, index: usize) -> Result<(), Error> {
+        let (mut head, mut order) = self.find_free_block(index).ok_or(Error::NotFree)?;
+
+        self.remove(head, order);
+
+        // Return the halves which don't contain the frame to the free lists.
+        while order > 0 {
+            order -= 1;
+
+            let half = 1 << order;
+            if index < (head + half) {
+                self.push(head + half, order);
+            } else {
+                self.push(head, order);
+                head += half;
+            }
+        }
+
+        self.free_counts[Zone::of(index) as usize] -= 1;
+        self.check_low_watermark();
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct CachedFrames {
+    frames: [usize; CACHE_CAPACITY],
+    len: usize,
+}
+
+/// Per-hardware-thread cache of free frames, allowing most frame allocations & frees to
+/// avoid the global allocator's lock. Frames move between the cache and the global
+/// allocator in batches of [`CACHE_BATCH`].
+///
+/// Each zone's frames are cached separately, so allocations are served from the same zones
+/// (in the same order of preference) as they would be by the global allocator. Frames from
+/// [`Zone::Low1M`] aren't cached, as it's too small to hoard.
+pub struct FrameCache {
+    zones: InterruptCell<Mutex<[CachedFrames; Zone::ALL.len()]>>,
+}
+
+struct FrameCaches {
+    caches: [Option<&'static FrameCache>; crate::cpu::MAX_HWTHREADS],
+    len: usize,
+}
+
+impl FrameCaches {
+    fn iter(&self) -> impl Iterator<Item = &'static FrameCache> + '_ {
+        self.caches[..self.len].iter().flatten().copied()
+    }
+}
+
+/// Every registered frame cache, so their frames can be reclaimed when the global allocator runs out.
+///
+/// Caches are kept in a fixed-size table, so registering one never allocates (and so can't itself
+/// drain the caches, or run the shrinkers, while holding the lock).
+static FRAME_CACHES: InterruptCell<Mutex<FrameCaches>> =
+    InterruptCell::new(Mutex::new(FrameCaches {
+        caches: [None; crate::cpu::MAX_HWTHREADS],
+        len: 0,
+    }));
+
+impl FrameCache {
+    pub const fn new() -> Self {
+        Self {
+            zones: InterruptCell::new(Mutex::new(
+                [CachedFrames {
+                    frames: [0; CACHE_CAPACITY],
+                    len: 0,
+                }; Zone::ALL.len()],
+            )),
+        }
+    }
+
+    /// Registers the cache, so its frames can be drained back to the global allocator
+    /// when another hardware thread runs out of free frames.
+    pub fn register(cache: &'static Self) {
+        FRAME_CACHES.with(|caches| {
+            let mut caches = caches.lock();
+
+            let len = caches.len;
+            *caches
+                .caches
+                .get_mut(len)
+                .expect("frame cache table is full") = Some(cache);
+            caches.len += 1;
+        });
+    }
+
+    /// Whether frames from `zone` are cached.
+    const fn caches(zone: Zone) -> bool {
+        !matches!(zone, Zone::Low1M)
+    }
+
+    /// Number of frames currently held by the cache.
+    pub fn len(&self) -> usize {
+        self.zones
+            .with(|zones| zones.lock().iter().map(|frames| frames.len).sum())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of frames from `zone` currently held by the cache.
+    fn len_in(&self, zone: Zone) -> usize {
+        self.zones.with(|zones| zones.lock()[zone as usize].len)
+    }
+
+    /// Takes a frame from `zone` (or one of its fallbacks), refilling the cache of each zone
+    /// from the global allocator when it's empty.
+    fn pop(&self, zone: Zone) -> Option<usize> {
+        self.zones.with(|zones| {
+            let mut zones = zones.lock();
+
+            zone.fallbacks()
+                .iter()
+                .copied()
+                .filter(|&zone| Self::caches(zone))
+                .find_map(|zone| {
+                    let frames = &mut zones[zone as usize];
+
+                    if frames.len == 0 {
+                        PhysicalMemoryManager::with_allocator(|allocator| {
+                            while frames.len < PhysicalMemoryManager::get_static().cache_batch
+                                && let Some(index) = allocator.allocate_from(0, zone)
+                            {
+                                frames.frames[frames.len] = index;
+                                frames.len += 1;
+                            }
+
+                            Ok(())
+                        })
+                        .ok()?;
+                    }
+
+                    frames.len = frames.len.checked_sub(1)?;
+                    Some(frames.frames[frames.len])
+                })
+        })
+    }
+
+    /// Places a freed frame into the cache of its zone, draining a batch to the global
+    /// allocator once above the high watermark.
+    fn push(&self, index: usize) {
+        PhysicalMemoryManager::assert_unreferenced(index);
+
+        self.zones.with(|zones| {
+            let mut zones = zones.lock();
+            let frames = &mut zones[Zone::of(index) as usize];
+
+            let len = frames.len;
+            assert!(
+                !frames.frames[..len].contains(&index),
+                "frame {index:#X} was freed while already in a frame cache"
+            );
+            frames.frames[len] = index;
+            frames.len += 1;
+
+            let pmm = PhysicalMemoryManager::get_static();
+            if frames.len > pmm.cache_high_watermark {
+                let drain_start = frames.len - pmm.cache_batch;
+                PhysicalMemoryManager::with_allocator(|allocator| {
+                    for &index in &frames.frames[drain_start..] {
+                        PhysicalMemoryManager::assert_unreferenced(index);
+                        allocator.free_block(index, 0);
+                    }
+
+                    Ok(())
+                })
+                .unwrap();
+                frames.len = drain_start;
+            }
+        });
+    }
+
+    /// Removes a specific frame from the cache, returning whether it was present.
+    fn take(&self, index: usize) -> bool {
+        self.zones.with(|zones| {
+            let mut zones = zones.lock();
+            let frames = &mut zones[Zone::of(index) as usize];
+            let len = frames.len;
+
+            if let Some(position) = frames.frames[..len].iter().position(|&i| i == index) {
+                frames.frames.swap(position, len - 1);
+                frames.len -= 1;
+
+                true
+            } else {
+                false
+            }
+        })
+    }
+
+    /// Returns every frame in the cache to the global allocator, returning the number of frames drained.
+    fn drain(&self) -> usize {
+        self.zones.with(|zones| {
+            let mut zones = zones.lock();
+
+            PhysicalMemoryManager::with_allocator(|allocator| {
+                for frames in zones.iter() {
+                    for &index in &frames.frames[..frames.len] {
+                        PhysicalMemoryManager::assert_unreferenced(index);
+                        allocator.free_block(index, 0);
+                    }
+                }
+
+                Ok(())
+            })
+            .unwrap();
+
+            zones
+                .iter_mut()
+                .map(|frames| core::mem::take(&mut frames.len))
+                .sum()
+        })
+    }
+}
+
+impl Default for FrameCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Snapshot of the physical memory manager's frame usage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryStats {
+    pub total_frames: usize,
+    /// Frames which are free (including those held in per-core frame caches).
+    pub free_frames: usize,
+    /// Frames which are allocated, or otherwise in use by a known consumer.
+    pub used_frames: usize,
+    /// Frames which aren't usable memory, or are in use by firmware or the bootloader.
+    pub reserved_frames: usize,
+    frames_by_kind: [usize; FrameKind::ALL.len()],
+}
+
+impl MemoryStats {
+    /// Number of frames being used as `kind`.
+    pub const fn frames_of(&self, kind: FrameKind) -> usize {
+        self.frames_by_kind[kind as usize]
+    }
+}
 
 pub struct PhysicalMemoryManager {
-    table: InterruptCell<FrameTable>,
+    allocator: InterruptCell<Mutex<BuddyAllocator>>,
+    descriptors: &'static [FrameDescriptor],
+    /// Number of frames of each [`FrameKind`].
+    kind_counts: [AtomicUsize; FrameKind::ALL.len()],
     total_frames: usize,
+    cache_batch: usize,
+    cache_high_watermark: usize,
 }
 
-// Safety: Type uses entirely atomic operations.
-unsafe impl Send for PhysicalMemoryManager {}
-// Safety: Type uses entirely atomic operations.
-unsafe impl Sync for PhysicalMemoryManager {}
-
 impl PhysicalMemoryManager {
     /// Initializes the static physical memory manager with the provided bootloader memory map request.
     pub fn init(memory_map_request: &limine::request::MemoryMapRequest) {
@@ -69,17 +615,14 @@ impl PhysicalMemoryManager {
             trace!("Total phyiscal memory: {}M", total_memory / 1_000_000);
 
             let total_frames = total_memory / page_size();
-            let table_slice_len = libsys::align_up_div(
-                total_frames,
-                NonZeroU32::new(usize::BITS.trailing_zeros()).unwrap(),
-            );
-            let table_size_in_frames = libsys::align_up_div(
-                table_slice_len * core::mem::size_of::<usize>(),
-                page_shift(),
-            );
+            // The frame table holds a descriptor per frame, followed by the allocator's per-frame state.
+            let descriptors_size = total_frames * size_of::<FrameDescriptor>();
+            let table_size_in_frames =
+                libsys::align_up_div(descriptors_size + total_frames, page_shift());
             let table_size_in_bytes = table_size_in_frames * page_size();
 
             let select_region = free_ranges
+                .clone()
                 .filter(|region| (region.start & page_mask()) == 0)
                 .find(|region| region.len() >= table_size_in_bytes)
                 .map(|region| region.start..(region.start + table_size_in_bytes))
@@ -90,30 +633,126 @@ impl PhysicalMemoryManager {
 
             trace!("Frame table region: {select_region:X?}");
 
-            // Safety: Region is guaranteed by the memory map to be unused.
-            let table = unsafe {
+            let table_ptr = Hhdm::offset().get() + select_region.start;
+
+            // Safety: Region is guaranteed by the memory map to be unused, and is page-aligned.
+            let descriptors = unsafe {
                 core::slice::from_raw_parts_mut(
-                    core::ptr::with_exposed_provenance_mut::<MaybeUninit<AtomicUsize>>(
-                        Hhdm::offset().get() + select_region.start,
+                    core::ptr::with_exposed_provenance_mut::<MaybeUninit<FrameDescriptor>>(
+                        table_ptr,
                     ),
-                    table_slice_len,
+                    total_frames,
                 )
             };
-            table.fill_with(|| MaybeUninit::new(AtomicUsize::new(0)));
-            // Safety: `table` has been initialized in the prior line.
-            let table = BitSlice::from_slice_mut(unsafe { table.assume_init_mut() });
+            // Every frame starts reserved, with a single reference (so it can later be freed,
+            // as with bootloader reclaimable memory). Only usable memory is then freed.
+            descriptors.fill_with(|| {
+                MaybeUninit::new(FrameDescriptor {
+                    refcount: AtomicU32::new(1),
+                    kind: AtomicU8::new(FrameKind::Reserved as u8),
+                    flags: AtomicU8::new(FrameFlags::PINNED.bits()),
+                })
+            });
+            // Safety: `descriptors` has been initialized in the prior line.
+            let descriptors = unsafe { descriptors.assume_init_ref() };
 
-            // Fill the padding bits, as the table may have more bits than there are frames.
-            table[total_frames..].fill(true);
+            // Safety: Region is guaranteed by the memory map to be unused, and follows the descriptors.
+            let heads = unsafe {
+                core::slice::from_raw_parts_mut(
+                    core::ptr::with_exposed_provenance_mut::<MaybeUninit<u8>>(
+                        table_ptr + descriptors_size,
+                    ),
+                    total_frames,
+                )
+            };
+            heads.fill(MaybeUninit::new(NOT_HEAD));
+            // Safety: `heads` has been initialized in the prior line.
+            let heads = unsafe { heads.assume_init_mut() };
+
+            let mut allocator = BuddyAllocator {
+                heads,
+                free_lists: [[NIL; MAX_ORDER + 1]; Zone::ALL.len()],
+                free_counts: [0; Zone::ALL.len()],
+                managed_counts: [0; Zone::ALL.len()],
+                watermarks: Watermarks { low: 0, high: 0 },
+                under_pressure: false,
+            };
 
-            // Ensure the table's frames are reserved.
             let table_start_index = select_region.start / page_size();
             let table_end_index = select_region.end / page_size();
-            table[table_start_index..table_end_index].fill(true);
+
+            for region in free_ranges {
+                let start_index = libsys::align_up_div(region.start, page_shift());
+                let end_index = usize::min(region.end / page_size(), total_frames);
+
+                // Ensure the table's frames are kept locked.
+                let ranges = if (start_index..end_index).contains(&table_start_index) {
+                    [start_index..table_start_index, table_end_index..end_index]
+                } else {
+                    [start_index..end_index, end_index..end_index]
+                };
+
+                for range in ranges {
+                    for descriptor in &descriptors[range.clone()] {
+                        descriptor.refcount.store(0, Ordering::Relaxed);
+                        descriptor
+                            .kind
+                            .store(FrameKind::Free as u8, Ordering::Relaxed);
+                        descriptor.flags.store(0, Ordering::Relaxed);
+                    }
+
+                    for index in range.clone() {
+                        allocator.managed_counts[Zone::of(index) as usize] += 1;
+                    }
+
+                    allocator.free_range(range.start, range.end);
+                }
+            }
+
+            allocator.watermarks = Watermarks::new(allocator.managed_counts.iter().sum());
+            trace!("Free frame watermarks: {:?}", allocator.watermarks);
+
+            // Attribute the frames of known (non-usable) regions to their consumers.
+            for entry in memory_map {
+                let kind = match entry.entry_type {
+                    limine::memory_map::EntryType::EXECUTABLE_AND_MODULES => FrameKind::Kernel,
+                    limine::memory_map::EntryType::FRAMEBUFFER => FrameKind::Framebuffer,
+                    _ => continue,
+                };
+
+                let start_index = usize::try_from(entry.base).unwrap() / page_size();
+                let end_index = usize::min(
+                    libsys::align_up_div(
+                        usize::try_from(entry.base + entry.length).unwrap(),
+                        page_shift(),
+                    ),
+                    total_frames,
+                );
+
+                for descriptor in &descriptors[start_index..end_index] {
+                    descriptor.kind.store(kind as u8, Ordering::Relaxed);
+                }
+            }
+
+            let kind_counts = core::array::from_fn(|_| AtomicUsize::new(0));
+            for descriptor in descriptors {
+                let kind = usize::from(descriptor.kind.load(Ordering::Relaxed));
+                *kind_counts[kind].get_mut() += 1;
+            }
+
+            let (cache_batch, cache_high_watermark) = if crate::params::use_low_memory() {
+                (LOW_MEMORY_CACHE_BATCH, LOW_MEMORY_CACHE_HIGH_WATERMARK)
+            } else {
+                (CACHE_BATCH, CACHE_HIGH_WATERMARK)
+            };
 
             Self {
-                table: InterruptCell::new(spin::RwLock::new(table)),
+                allocator: InterruptCell::new(Mutex::new(allocator)),
+                descriptors,
+                kind_counts,
                 total_frames,
+                cache_batch,
+                cache_high_watermark,
             }
         });
     }
@@ -123,9 +762,13 @@ impl PhysicalMemoryManager {
             .expect("physical memory manager has not been initialized")
     }
 
-    /// Passes the static physical memory manager's frame table to `with_fn`, returning the result.
-    fn with_table<T>(with_fn: impl FnOnce(&FrameTable) -> Result<T, Error>) -> Result<T, Error> {
-        Self::get_static().table.with(with_fn)
+    /// Passes the static physical memory manager's allocator to `with_fn`, returning the result.
+    fn with_allocator<T>(
+        with_fn: impl FnOnce(&mut BuddyAllocator) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        Self::get_static()
+            .allocator
+            .with(|allocator| with_fn(&mut allocator.lock()))
     }
 
     pub fn total_frames() -> usize {
@@ -136,85 +779,476 @@ impl PhysicalMemoryManager {
         Self::total_frames() * libsys::page_size()
     }
 
-    pub fn next_frame() -> Result<Address<Frame>, Error> {
-        Self::with_table(|table| {
-            let mut table = table.write();
-            let index = table.first_zero().ok_or(Error::NoneFree)?;
-            table.set(index, true);
+    /// Runs `with_fn` against the allocator. If it fails to allocate, memory is reclaimed
+    /// in increasingly drastic steps, and `with_fn` is run once more after each:
+    ///
+    /// 1. The per-core frame caches are drained back to the allocator.
+    /// 2. The registered shrinkers are run, to reclaim (at least) `required` frames.
+    ///
+    /// If neither frees enough, the OOM policy kills a user task. Its memory is only freed once
+    /// it's reaped outside of the allocation, so the allocation still fails.
+    fn with_allocator_or_reclaim<T>(
+        required: usize,
+        with_fn: impl Fn(&mut BuddyAllocator) -> Option<T>,
+    ) -> Result<T, Error> {
+        let try_allocate =
+            || Self::with_allocator(|allocator| with_fn(allocator).ok_or(Error::NoneFree));
+
+        if let Ok(value) = try_allocate() {
+            return Ok(value);
+        }
+
+        trace!("Global frame allocator exhausted; draining per-core frame caches.");
+        Self::drain_frame_caches();
+        if let Ok(value) = try_allocate() {
+            return Ok(value);
+        }
+
+        trace!("Global frame allocator exhausted; running shrinkers.");
+        crate::mem::reclaim::shrink(required);
+        if let Ok(value) = try_allocate() {
+            return Ok(value);
+        }
+
+        crate::mem::reclaim::out_of_memory();
+
+        Err(Error::NoneFree)
+    }
+
+    /// Runs the shrinkers if an allocation has pushed the free frames below the low watermark.
+    ///
+    /// This must be called without the allocator (or any frame cache) locked.
+    fn reclaim_if_pending() {
+        let target = RECLAIM_TARGET.swap(0, Ordering::Relaxed);
+
+        if target > 0 {
+            debug!("Free frames are below the low watermark; reclaiming {target} frames.");
+            crate::mem::reclaim::shrink(target);
+        }
+    }
+
+    /// Returns the frames held by every per-core frame cache to the global allocator,
+    /// returning the number of frames drained.
+    pub fn drain_frame_caches() -> usize {
+        FRAME_CACHES.with(|caches| caches.lock().iter().map(|cache| cache.drain()).sum())
+    }
+
+    /// Number of frames which are currently free (including those held in per-core caches).
+    pub fn free_frames() -> usize {
+        let cached_frames = FRAME_CACHES
+            .with(|caches| caches.lock().iter().map(|cache| cache.len()).sum::<usize>());
+
+        Self::with_allocator(|allocator| Ok(allocator.free_frames())).unwrap() + cached_frames
+    }
 
-            Ok(Address::new(index << page_shift().get()).unwrap())
+    fn descriptor(address: Address<Frame>) -> Result<&'static FrameDescriptor, Error> {
+        Self::get_static()
+            .descriptors
+            .get(address.index())
+            .ok_or(Error::OutOfBounds)
+    }
+
+    /// Panics if the frame at `index` is referenced (or in use as anything but a free frame), as
+    /// handing it back to the allocator would have it allocated twice.
+    fn assert_unreferenced(index: usize) {
+        let descriptor = &Self::get_static().descriptors[index];
+
+        assert!(
+            descriptor.refcount.load(Ordering::Acquire) == 0
+                && descriptor.kind.load(Ordering::Relaxed) == FrameKind::Free as u8,
+            "frame {index:#X} was freed while still in use"
+        );
+    }
+
+    /// Marks the free frames in `index..(index + count)` as referenced once by `kind`.
+    fn claim(index: usize, count: usize, kind: FrameKind) {
+        let pmm = Self::get_static();
+
+        for descriptor in &pmm.descriptors[index..(index + count)] {
+            debug_assert_eq!(descriptor.refcount.load(Ordering::Relaxed), 0);
+
+            descriptor.kind.store(kind as u8, Ordering::Relaxed);
+            descriptor.flags.store(0, Ordering::Relaxed);
+            descriptor.refcount.store(1, Ordering::Release);
+        }
+
+        pmm.kind_counts[FrameKind::Free as usize].fetch_sub(count, Ordering::Relaxed);
+        pmm.kind_counts[kind as usize].fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Returns an unreferenced frame to the local frame cache, or the global allocator.
+    fn release(index: usize) {
+        let pmm = Self::get_static();
+
+        let kind = pmm.descriptors[index]
+            .kind
+            .swap(FrameKind::Free as u8, Ordering::Relaxed);
+        pmm.kind_counts[usize::from(kind)].fetch_sub(1, Ordering::Relaxed);
+        pmm.kind_counts[FrameKind::Free as usize].fetch_add(1, Ordering::Relaxed);
+
+        if FrameCache::caches(Zone::of(index))
+            && let Some(cache) = crate::cpu::state::frame_cache()
+        {
+            cache.push(index);
+        } else {
+            Self::with_allocator(|allocator| {
+                allocator.free_block(index, 0);
+
+                Ok(())
+            })
+            .unwrap();
+        }
+    }
+
+    /// Number of frames currently being used as `kind`.
+    pub fn frames_of(kind: FrameKind) -> usize {
+        Self::get_static().kind_counts[kind as usize].load(Ordering::Relaxed)
+    }
+
+    /// Frame usage statistics, broken down by consumer.
+    ///
+    /// # Remark
+    ///
+    /// The counters are updated without synchronization between them, so a snapshot taken
+    /// while frames are being allocated or freed may be momentarily inconsistent.
+    pub fn memory_stats() -> MemoryStats {
+        let frames_by_kind = FrameKind::ALL.map(Self::frames_of);
+        let free_frames = frames_by_kind[FrameKind::Free as usize];
+        let reserved_frames = frames_by_kind[FrameKind::Reserved as usize];
+        let total_frames = Self::total_frames();
+
+        MemoryStats {
+            total_frames,
+            free_frames,
+            used_frames: total_frames.saturating_sub(free_frames + reserved_frames),
+            reserved_frames,
+            frames_by_kind,
+        }
+    }
+
+    /// Per-zone frame statistics.
+    pub fn zone_stats() -> [ZoneStats; Zone::ALL.len()] {
+        let cached_frames = Zone::ALL.map(|zone| {
+            FRAME_CACHES.with(|caches| {
+                caches
+                    .lock()
+                    .iter()
+                    .map(|cache| cache.len_in(zone))
+                    .sum::<usize>()
+            })
+        });
+
+        Self::with_allocator(|allocator| {
+            Ok(Zone::ALL.map(|zone| ZoneStats {
+                zone,
+                managed_frames: allocator.managed_counts[zone as usize],
+                free_frames: allocator.free_counts[zone as usize] + cached_frames[zone as usize],
+            }))
         })
+        .unwrap()
+    }
+
+    pub fn next_frame(kind: FrameKind) -> Result<Address<Frame>, Error> {
+        let index = match crate::cpu::state::frame_cache().and_then(|cache| cache.pop(Zone::Normal))
+        {
+            Some(index) => index,
+            None => {
+                Self::with_allocator_or_reclaim(1, |allocator| allocator.allocate(0, Zone::Normal))?
+            }
+        };
+
+        Self::claim(index, 1, kind);
+        Self::reclaim_if_pending();
+
+        Ok(Address::from_index(index).unwrap())
     }
 
+    /// Allocates `count` physically contiguous frames, with the first frame aligned
+    /// to `align_bits` (in bytes, which must be a power-of-two).
     pub fn next_frames(
         count: NonZeroUsize,
         align_bits: Option<NonZeroU32>,
+        kind: FrameKind,
     ) -> Result<Address<Frame>, Error> {
-        Self::with_table(|table| {
-            let mut table = table.write();
-
-            let align_bits = align_bits.unwrap_or(NonZeroU32::MIN).get();
-            let align_index_skip = u32::max(1, align_bits >> page_shift().get());
-
-            let free_frames_index = table
-                .windows(count.get())
-                .enumerate()
-                .step_by(align_index_skip.try_into().unwrap())
-                .find_map(|(index, window)| window.not_any().then_some(index))
-                .ok_or(Error::NoneFree)?;
-
-            // It's a bit uglier to find the index of the window, then effectively reacreate it. However, `.windows()`
-            // does not return a mutable bitslice, so this is how it must be done.
-            let free_frames = table
-                .get_mut(free_frames_index..(free_frames_index + count.get()))
-                .unwrap();
-            free_frames.fill(true);
+        Self::next_frames_in(count, align_bits, kind, Zone::Normal)
+    }
 
-            Ok(Address::new(free_frames_index << page_shift().get()).unwrap())
-        })
+    /// Allocates `count` physically contiguous frames from within `zone` (or a more
+    /// constrained zone), with the first frame aligned to `align_bits` (in bytes, which
+    /// must be a power-of-two).
+    pub fn next_frames_in(
+        count: NonZeroUsize,
+        align_bits: Option<NonZeroU32>,
+        kind: FrameKind,
+        zone: Zone,
+    ) -> Result<Address<Frame>, Error> {
+        let align_bits = align_bits.unwrap_or(NonZeroU32::MIN);
+        if !align_bits.is_power_of_two() {
+            return Err(Error::InvalidAlignment);
+        }
+
+        let align_frames = usize::max(1, (align_bits.get() >> page_shift().get()) as usize);
+        let order = usize::max(
+            count.get().next_power_of_two().trailing_zeros() as usize,
+            align_frames.trailing_zeros() as usize,
+        );
+
+        if order > MAX_ORDER {
+            return Err(Error::NoneFree);
+        }
+
+        let index = Self::with_allocator_or_reclaim(count.get(), |allocator| {
+            let index = allocator.allocate(order, zone)?;
+
+            // Return the frames beyond the requested count.
+            allocator.free_range(index + count.get(), index + (1 << order));
+
+            Some(index)
+        })?;
+
+        Self::claim(index, count.get(), kind);
+        Self::reclaim_if_pending();
+
+        Ok(Address::from_index(index).unwrap())
     }
 
-    pub fn lock_frame(address: Address<Frame>) -> Result<(), Error> {
-        Self::with_table(|table| {
-            let table = table.read();
-            let index = address.index();
+    /// Allocates the specific frame at `address`, which must be free.
+    pub fn lock_frame(address: Address<Frame>, kind: FrameKind) -> Result<(), Error> {
+        let index = address.index();
+        if index >= Self::total_frames() {
+            return Err(Error::OutOfBounds);
+        }
 
-            // The table may have more bits than there are frames due to the
-            // padding effect of using a `usize` as the underlying data type.
-            if index < Self::total_frames() {
-                // if the frame is free...
-                if table[index] {
-                    table.set_aliased(index, true);
+        match Self::with_allocator(|allocator| allocator.lock(index)) {
+            Ok(()) => {}
 
-                    Ok(())
+            // The frame may be free, but held in a per-core cache.
+            Err(Error::NotFree)
+                if FRAME_CACHES
+                    .with(|caches| caches.lock().iter().any(|cache| cache.take(index))) => {}
+
+            Err(error) => return Err(error),
+        }
+
+        Self::claim(index, 1, kind);
+        Self::reclaim_if_pending();
+
+        Ok(())
+    }
+
+    /// Takes an additional reference to an allocated frame, returning the new reference count.
+    pub fn get_frame(address: Address<Frame>) -> Result<u32, Error> {
+        Self::descriptor(address)?
+            .refcount
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |refcount| {
+                // A frame without references is free, and so can't gain new ones.
+                if refcount == 0 {
+                    None
                 } else {
-                    Err(Error::NotFree)
+                    Some(
+                        refcount
+                            .checked_add(1)
+                            .expect("frame reference count overflowed"),
+                    )
                 }
-            } else {
-                Err(Error::OutOfBounds)
-            }
-        })
+            })
+            .map(|refcount| refcount + 1)
+            .map_err(|_| Error::NotLocked)
     }
 
+    /// Drops a reference to an allocated frame, freeing it once it's no longer referenced.
+    /// Returns whether the frame was freed.
+    pub fn put_frame(address: Address<Frame>) -> Result<bool, Error> {
+        let refcount = Self::descriptor(address)?
+            .refcount
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |refcount| {
+                refcount.checked_sub(1)
+            })
+            .map_err(|_| Error::NotLocked)?;
+
+        if refcount == 1 {
+            Self::release(address.index());
+
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Drops a reference to an allocated frame, freeing it once it's no longer referenced.
     pub fn free_frame(address: Address<Frame>) -> Result<(), Error> {
-        Self::with_table(|table| {
-            let table = table.read();
-            let index = address.index();
-
-            // The table may have more bits than there are frames due to the
-            // padding effect of using a `usize` as the underlying data type.
-            if index < Self::total_frames() {
-                // if the frame is locked...
-                if table[index] {
-                    Err(Error::NotLocked)
-                } else {
-                    table.set_aliased(index, false);
+        Self::put_frame(address).map(|_| ())
+    }
 
-                    Ok(())
+    pub fn frame_info(address: Address<Frame>) -> Result<FrameInfo, Error> {
+        let descriptor = Self::descriptor(address)?;
+
+        Ok(FrameInfo {
+            refcount: descriptor.refcount.load(Ordering::Acquire),
+            kind: FrameKind::try_from(descriptor.kind.load(Ordering::Relaxed)).unwrap(),
+            flags: FrameFlags::from_bits_retain(descriptor.flags.load(Ordering::Relaxed)),
+        })
+    }
+
+    pub fn insert_frame_flags(address: Address<Frame>, flags: FrameFlags) -> Result<(), Error> {
+        Self::descriptor(address)?
+            .flags
+            .fetch_or(flags.bits(), Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    pub fn remove_frame_flags(address: Address<Frame>, flags: FrameFlags) -> Result<(), Error> {
+        Self::descriptor(address)?
+            .flags
+            .fetch_and(!flags.bits(), Ordering::Relaxed);
+
+        Ok(())
+    }
+}
+
+crate::kernel_test! {
+    fn pmm_lock_and_free() {
+        let frame = PhysicalMemoryManager::next_frame(FrameKind::Kernel)
+            .map_err(|err| alloc::format!("{err}"))?;
+
+        crate::ktest_assert_eq!(
+            PhysicalMemoryManager::lock_frame(frame, FrameKind::Kernel),
+            Err(Error::NotFree)
+        );
+        crate::ktest_assert_eq!(PhysicalMemoryManager::free_frame(frame), Ok(()));
+        crate::ktest_assert_eq!(PhysicalMemoryManager::free_frame(frame), Err(Error::NotLocked));
+        crate::ktest_assert_eq!(
+            PhysicalMemoryManager::lock_frame(frame, FrameKind::Kernel),
+            Ok(())
+        );
+        crate::ktest_assert_eq!(PhysicalMemoryManager::free_frame(frame), Ok(()));
+
+        Ok(())
+    }
+}
+
+crate::kernel_test! {
+    fn pmm_contiguous_aligned() {
+        let free_before = PhysicalMemoryManager::free_frames();
+
+        let count = NonZeroUsize::new(3).unwrap();
+        let align = NonZeroU32::new(0x10000).unwrap();
+        let frames = PhysicalMemoryManager::next_frames(count, Some(align), FrameKind::Dma)
+            .map_err(|err| alloc::format!("{err}"))?;
+
+        crate::ktest_assert_eq!(frames.get().get() & 0xFFFF, 0);
+        crate::ktest_assert_eq!(PhysicalMemoryManager::free_frames(), free_before - 3);
+
+        for index in frames.index()..(frames.index() + 3) {
+            let frame = Address::from_index(index).unwrap();
+            crate::ktest_assert_eq!(PhysicalMemoryManager::free_frame(frame), Ok(()));
+        }
+
+        crate::ktest_assert_eq!(PhysicalMemoryManager::free_frames(), free_before);
+        crate::ktest_assert_eq!(
+            PhysicalMemoryManager::next_frames(count, NonZeroU32::new(3), FrameKind::Dma),
+            Err(Error::InvalidAlignment)
+        );
+
+        Ok(())
+    }
+}
+
+crate::kernel_test! {
+    fn pmm_frame_refcount() {
+        let frame = PhysicalMemoryManager::next_frame(FrameKind::UserAnon)
+            .map_err(|err| alloc::format!("{err}"))?;
+
+        let info = PhysicalMemoryManager::frame_info(frame).map_err(|err| alloc::format!("{err}"))?;
+        crate::ktest_assert_eq!(info.refcount, 1);
+        crate::ktest_assert_eq!(info.kind, FrameKind::UserAnon);
+
+        crate::ktest_assert_eq!(PhysicalMemoryManager::get_frame(frame), Ok(2));
+        crate::ktest_assert_eq!(PhysicalMemoryManager::put_frame(frame), Ok(false));
+        crate::ktest_assert_eq!(PhysicalMemoryManager::put_frame(frame), Ok(true));
+        crate::ktest_assert_eq!(PhysicalMemoryManager::put_frame(frame), Err(Error::NotLocked));
+        crate::ktest_assert_eq!(PhysicalMemoryManager::get_frame(frame), Err(Error::NotLocked));
+
+        Ok(())
+    }
+}
+
+crate::kernel_test! {
+    fn pmm_zone_constraints() {
+        let count = NonZeroUsize::MIN;
+
+        for zone in [Zone::Dma32, Zone::Low1M] {
+            match PhysicalMemoryManager::next_frames_in(count, None, FrameKind::Dma, zone) {
+                Ok(frame) => {
+                    crate::ktest_assert!(frame.index() < zone.end_index());
+                    crate::ktest_assert_eq!(PhysicalMemoryManager::free_frame(frame), Ok(()));
                 }
-            } else {
-                Err(Error::OutOfBounds)
+
+                // Small machines may have no free memory in the zone.
+                Err(Error::NoneFree) => {}
+
+                Err(error) => return Err(alloc::format!("{error}")),
             }
-        })
+        }
+
+        let managed_frames = PhysicalMemoryManager::zone_stats()
+            .iter()
+            .map(|stats| stats.managed_frames)
+            .sum::<usize>();
+        crate::ktest_assert!(managed_frames >= PhysicalMemoryManager::free_frames());
+
+        Ok(())
+    }
+}
+
+crate::kernel_test! {
+    fn pmm_memory_stats() {
+        let stats = PhysicalMemoryManager::memory_stats();
+        crate::ktest_assert_eq!(
+            stats.free_frames + stats.used_frames + stats.reserved_frames,
+            stats.total_frames
+        );
+        crate::ktest_assert_eq!(
+            FrameKind::ALL.iter().map(|&kind| stats.frames_of(kind)).sum::<usize>(),
+            stats.total_frames
+        );
+
+        let page_table_frames = PhysicalMemoryManager::frames_of(FrameKind::PageTable);
+        let frame = PhysicalMemoryManager::next_frame(FrameKind::PageTable)
+            .map_err(|err| alloc::format!("{err}"))?;
+        crate::ktest_assert_eq!(
+            PhysicalMemoryManager::frames_of(FrameKind::PageTable),
+            page_table_frames + 1
+        );
+
+        crate::ktest_assert_eq!(PhysicalMemoryManager::free_frame(frame), Ok(()));
+        crate::ktest_assert_eq!(
+            PhysicalMemoryManager::frames_of(FrameKind::PageTable),
+            page_table_frames
+        );
+
+        Ok(())
+    }
+}
+
+crate::kernel_test! {
+    fn pmm_frame_cache_reuse() {
+        let cache = crate::cpu::state::frame_cache().ok_or("no frame cache")?;
+        // Start from empty caches, so freeing a frame can't push the cache over its high watermark.
+        PhysicalMemoryManager::drain_frame_caches();
+
+        let frame = PhysicalMemoryManager::next_frame(FrameKind::Kernel)
+            .map_err(|err| alloc::format!("{err}"))?;
+        let cached_frames = cache.len();
+
+        // Whatever zone the frame is from (machines with at most 4 GiB have no `Zone::Normal`),
+        // freeing it places it in the cache, and it's the next frame handed out.
+        crate::ktest_assert_eq!(PhysicalMemoryManager::free_frame(frame), Ok(()));
+        crate::ktest_assert_eq!(cache.len(), cached_frames + 1);
+        crate::ktest_assert_eq!(PhysicalMemoryManager::next_frame(FrameKind::Kernel), Ok(frame));
+        crate::ktest_assert_eq!(PhysicalMemoryManager::free_frame(frame), Ok(()));
+
+        Ok(())
     }
 }
@@ -0,0 +1,196 @@
+//! Memory pressure handling.
+//!
+//! Subsystems register shrinkers (e.g. to drain caches, or drop data only kept for
+//! debugging), which the physical memory manager runs once its free frames fall below the
+//! low watermark. If shrinking can't satisfy an allocation, the OOM policy kills the user
+//! task with the most resident pages, rather than failing the allocation outright.
+
+use crate::interrupts::InterruptCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+/// Maximum number of shrinkers which can be registered.
+const MAX_SHRINKERS: usize = 16;
+
+/// Attempts to free (at least) `target` frames, returning the number of frames actually freed.
+pub type Shrinker = fn(target: usize) -> usize;
+
+#[derive(Clone, Copy)]
+struct Registration {
+    name: &'static str,
+    shrink: Shrinker,
+}
+
+struct Shrinkers {
+    registrations: [Option<Registration>; MAX_SHRINKERS],
+    len: usize,
+}
+
+// Shrinkers are kept in a fixed-size table, so registering one never allocates (and so
+// can't itself trigger a reclaim while holding the lock).
+static SHRINKERS: InterruptCell<Mutex<Shrinkers>> = InterruptCell::new(Mutex::new(Shrinkers {
+    registrations: [None; MAX_SHRINKERS],
+    len: 0,
+}));
+
+/// Set while reclaiming, so allocations made by a shrinker (or the OOM policy) don't
+/// recursively reclaim.
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
+
+/// Free frame counts which drive reclaim, relative to the memory managed by the
+/// physical memory manager.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watermarks {
+    /// Free frame count below which the shrinkers are run.
+    pub low: usize,
+    /// Free frame count the shrinkers attempt to restore.
+    pub high: usize,
+}
+
+impl Watermarks {
+    pub fn new(managed_frames: usize) -> Self {
+        // Low-memory mode keeps smaller reserves, with a narrower band between the
+        // watermarks, so less memory sits idle on small machines.
+        if crate::params::use_low_memory() {
+            let low = usize::max(managed_frames / 256, 16);
+
+            Self {
+                low,
+                high: low + (low / 2),
+            }
+        } else {
+            let low = usize::max(managed_frames / 64, 64);
+
+            Self { low, high: low * 2 }
+        }
+    }
+}
+
+/// Registers a shrinker, to be run whenever memory is low. Shrinkers run in the order
+/// they're registered, so cheaper shrinkers should be registered first.
+///
+/// # Remark
+///
+/// Shrinkers are run from within frame allocations, so they must never block on a lock
+/// which might be held while allocating (i.e. prefer `try_lock()`).
+pub fn register_shrinker(name: &'static str, shrink: Shrinker) {
+    SHRINKERS.with(|shrinkers| {
+        let mut shrinkers = shrinkers.lock();
+
+        let len = shrinkers.len;
+        let registration = shrinkers
+            .registrations
+            .get_mut(len)
+            .expect("shrinker table is full");
+        *registration = Some(Registration { name, shrink });
+        shrinkers.len += 1;
+    });
+
+    debug!("Registered shrinker: {name}");
+}
+
+/// Runs `func`, unless a reclaim is already in progress (on any hardware thread).
+fn reclaim_exclusive<T>(func: impl FnOnce() -> T) -> Option<T> {
+    if RECLAIMING.swap(true, Ordering::Acquire) {
+        return None;
+    }
+
+    let result = func();
+    RECLAIMING.store(false, Ordering::Release);
+
+    Some(result)
+}
+
+/// Runs the registered shrinkers until `target` frames have been freed, returning the
+/// number of frames freed.
+pub fn shrink(target: usize) -> usize {
+    reclaim_exclusive(|| {
+        // Copy out the registrations, so the shrinkers don't run with the table locked.
+        let registrations = SHRINKERS.with(|shrinkers| shrinkers.lock().registrations);
+
+        let mut freed = 0;
+        for Registration { name, shrink } in registrations.into_iter().flatten() {
+            if freed >= target {
+                break;
+            }
+
+            let shrinker_freed = shrink(target - freed);
+            trace!("Shrinker {name} freed {shrinker_freed} frames.");
+
+            freed += shrinker_freed;
+        }
+
+        debug!("Reclaimed {freed} of {target} requested frames.");
+
+        freed
+    })
+    .unwrap_or(0)
+}
+
+/// Kills the queued user task with the most resident pages, returning whether a task was killed.
+///
+/// # Remark
+///
+/// Only tasks waiting in the global task queue are considered, as tasks which are
+/// currently executing can't be safely torn down from another hardware thread.
+///
+/// This is run from within frame allocations, whose callers may hold the locks tearing a task down
+/// requires (e.g. the kernel mapper), so the task is handed to the reaper rather than torn down here.
+/// Its memory is freed once the scheduler next reaps (see [`crate::task::reap_pending`]).
+pub fn out_of_memory() -> bool {
+    reclaim_exclusive(|| {
+        // The task queue may be locked by the allocating context, in which case no task can be killed.
+        let Some(mut processes) = crate::task::PROCESSES.try_lock() else {
+            warn!("Out of memory, but the task queue is locked; no task can be killed.");
+            return false;
+        };
+
+        let Some((index, resident_pages)) = processes
+            .iter()
+            .map(|task| task.address_space().resident_pages())
+            .enumerate()
+            .max_by_key(|&(_, resident_pages)| resident_pages)
+        else {
+            warn!("Out of memory, and there are no tasks to kill.");
+            return false;
+        };
+
+        let task = processes.remove(index).unwrap();
+        let id = task.id();
+
+        match crate::task::defer_reap(task) {
+            Ok(()) => {
+                warn!(
+                    "Out of memory: killed task {id:?} ({resident_pages} resident pages), pending reaping."
+                );
+
+                true
+            }
+
+            Err(task) => {
+                // Earlier victims haven't been reaped yet, so killing another wouldn't help.
+                processes.insert(index, task);
+                warn!("Out of memory, but killed tasks are still awaiting reaping.");
+
+                false
+            }
+        }
+    })
+    .unwrap_or(false)
+}
+
+crate::kernel_test! {
+    fn reclaim_watermarks() {
+        for managed_frames in [0, 0x1000, 0x10_0000] {
+            let watermarks = Watermarks::new(managed_frames);
+
+            crate::ktest_assert!(watermarks.low > 0);
+            crate::ktest_assert!(watermarks.low < watermarks.high);
+        }
+
+        // Shrinking is a no-op while a reclaim is already in progress.
+        crate::ktest_assert_eq!(reclaim_exclusive(|| shrink(usize::MAX)), Some(0));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+//! Cross-CPU TLB shootdowns.
+//!
+//! Hardware threads only ever invalidate their own TLBs, so whenever a mapping is changed or
+//! removed, every other hardware thread which may have the address space loaded is sent an IPI
+//! to invalidate the affected pages. The initiator then waits until each target acknowledges,
+//! so the caller can be sure no stale translations remain (e.g. before freeing the frames).
+
+use crate::{interrupts::InterruptCell, mem::pcid};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use libsys::{Address, Frame, Page};
+use spin::Mutex;
+
+/// Number of pages above which targets flush their entire TLB, rather than each page.
+pub const FULL_FLUSH_THRESHOLD: usize = 32;
+
+/// Shootdown state of a single hardware thread.
+pub struct ShootdownState {
+    apic_id: u32,
+    /// Root page table frame currently loaded by the hardware thread.
+    active_root: AtomicUsize,
+    /// Set by the initiator of a shootdown, and cleared by the target once it's flushed.
+    pending: AtomicBool,
+}
+
+struct Targets {
+    states: [Option<&'static ShootdownState>; crate::cpu::MAX_HWTHREADS],
+    len: usize,
+}
+
+impl Targets {
+    fn iter(&self) -> impl Iterator<Item = &'static ShootdownState> + '_ {
+        self.states[..self.len].iter().flatten().copied()
+    }
+}
+
+/// Every registered hardware thread, which may be the target of a shootdown.
+///
+/// Targets are kept in a fixed-size table, so registering one never allocates (and so can't reach
+/// reclaim while holding the lock).
+static TARGETS: InterruptCell<Mutex<Targets>> = InterruptCell::new(Mutex::new(Targets {
+    states: [None; crate::cpu::MAX_HWTHREADS],
+    len: 0,
+}));
+
+/// Only one shootdown may be in flight at a time, as targets share the request below. It's
+/// only ever locked with interrupts disabled.
+static SHOOTDOWN: Mutex<()> = Mutex::new(());
+static REQUEST_PAGE: AtomicUsize = AtomicUsize::new(0);
+static REQUEST_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+impl ShootdownState {
+    pub fn new(apic_id: u32, active_root: Address<Frame>) -> Self {
+        Self {
+            apic_id,
+            active_root: AtomicUsize::new(active_root.get().get()),
+            pending: AtomicBool::new(false),
+        }
+    }
+
+    /// Registers the state as a shootdown target.
+    pub fn register(state: &'static Self) {
+        TARGETS.with(|targets| {
+            let mut targets = targets.lock();
+
+            let len = targets.len;
+            *targets
+                .states
+                .get_mut(len)
+                .expect("shootdown target table is full") = Some(state);
+            targets.len += 1;
+        });
+    }
+
+    /// Records the root page table frame which has been loaded by the hardware thread.
+    pub fn set_active_root(&self, root_frame: Address<Frame>) {
+        self.active_root
+            .store(root_frame.get().get(), Ordering::SeqCst);
+    }
+
+    fn has_loaded(&self, root_frame: Address<Frame>) -> bool {
+        self.active_root.load(Ordering::SeqCst) == root_frame.get().get()
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::Acquire)
+    }
+}
+
+fn is_kernel_page(page: usize) -> bool {
+    (page & (1 << 63)) != 0
+}
+
+/// Invalidates `page_count` pages from `page` on the current hardware thread, or the entire TLB if
+/// there are more than [`FULL_FLUSH_THRESHOLD`].
+fn flush_local(page: usize, page_count: usize) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        use crate::arch::x86_64::{
+            instructions::tlb,
+            registers::control::{CR3, CR4, CR4Flags},
+        };
+
+        let is_kernel_page = is_kernel_page(page);
+        let pages = (0..page_count)
+            .map(|offset| Address::<Page>::new_truncate(page + (offset * libsys::page_size())));
+
+        if page_count > FULL_FLUSH_THRESHOLD {
+            if is_kernel_page {
+                tlb::flush_all();
+            } else {
+                tlb::flush_context();
+            }
+        } else if is_kernel_page && CR4::read().contains(CR4Flags::PCIDE) {
+            // `invlpg` only invalidates the current PCID (and global pages), whereas kernel pages may
+            // be cached under any PCID assigned by this hardware thread.
+            if tlb::has_invpcid() {
+                let current_pcid = CR3::read_pcid();
+                let assigned_pcids =
+                    crate::cpu::state::with_pcid_allocator(|pcids| pcids.assigned())
+                        .unwrap_or_default();
+
+                for page in pages {
+                    tlb::invlpg(page);
+
+                    for pcid in assigned_pcids.clone().filter(|&pcid| pcid != current_pcid) {
+                        // Safety: `INVPCID` is supported.
+                        unsafe { tlb::invpcid(tlb::InvpcidKind::Address, pcid, page) };
+                    }
+                }
+            } else {
+                tlb::flush_all();
+            }
+        } else {
+            pages.for_each(tlb::invlpg);
+        }
+    }
+}
+
+/// Services a pending shootdown request for the current hardware thread, if there is one.
+///
+/// This is called from the shootdown IPI handler, and should also be polled by any
+/// context which spins with interrupts disabled, so it can't stall an initiator.
+pub fn service_pending() {
+    let Some(state) = crate::cpu::state::shootdown_state() else {
+        return;
+    };
+
+    if state.is_pending() {
+        flush_local(
+            REQUEST_PAGE.load(Ordering::Acquire),
+            REQUEST_COUNT.load(Ordering::Acquire),
+        );
+
+        // Acknowledge the request.
+        state.pending.store(false, Ordering::Release);
+    }
+}
+
+/// Invalidates `page_count` pages from `page` within the address space rooted at `root_frame`, on
+/// every hardware thread which may have cached translations for them.
+///
+/// Pages in the higher half are shared by every address space, so they're invalidated on every
+/// hardware thread. Hardware threads which only have the address space cached under a PCID are
+/// left to flush it when they next load it.
+pub fn shootdown(
+    root_frame: Address<Frame>,
+    context: &pcid::Context,
+    page: Address<Page>,
+    page_count: usize,
+) {
+    if page_count == 0 {
+        return;
+    }
+
+    let page = page.get().get();
+    let is_kernel_page = is_kernel_page(page);
+
+    if !is_kernel_page {
+        // This must happen before the targets are selected; see `Mapper::swap_into`.
+        context.invalidate();
+    }
+
+    crate::interrupts::without(|| {
+        flush_local(page, page_count);
+
+        let Some(local_state) = crate::cpu::state::shootdown_state() else {
+            // Other hardware threads can't be targeted until this one has its core-local state.
+            return;
+        };
+
+        let _shootdown = loop {
+            if let Some(guard) = SHOOTDOWN.try_lock() {
+                break guard;
+            }
+
+            // Another hardware thread may be waiting on this one to flush.
+            service_pending();
+            core::hint::spin_loop();
+        };
+
+        REQUEST_PAGE.store(page, Ordering::Release);
+        REQUEST_COUNT.store(page_count, Ordering::Release);
+
+        TARGETS.with(|targets| {
+            let targets = targets.lock();
+            let is_target = |target: &&ShootdownState| {
+                !core::ptr::eq(*target, local_state)
+                    && (is_kernel_page || target.has_loaded(root_frame))
+            };
+
+            for target in targets.iter().filter(is_target) {
+                target.pending.store(true, Ordering::Release);
+
+                // Safety: The shootdown vector is handled by every hardware thread.
+                unsafe {
+                    crate::cpu::state::send_ipi(
+                        target.apic_id,
+                        crate::interrupts::Vector::TlbShootdown,
+                    );
+                }
+            }
+
+            // Wait for every target to acknowledge.
+            for target in targets.iter() {
+                while target.is_pending() {
+                    core::hint::spin_loop();
+                }
+            }
+        });
+    });
+}
+
+crate::kernel_test! {
+    fn tlb_shootdown_acknowledged() {
+        let root_frame = crate::mem::PagingRegister::read().frame();
+        let page = Address::<Page>::new_truncate(crate::mem::Hhdm::offset().get());
+
+        let context = pcid::Context::new();
+
+        for page_count in [1, FULL_FLUSH_THRESHOLD + 1] {
+            shootdown(root_frame, &context, page, page_count);
+
+            crate::ktest_assert!(TARGETS.with(|targets| {
+                targets.lock().iter().all(|target| !target.is_pending())
+            }));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,286 @@
+//! Kernel virtual memory allocations.
+//!
+//! Allocations are placed in a dedicated area of the kernel's address space, and each of their pages
+//! is mapped to whichever frame is free, so they never require a physically contiguous run of frames.
+//! Every allocation is preceded by an unmapped guard page, so running off the end of one (or the
+//! bottom of a stack) faults, rather than silently corrupting its neighbour.
+
+use crate::{
+    interrupts::InterruptCell,
+    mem::{
+        paging::{CacheMode, TableDepth, TableEntryFlags},
+        pmm::FrameKind,
+    },
+};
+use core::{
+    num::NonZeroUsize,
+    ops::Range,
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use libsys::{Address, Page, Virtual, page_size, table_index_size};
+use spin::Mutex;
+
+/// Number of unmapped pages preceding each allocation.
+pub const GUARD_PAGES: usize = 1;
+
+/// Maximum number of allocations which may exist at once.
+const MAX_RESERVATIONS: usize = 0x1000;
+
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    #[error("kernel virtual allocations can't be made until the kernel page tables are loaded")]
+    Unavailable,
+
+    #[error("the kernel virtual area has no free range large enough")]
+    NoneFree,
+
+    #[error("too many kernel virtual allocations exist")]
+    TooManyAllocations,
+
+    #[error("failed to map the allocation")]
+    Paging(#[from] crate::mem::paging::Error),
+
+    #[error("the pointer was not returned by a kernel virtual allocation")]
+    NotAllocated,
+}
+
+/// Set once the kernel page tables are loaded, as allocations are only mapped in those.
+static AVAILABLE: AtomicBool = AtomicBool::new(false);
+
+/// Reserved ranges of the area, as their start address and page count (including their guard pages),
+/// sorted by start address.
+struct Reservations {
+    ranges: [(usize, usize); MAX_RESERVATIONS],
+    len: usize,
+}
+
+impl Reservations {
+    fn ranges(&self) -> &[(usize, usize)] {
+        &self.ranges[..self.len]
+    }
+
+    /// Page count of the range reserved at `start`, if any.
+    fn get(&self, start: usize) -> Option<usize> {
+        let ranges = self.ranges();
+        let index = ranges
+            .binary_search_by_key(&start, |&(start, _)| start)
+            .ok()?;
+
+        Some(ranges[index].1)
+    }
+
+    /// Reserves `page_count` pages at `start`, which must not overlap any reserved range.
+    fn insert(&mut self, start: usize, page_count: usize) -> Result<(), Error> {
+        if self.len == MAX_RESERVATIONS {
+            return Err(Error::TooManyAllocations);
+        }
+
+        let index = self
+            .ranges()
+            .partition_point(|&(range_start, _)| range_start < start);
+        self.ranges.copy_within(index..self.len, index + 1);
+        self.ranges[index] = (start, page_count);
+        self.len += 1;
+
+        Ok(())
+    }
+
+    fn remove(&mut self, start: usize) {
+        if let Ok(index) = self
+            .ranges()
+            .binary_search_by_key(&start, |&(start, _)| start)
+        {
+            self.ranges.copy_within((index + 1)..self.len, index);
+            self.len -= 1;
+        }
+    }
+}
+
+/// Reservations are kept in a fixed-size table, so reserving a range never allocates (and so can't
+/// reach reclaim, which may free allocations, while holding the lock).
+static RESERVED: InterruptCell<Mutex<Reservations>> =
+    InterruptCell::new(Mutex::new(Reservations {
+        ranges: [(0, 0); MAX_RESERVATIONS],
+        len: 0,
+    }));
+
+/// The virtual address range allocations are made within. This is the second-to-last root table
+/// entry (as the last holds the kernel executable), from a random offset (see [`crate::mem::kaslr`]).
+pub fn area() -> Range<usize> {
+    let entry_size = TableDepth::max().next().align();
+    // Addresses in the higher half are sign-extended from the root table's span.
+    let sign_extension = !((entry_size * table_index_size()) - 1);
+
+    let start = sign_extension | ((table_index_size() - 2) * entry_size);
+    (start + crate::mem::kaslr::vmalloc_offset())..(start + entry_size)
+}
+
+/// Whether `address` is within the kernel virtual area (though it may not be mapped).
+pub fn contains(address: Address<Virtual>) -> bool {
+    area().contains(&address.get())
+}
+
+/// Marks the kernel virtual area as usable.
+///
+/// ## Safety
+///
+/// The kernel page tables must be loaded on the current hardware thread, and must be loaded by any
+/// other hardware thread before it touches kernel virtual allocations.
+pub unsafe fn set_available() {
+    AVAILABLE.store(true, Ordering::Release);
+}
+
+pub fn is_available() -> bool {
+    AVAILABLE.load(Ordering::Acquire)
+}
+
+/// Finds and reserves the lowest free range of `page_count` pages in the area.
+fn reserve(page_count: usize) -> Result<usize, Error> {
+    let area = area();
+    let len = page_count * page_size();
+
+    RESERVED.with(|reserved| {
+        let mut reserved = reserved.lock();
+
+        let mut range_start = area.start;
+        for &(start, count) in reserved.ranges() {
+            if start >= range_start + len {
+                break;
+            }
+
+            range_start = start + (count * page_size());
+        }
+
+        if (range_start + len) > area.end {
+            return Err(Error::NoneFree);
+        }
+
+        reserved.insert(range_start, page_count)?;
+
+        Ok(range_start)
+    })
+}
+
+/// Allocates `page_count` pages of writable kernel memory, which needn't be physically contiguous.
+pub fn allocate(page_count: NonZeroUsize) -> Result<NonNull<[u8]>, Error> {
+    if !is_available() {
+        return Err(Error::Unavailable);
+    }
+
+    let range_start = reserve(page_count.get() + GUARD_PAGES)?;
+    let start = range_start + (GUARD_PAGES * page_size());
+    let page = Address::<Page>::new(start).unwrap();
+
+    let result = crate::mem::with_kmapper(|kmapper| {
+        let mut mapped_count = 0;
+        let result = (0..page_count.get())
+            .map(|offset| Address::new(start + (offset * page_size())).unwrap())
+            .try_for_each(|page| {
+                kmapper.auto_map(
+                    page,
+                    FrameKind::KernelHeap,
+                    TableEntryFlags::RW,
+                    CacheMode::WriteBack,
+                )?;
+                mapped_count += 1;
+
+                Ok(())
+            });
+
+        if result.is_err() {
+            // Safety: The pages were only just mapped, so nothing references them.
+            unsafe { kmapper.unmap_range(page, mapped_count, true) }.unwrap();
+        }
+
+        result
+    });
+
+    if let Err(error) = result {
+        RESERVED.with(|reserved| reserved.lock().remove(range_start));
+
+        return Err(Error::Paging(error));
+    }
+
+    Ok(NonNull::slice_from_raw_parts(
+        NonNull::new(page.as_ptr()).unwrap(),
+        page_count.get() * page_size(),
+    ))
+}
+
+/// Unmaps and frees an allocation made with [`allocate`].
+///
+/// ## Safety
+///
+/// - `ptr` must be the start of an allocation made with [`allocate`].
+/// - Nothing may reference the allocation's memory after it's freed.
+pub unsafe fn free(ptr: NonNull<u8>) -> Result<(), Error> {
+    let Some(range_start) = ptr.addr().get().checked_sub(GUARD_PAGES * page_size()) else {
+        return Err(Error::NotAllocated);
+    };
+
+    let page_count = RESERVED
+        .with(|reserved| reserved.lock().get(range_start))
+        .ok_or(Error::NotAllocated)?
+        - GUARD_PAGES;
+
+    crate::mem::with_kmapper(|kmapper| {
+        // Safety: Caller is required to ensure nothing references the allocation.
+        unsafe { kmapper.unmap_range(Address::new(ptr.addr().get()).unwrap(), page_count, true) }
+    })?;
+
+    // The range can only be reused once its pages are unmapped everywhere.
+    RESERVED.with(|reserved| reserved.lock().remove(range_start));
+
+    Ok(())
+}
+
+crate::kernel_test! {
+    fn vmalloc_guard_pages() {
+        let page_count = NonZeroUsize::new(4).unwrap();
+        let allocation = allocate(page_count).map_err(|err| alloc::format!("{err}"))?;
+        let start = allocation.as_non_null_ptr().addr().get();
+        let guard_page = Address::<Page>::new(start - page_size()).unwrap();
+        let pages = (0..page_count.get())
+            .map(|offset| Address::<Page>::new(start + (offset * page_size())).unwrap());
+
+        crate::ktest_assert!(contains(guard_page.get()));
+        crate::ktest_assert!(crate::mem::with_kmapper(|kmapper| {
+            !kmapper.is_mapped(guard_page, None)
+                && pages.clone().all(|page| kmapper.is_mapped(page, None))
+        }));
+
+        // Safety: The allocation isn't referenced.
+        unsafe { free(allocation.as_non_null_ptr()) }.map_err(|err| alloc::format!("{err}"))?;
+
+        crate::ktest_assert!(crate::mem::with_kmapper(|kmapper| {
+            pages.clone().all(|page| !kmapper.is_mapped(page, None))
+        }));
+
+        Ok(())
+    }
+}
+
+crate::kernel_test! {
+    fn vmalloc_reuses_freed_ranges() {
+        let page_count = NonZeroUsize::new(2).unwrap();
+        let allocations = (0..3)
+            .map(|_| allocate(page_count).map(|allocation| allocation.as_non_null_ptr()))
+            .collect::<Result<alloc::vec::Vec<_>, _>>()
+            .map_err(|err| alloc::format!("{err}"))?;
+
+        // Safety: The allocation isn't referenced.
+        unsafe { free(allocations[1]) }.map_err(|err| alloc::format!("{err}"))?;
+
+        // The lowest free range is reused, between the remaining allocations.
+        let reused = allocate(page_count).map_err(|err| alloc::format!("{err}"))?;
+        crate::ktest_assert_eq!(reused.as_non_null_ptr(), allocations[1]);
+
+        for ptr in [allocations[0], reused.as_non_null_ptr(), allocations[2]] {
+            // Safety: The allocation isn't referenced.
+            unsafe { free(ptr) }.map_err(|err| alloc::format!("{err}"))?;
+        }
+
+        Ok(())
+    }
+}
@@ -50,19 +50,14 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
 
     stack_trace();
 
+    // A panic while running the kernel test suite should fail the run, not hang it.
+    crate::selftest::on_panic();
+
     // Safety: It's dead, Jim.
     unsafe { crate::interrupts::halt_and_catch_fire() }
 }
 
 fn stack_trace() {
-    fn print_stack_trace_entry<D: core::fmt::Display>(
-        entry_num: usize,
-        fn_address: Address<Virtual>,
-        symbol_name: D,
-    ) {
-        error!("#{entry_num: <4}0x{:X} {symbol_name:#}", fn_address.get());
-    }
-
     error!("----------STACK-TRACE---------");
 
     let frame_ptr = {
@@ -74,19 +69,53 @@ fn stack_trace() {
 
     // Safety: Frame pointer is pulled directly from the frame pointer register.
     let stack_tracer = unsafe { StackTracer::new(NonNull::new(frame_ptr.cast_mut()).unwrap()) };
-    for (depth, trace_address) in stack_tracer.enumerate() {
+    print_trace(stack_tracer);
+
+    error!("----------STACK-TRACE----------");
+}
+
+/// Records the return addresses of the current call stack (innermost first) into `trace`, so they can
+/// be symbolized later with [`print_trace`]. Unused entries are zeroed.
+#[inline(never)]
+pub fn capture_trace(trace: &mut [usize]) {
+    let frame_ptr = {
+        #[cfg(target_arch = "x86_64")]
+        {
+            crate::arch::x86_64::registers::stack::RBP::read() as *const StackFrame
+        }
+    };
+
+    // Safety: Frame pointer is pulled directly from the frame pointer register.
+    let stack_tracer = unsafe { StackTracer::new(NonNull::new(frame_ptr.cast_mut()).unwrap()) };
+    let mut addresses = stack_tracer.map(|address| address.get());
+    for entry in trace {
+        *entry = addresses.next().unwrap_or(0);
+    }
+}
+
+/// Prints each address of `trace` along with the name of the function containing it.
+pub fn print_trace(trace: impl IntoIterator<Item = Address<Virtual>>) {
+    fn print_stack_trace_entry<D: core::fmt::Display>(
+        entry_num: usize,
+        fn_address: Address<Virtual>,
+        symbol_name: D,
+    ) {
+        error!("#{entry_num: <4}0x{:X} {symbol_name:#}", fn_address.get());
+    }
+
+    for (depth, trace_address) in trace.into_iter().enumerate() {
         const SYMBOL_TYPE_FUNCTION: u8 = 2;
 
-        if let Some(symbol_name) = symbols::get_name(trace_address) {
+        let found_symbol = symbols::with_name(trace_address, |symbol_name| {
             if let Ok(demangled) = rustc_demangle::try_demangle(symbol_name) {
                 print_stack_trace_entry(depth, trace_address, demangled);
             } else {
                 print_stack_trace_entry(depth, trace_address, symbol_name);
             }
-        } else {
+        });
+
+        if found_symbol.is_none() {
             print_stack_trace_entry(depth, trace_address, "!!! no function found !!!");
         }
     }
-
-    error!("----------STACK-TRACE----------");
 }
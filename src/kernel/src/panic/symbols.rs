@@ -1,5 +1,5 @@
 use elf::{ElfBytes, endian::AnyEndian, string_table::StringTable, symbol::SymbolTable};
-use libsys::{Address, Virtual};
+use libsys::{Address, Frame, Virtual};
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -13,7 +13,15 @@ pub enum Error {
     NoSymbolTable,
 }
 
-static SYMBOL_TABLE: spin::Once<(SymbolTable<AnyEndian>, StringTable)> = spin::Once::new();
+struct Symbols {
+    symbols: SymbolTable<'static, AnyEndian>,
+    strings: StringTable<'static>,
+
+    /// Kernel file the tables are parsed from, which is freed along with them.
+    kernel_file: &'static [u8],
+}
+
+static SYMBOLS: spin::RwLock<Option<Symbols>> = spin::RwLock::new(None);
 
 pub fn parse(kernel_file_request: &limine::request::ExecutableFileRequest) {
     let Some(response) = kernel_file_request.get_response() else {
@@ -23,9 +31,10 @@ pub fn parse(kernel_file_request: &limine::request::ExecutableFileRequest) {
 
     // Safety: Bootloader guarantees the address and size of the executable file will be correct.
     //         Additionally, given the context, it also guarantees the file will be mapped into memory.
+    //         The file outlives the boot phase, so it's referenced through the kernel's HHDM.
     let kernel_file = unsafe {
         core::slice::from_raw_parts::<'static>(
-            response.file().addr(),
+            crate::mem::kaslr::rebase_boot_pointer(response.file().addr()),
             response.file().size().try_into().unwrap(),
         )
     };
@@ -44,20 +53,40 @@ pub fn parse(kernel_file_request: &limine::request::ExecutableFileRequest) {
         return;
     };
 
-    let Some(symbol_table) = symbol_table else {
+    let Some((symbols, strings)) = symbol_table else {
         error!("Kernel file has no symbol table.");
         return;
     };
 
-    SYMBOL_TABLE.call_once(|| symbol_table);
+    let mut symbol_info = SYMBOLS.write();
+    if symbol_info.is_none() {
+        *symbol_info = Some(Symbols {
+            symbols,
+            strings,
+            kernel_file,
+        });
+    }
 }
 
-pub fn get_name(address: Address<Virtual>) -> Option<&'static str> {
-    let (symbols, strings) = SYMBOL_TABLE.get()?;
+/// Looks up the name of the symbol containing `address`, and passes it to `with_fn`. The symbol table
+/// holds link addresses, so the kernel's slide is removed from `address` first.
+///
+/// # Remark
+///
+/// This is used by the panic handler, so it never blocks; if the symbol info is being
+/// dropped concurrently, no name is found.
+pub fn with_name<T>(address: Address<Virtual>, with_fn: impl FnOnce(&str) -> T) -> Option<T> {
+    let symbol_info = SYMBOLS.try_read()?;
+    let Symbols {
+        symbols, strings, ..
+    } = symbol_info.as_ref()?;
 
+    let link_address = address
+        .get()
+        .wrapping_sub(crate::mem::kaslr::kernel_slide());
     let symbol = symbols.iter().find(|symbol| {
         (symbol.st_value..(symbol.st_value + symbol.st_size))
-            .contains(&address.get().try_into().unwrap())
+            .contains(&link_address.try_into().unwrap())
     })?;
 
     let Ok(string) = strings.get(symbol.st_name.try_into().unwrap()) else {
@@ -65,5 +94,73 @@ pub fn get_name(address: Address<Virtual>) -> Option<&'static str> {
         return None;
     };
 
-    Some(string)
+    Some(with_fn(string))
+}
+
+/// Drops the kernel symbol info, freeing the memory of the kernel file it was parsed
+/// from. Returns the number of frames freed.
+///
+/// Stack traces will no longer include symbol names after this is called.
+pub fn drop_symbol_info() -> usize {
+    use crate::mem::{
+        Hhdm,
+        paging::{FlagsModify, TableDepth, TableEntryFlags},
+        pmm::PhysicalMemoryManager,
+    };
+    use libsys::{Page, page_shift, page_size};
+
+    // Don't wait on any locks, as this may be called from within an allocation.
+    let Some(mut symbol_info) = SYMBOLS.try_write() else {
+        return 0;
+    };
+    let Some(kernel_file) = symbol_info.as_ref().map(|symbols| symbols.kernel_file) else {
+        return 0;
+    };
+
+    // The bootloader allocates the kernel file as whole pages, so its final page can be freed too.
+    let file_range = kernel_file.as_ptr_range();
+    let pages = (libsys::align_down(file_range.start.addr(), page_shift())..file_range.end.addr())
+        .step_by(page_size())
+        .map(|address| Address::<Page>::new(address).unwrap());
+
+    // The HHDM maps the kernel file read-only, but its frames must be writable once freed.
+    let remapped = crate::mem::try_with_kmapper(|kmapper| {
+        for page in pages.clone() {
+            [
+                TableDepth::min(),
+                TableDepth::new(1).unwrap(),
+                TableDepth::new(2).unwrap(),
+            ]
+            .into_iter()
+            .find_map(|depth| {
+                // Safety: The kernel file is only referenced by the symbol info, which is dropped below.
+                unsafe {
+                    kmapper.set_page_attributes(
+                        page,
+                        Some(depth),
+                        TableEntryFlags::WRITABLE,
+                        FlagsModify::Insert,
+                    )
+                }
+                .ok()
+            })
+            .expect("kernel file is not mapped in the HHDM");
+        }
+    });
+
+    if remapped.is_none() {
+        return 0;
+    }
+
+    *symbol_info = None;
+    drop(symbol_info);
+
+    let frames = pages
+        .map(|page| Address::<Frame>::new(page.get().get() - Hhdm::offset().get()).unwrap())
+        .filter(|&frame| PhysicalMemoryManager::free_frame(frame).is_ok())
+        .count();
+
+    debug!("Dropped kernel symbol info, freeing {frames} frames.");
+
+    frames
 }
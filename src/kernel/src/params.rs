@@ -1,27 +1,195 @@
+use core::num::NonZeroUsize;
+
 static PARAMS: spin::Once<Parameters> = spin::Once::new();
 
+/// Maximum length (in bytes) of any path provided on the kernel command line.
+pub const MAX_PATH_LEN: usize = 128;
+
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum Error<'a> {
+    #[error("unknown command line argument: {0:?}")]
+    UnknownKey(&'a str),
+
+    #[error("command line argument {0:?} requires a value")]
+    MissingValue(&'a str),
+
+    #[error("command line argument {0:?} does not accept a value")]
+    UnexpectedValue(&'a str),
+
+    #[error("invalid value for command line argument {key:?}: {value:?}")]
+    InvalidValue { key: &'a str, value: &'a str },
+}
+
+/// Fixed-capacity path string, copied out of the command line so it remains
+/// valid after bootloader memory is reclaimed.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Path {
+    bytes: [u8; MAX_PATH_LEN],
+    len: usize,
+}
+
+impl Path {
+    pub fn new(path: &str) -> Option<Self> {
+        let len = path.len();
+        if len == 0 || len > MAX_PATH_LEN {
+            return None;
+        }
+
+        let mut bytes = [0u8; MAX_PATH_LEN];
+        bytes[..len].copy_from_slice(path.as_bytes());
+
+        Some(Self { bytes, len })
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Safety: Bytes were copied from a valid `&str` in `Self::new()`.
+        unsafe { core::str::from_utf8_unchecked(&self.bytes[..self.len]) }
+    }
+}
+
+impl core::fmt::Debug for Path {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Parameters {
     /// Whether the kernel should utilize multi-processing.
     pub use_multiprocessing: bool,
 
+    /// Maximum number of hardware threads to bring online (including the boot thread).
+    pub max_hwthreads: Option<NonZeroUsize>,
+
     /// Whether to keep the kernel symbol info before reclaiming extra memory.
     pub drop_symbol_info: bool,
 
     /// Whether the kernel should use low-memory mode.
     pub low_memory_mode: bool,
+
+    /// Maximum log level the kernel will emit.
+    pub log_level: log::LevelFilter,
+
+    /// Path of the first userspace task to execute.
+    pub init_path: Option<Path>,
+
+    /// Whether to run the in-kernel test suite (rather than the scheduler) after init.
+    pub selftest: bool,
+
+    /// Whether the kernel allocators check for heap corruption (see `mem::heap_debug`).
+    pub heap_debug: bool,
 }
 
 impl Default for Parameters {
     fn default() -> Self {
         Parameters {
             use_multiprocessing: true,
+            max_hwthreads: None,
             drop_symbol_info: false,
             low_memory_mode: false,
+            log_level: log::LevelFilter::Trace,
+            init_path: None,
+            selftest: false,
+            heap_debug: cfg!(debug_assertions),
+        }
+    }
+}
+
+impl Parameters {
+    /// Applies a single `key` or `key=value` argument to the parameters.
+    fn apply<'a>(&mut self, key: &'a str, value: Option<&'a str>) -> Result<(), Error<'a>> {
+        fn flag<'a>(key: &'a str, value: Option<&'a str>) -> Result<(), Error<'a>> {
+            match value {
+                None => Ok(()),
+                Some(_) => Err(Error::UnexpectedValue(key)),
+            }
+        }
+
+        fn required<'a>(key: &'a str, value: Option<&'a str>) -> Result<&'a str, Error<'a>> {
+            match value {
+                Some("") | None => Err(Error::MissingValue(key)),
+                Some(value) => Ok(value),
+            }
+        }
+
+        match key {
+            "nomp" => {
+                flag(key, value)?;
+                self.use_multiprocessing = false;
+            }
+
+            "lomem" => {
+                flag(key, value)?;
+                self.low_memory_mode = true;
+            }
+
+            "drop_symbols" => {
+                flag(key, value)?;
+                self.drop_symbol_info = true;
+            }
+
+            "selftest" => {
+                flag(key, value)?;
+                self.selftest = true;
+            }
+
+            // Checked by `mem::kaslr` itself, long before the command line is parsed.
+            "nokaslr" => flag(key, value)?,
+
+            "heapdebug" => {
+                flag(key, value)?;
+                self.heap_debug = true;
+            }
+
+            "noheapdebug" => {
+                flag(key, value)?;
+                self.heap_debug = false;
+            }
+
+            "smp" => {
+                let value = required(key, value)?;
+                let hwthreads = value
+                    .parse::<NonZeroUsize>()
+                    .map_err(|_| Error::InvalidValue { key, value })?;
+
+                self.use_multiprocessing = hwthreads.get() > 1;
+                self.max_hwthreads = Some(hwthreads);
+            }
+
+            "loglevel" => {
+                let value = required(key, value)?;
+                self.log_level = value
+                    .parse::<log::LevelFilter>()
+                    .map_err(|_| Error::InvalidValue { key, value })?;
+            }
+
+            "init" => {
+                let value = required(key, value)?;
+                self.init_path = Some(Path::new(value).ok_or(Error::InvalidValue { key, value })?);
+            }
+
+            key => return Err(Error::UnknownKey(key)),
         }
+
+        Ok(())
     }
 }
 
+/// Splits a kernel command line into `(key, value)` pairs.
+///
+/// Arguments are separated by whitespace, and may optionally be prefixed with `--`
+/// (i.e. `--nomp` and `nomp` are equivalent).
+pub fn arguments(cmdline: &str) -> impl Iterator<Item = (&str, Option<&str>)> {
+    cmdline.split_ascii_whitespace().map(|arg| {
+        let arg = arg.strip_prefix("--").unwrap_or(arg);
+
+        match arg.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (arg, None),
+        }
+    })
+}
+
 pub fn parse(kernel_cmdline_request: &limine::request::ExecutableCmdlineRequest) {
     PARAMS.call_once(|| {
         let mut params = Parameters::default();
@@ -31,16 +199,12 @@ pub fn parse(kernel_cmdline_request: &limine::request::ExecutableCmdlineRequest)
             .map(limine::response::ExecutableCmdlineResponse::cmdline)
             .map(core::ffi::CStr::to_str)
         {
-            Some(Ok("")) => {
-                // Ignore accidental extra spaces
-            }
-
-            Some(Ok("--nomp")) => params.use_multiprocessing = false,
-
-            Some(Ok("--lomem")) => params.low_memory_mode = true,
-
-            Some(Ok(arg)) => {
-                warn!("Unknown command line argument: {arg:?}");
+            Some(Ok(cmdline)) => {
+                for (key, value) in arguments(cmdline) {
+                    if let Err(error) = params.apply(key, value) {
+                        warn!("Failed to apply command line argument: {error}");
+                    }
+                }
             }
 
             Some(Err(error)) => {
@@ -52,6 +216,8 @@ pub fn parse(kernel_cmdline_request: &limine::request::ExecutableCmdlineRequest)
             }
         }
 
+        log::set_max_level(params.log_level);
+
         debug!("Kernel Parameters:\n{params:#?}");
 
         params
@@ -62,6 +228,74 @@ pub fn use_multiprocessing() -> bool {
     PARAMS.get().unwrap().use_multiprocessing
 }
 
+pub fn max_hwthreads() -> Option<NonZeroUsize> {
+    PARAMS.get().unwrap().max_hwthreads
+}
+
 pub fn use_low_memory() -> bool {
     PARAMS.get().unwrap().low_memory_mode
 }
+
+pub fn drop_symbol_info() -> bool {
+    PARAMS.get().unwrap().drop_symbol_info
+}
+
+pub fn log_level() -> log::LevelFilter {
+    PARAMS.get().unwrap().log_level
+}
+
+pub fn init_path() -> Option<Path> {
+    PARAMS.get().unwrap().init_path
+}
+
+pub fn selftest() -> bool {
+    PARAMS.get().unwrap().selftest
+}
+
+pub fn heap_debug() -> bool {
+    PARAMS.get().unwrap().heap_debug
+}
+
+crate::kernel_test! {
+    fn cmdline_arguments() {
+        let mut args = arguments("--nomp smp=4  init=/bin/init loglevel=");
+
+        crate::ktest_assert_eq!(args.next(), Some(("nomp", None)));
+        crate::ktest_assert_eq!(args.next(), Some(("smp", Some("4"))));
+        crate::ktest_assert_eq!(args.next(), Some(("init", Some("/bin/init"))));
+        crate::ktest_assert_eq!(args.next(), Some(("loglevel", Some(""))));
+        crate::ktest_assert_eq!(args.next(), None);
+
+        Ok(())
+    }
+}
+
+crate::kernel_test! {
+    fn cmdline_apply() {
+        let mut params = Parameters::default();
+
+        crate::ktest_assert!(params.apply("smp", Some("1")).is_ok());
+        crate::ktest_assert!(!params.use_multiprocessing);
+        crate::ktest_assert!(params.apply("nokaslr", None).is_ok());
+        crate::ktest_assert!(params.apply("heapdebug", None).is_ok());
+        crate::ktest_assert!(params.heap_debug);
+        crate::ktest_assert_eq!(
+            params.apply("smp", Some("0")),
+            Err(Error::InvalidValue { key: "smp", value: "0" })
+        );
+        crate::ktest_assert_eq!(
+            params.apply("loglevel", None),
+            Err(Error::MissingValue("loglevel"))
+        );
+        crate::ktest_assert_eq!(
+            params.apply("nomp", Some("1")),
+            Err(Error::UnexpectedValue("nomp"))
+        );
+        crate::ktest_assert_eq!(
+            params.apply("bogus", None),
+            Err(Error::UnknownKey("bogus"))
+        );
+
+        Ok(())
+    }
+}
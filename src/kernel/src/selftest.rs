@@ -0,0 +1,168 @@
+//! In-kernel test harness.
+//!
+//! Tests are registered with [`kernel_test!`](crate::kernel_test), which places a
+//! [`KernelTest`] descriptor into the `.kernel_tests` linker section. When the kernel
+//! is booted with the `selftest` command line argument, [`run`] executes every
+//! registered test once init has completed, then exits QEMU through its
+//! `isa-debug-exit` device with a status reflecting the results.
+
+use alloc::string::String;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+/// I/O port of QEMU's `isa-debug-exit` device (`-device isa-debug-exit,iobase=0xf4,iosize=0x04`).
+const DEBUG_EXIT_PORT: u16 = 0xF4;
+
+/// Status codes written to the `isa-debug-exit` device.
+///
+/// QEMU exits with `(code << 1) | 1`, so these become process exit codes `33` and `35`.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    Success = 0x10,
+    Failure = 0x11,
+}
+
+pub type TestResult = Result<(), String>;
+
+#[derive(Debug)]
+pub struct KernelTest {
+    pub name: &'static str,
+    pub func: fn() -> TestResult,
+}
+
+/// Defines a kernel test function and registers it with the test harness.
+///
+/// The function body must evaluate to a [`TestResult`]; use [`ktest_assert!`](crate::ktest_assert)
+/// and [`ktest_assert_eq!`](crate::ktest_assert_eq) to return a failure from within it.
+#[macro_export]
+macro_rules! kernel_test {
+    ($(#[$meta:meta])* fn $name:ident() $body:block) => {
+        $(#[$meta])*
+        fn $name() -> $crate::selftest::TestResult $body
+
+        const _: () = {
+            #[used]
+            #[unsafe(link_section = ".kernel_tests")]
+            static KERNEL_TEST: $crate::selftest::KernelTest = $crate::selftest::KernelTest {
+                name: concat!(module_path!(), "::", stringify!($name)),
+                func: $name,
+            };
+        };
+    };
+}
+
+/// Fails the enclosing kernel test if the condition does not hold.
+#[macro_export]
+macro_rules! ktest_assert {
+    ($cond:expr $(,)?) => {
+        if !$cond {
+            return Err(alloc::format!(
+                "assertion failed at {}:{}: {}",
+                file!(),
+                line!(),
+                stringify!($cond)
+            ));
+        }
+    };
+}
+
+/// Fails the enclosing kernel test if the two values are not equal.
+#[macro_export]
+macro_rules! ktest_assert_eq {
+    ($left:expr, $right:expr $(,)?) => {{
+        let (left, right) = (&$left, &$right);
+        if *left != *right {
+            return Err(alloc::format!(
+                "assertion failed at {}:{}: {} == {}\n  left: {:?}\n right: {:?}",
+                file!(),
+                line!(),
+                stringify!($left),
+                stringify!($right),
+                left,
+                right
+            ));
+        }
+    }};
+}
+
+/// Test which is currently executing, so a panic can be attributed to it.
+static CURRENT_TEST: AtomicPtr<KernelTest> = AtomicPtr::new(core::ptr::null_mut());
+
+fn tests() -> &'static [KernelTest] {
+    unsafe extern "C" {
+        static __kernel_tests_start: KernelTest;
+        static __kernel_tests_end: KernelTest;
+    }
+
+    let start = &raw const __kernel_tests_start;
+    let end = &raw const __kernel_tests_end;
+
+    // Safety: The linker script places `__kernel_tests_start` and `__kernel_tests_end` at either
+    //         end of the `.kernel_tests` section, which only contains `KernelTest` descriptors.
+    unsafe {
+        let len = usize::try_from(end.offset_from(start)).unwrap();
+        core::slice::from_raw_parts(start, len)
+    }
+}
+
+/// Runs every registered kernel test, reporting each result to the log, then exits QEMU.
+pub fn run() -> ! {
+    let tests = tests();
+    info!("Running {} kernel tests...", tests.len());
+
+    let mut failed = 0usize;
+    for test in tests {
+        CURRENT_TEST.store(core::ptr::from_ref(test).cast_mut(), Ordering::Release);
+
+        match (test.func)() {
+            Ok(()) => info!("test {} ... ok", test.name),
+            Err(message) => {
+                error!("test {} ... FAILED\n{message}", test.name);
+                failed += 1;
+            }
+        }
+
+        CURRENT_TEST.store(core::ptr::null_mut(), Ordering::Release);
+    }
+
+    let passed = tests.len() - failed;
+    if failed == 0 {
+        info!("test result: ok. {passed} passed; 0 failed");
+        exit_qemu(ExitCode::Success)
+    } else {
+        error!("test result: FAILED. {passed} passed; {failed} failed");
+        exit_qemu(ExitCode::Failure)
+    }
+}
+
+/// Reports a failure for the currently executing test (if any) and exits QEMU.
+///
+/// # Remark
+///
+/// This is called from the panic handler, so it should *never* panic.
+pub fn on_panic() {
+    // Safety: Pointer is either null, or refers to a descriptor in the `.kernel_tests` section.
+    if let Some(test) = unsafe { CURRENT_TEST.load(Ordering::Acquire).as_ref() } {
+        error!("test {} ... FAILED (panicked)", test.name);
+        exit_qemu(ExitCode::Failure);
+    }
+}
+
+/// Exits QEMU via the `isa-debug-exit` device. If the device isn't present, the
+/// hardware thread is halted instead.
+pub fn exit_qemu(code: ExitCode) -> ! {
+    // Safety: Writing to the debug exit port has no effect other than exiting QEMU.
+    unsafe { ioports::WriteOnlyPort::<u32>::new(DEBUG_EXIT_PORT) }.write(code as u32);
+
+    // Safety: There's no meaningful state left to preserve.
+    unsafe { crate::interrupts::halt_and_catch_fire() }
+}
+
+crate::kernel_test! {
+    fn harness_sanity() {
+        crate::ktest_assert!(!tests().is_empty());
+        crate::ktest_assert_eq!(1 + 1, 2);
+
+        Ok(())
+    }
+}
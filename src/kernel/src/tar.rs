@@ -0,0 +1,309 @@
+//! Minimal read-only reader for USTAR archives (including the GNU long name extension).
+
+const BLOCK_SIZE: usize = 512;
+
+const NAME_FIELD: core::ops::Range<usize> = 0..100;
+const SIZE_FIELD: core::ops::Range<usize> = 124..136;
+const CHECKSUM_FIELD: core::ops::Range<usize> = 148..156;
+const TYPE_FIELD: usize = 156;
+
+const TYPE_REGULAR: u8 = b'0';
+const TYPE_REGULAR_OLD: u8 = b'\0';
+const TYPE_GNU_LONG_NAME: u8 = b'L';
+
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    #[error("archive ends in the middle of an entry header")]
+    TruncatedHeader,
+
+    #[error("entry data extends past the end of the archive")]
+    TruncatedData,
+
+    #[error("entry header checksum does not match its contents")]
+    BadChecksum,
+
+    #[error("entry header contains a malformed numeric field")]
+    BadNumber,
+
+    #[error("entry name is not valid UTF-8")]
+    BadName,
+}
+
+pub struct Archive<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Archive<'a> {
+    pub const fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    /// Iterates the regular file entries of the archive. Iteration ends after the
+    /// end-of-archive marker, or the first error encountered.
+    pub const fn entries(&self) -> Entries<'a> {
+        Entries {
+            data: self.data,
+            offset: 0,
+            finished: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<'a> {
+    name: &'a str,
+    data: &'a [u8],
+}
+
+impl<'a> Entry<'a> {
+    #[inline]
+    pub const fn name(&self) -> &'a str {
+        self.name
+    }
+
+    #[inline]
+    pub const fn data(&self) -> &'a [u8] {
+        self.data
+    }
+}
+
+pub struct Entries<'a> {
+    data: &'a [u8],
+    offset: usize,
+    finished: bool,
+}
+
+impl<'a> Entries<'a> {
+    /// Reads the header & data of the entry at the current offset, advancing past it.
+    /// Returns `None` upon reaching the end of the archive.
+    fn next_raw(&mut self) -> Result<Option<(&'a [u8; BLOCK_SIZE], &'a [u8])>, Error> {
+        // Archives may omit their end-of-archive blocks, but must not end mid-header.
+        if self.offset == self.data.len() {
+            return Ok(None);
+        }
+
+        let header: &[u8; BLOCK_SIZE] = self
+            .data
+            .get(self.offset..(self.offset + BLOCK_SIZE))
+            .ok_or(Error::TruncatedHeader)?
+            .try_into()
+            .unwrap();
+
+        // An all-zero block marks the end of the archive.
+        if header.iter().all(|byte| *byte == 0) {
+            return Ok(None);
+        }
+
+        let expected_checksum = parse_octal(&header[CHECKSUM_FIELD])?;
+        // The checksum is calculated with the checksum field itself treated as spaces.
+        let checksum = header
+            .iter()
+            .enumerate()
+            .map(|(index, byte)| {
+                if CHECKSUM_FIELD.contains(&index) {
+                    usize::from(b' ')
+                } else {
+                    usize::from(*byte)
+                }
+            })
+            .sum::<usize>();
+
+        if checksum != expected_checksum {
+            return Err(Error::BadChecksum);
+        }
+
+        let data_start = self.offset + BLOCK_SIZE;
+        let data = data_start
+            .checked_add(parse_octal(&header[SIZE_FIELD])?)
+            .and_then(|data_end| self.data.get(data_start..data_end))
+            .ok_or(Error::TruncatedData)?;
+
+        // Entry data is padded out to the block size.
+        self.offset = usize::min(
+            data_start + data.len().next_multiple_of(BLOCK_SIZE),
+            self.data.len(),
+        );
+
+        Ok(Some((header, data)))
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry<'a>>, Error> {
+        let mut long_name = None;
+
+        while let Some((header, data)) = self.next_raw()? {
+            match header[TYPE_FIELD] {
+                TYPE_REGULAR | TYPE_REGULAR_OLD => {
+                    let name = match long_name {
+                        Some(long_name) => long_name,
+                        None => parse_str(&header[NAME_FIELD])?,
+                    };
+
+                    return Ok(Some(Entry { name, data }));
+                }
+
+                // The data of a GNU long name entry is the name of the entry which follows it.
+                TYPE_GNU_LONG_NAME => long_name = Some(parse_str(data)?),
+
+                // Directories, links, and extended headers are not supported, so skip them.
+                _ => long_name = None,
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        let result = self.next_entry().transpose();
+        self.finished = !matches!(result, Some(Ok(_)));
+
+        result
+    }
+}
+
+/// Parses a NUL-terminated string field.
+fn parse_str(field: &[u8]) -> Result<&str, Error> {
+    let len = field
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(field.len());
+
+    core::str::from_utf8(&field[..len]).map_err(|_| Error::BadName)
+}
+
+/// Parses a space or NUL padded octal numeric field.
+fn parse_octal(field: &[u8]) -> Result<usize, Error> {
+    let digits = field
+        .iter()
+        .copied()
+        .skip_while(|byte| *byte == b' ')
+        .take_while(|byte| *byte != b' ' && *byte != 0);
+
+    let mut value = 0usize;
+    let mut has_digits = false;
+    for digit in digits {
+        if !(b'0'..=b'7').contains(&digit) {
+            return Err(Error::BadNumber);
+        }
+
+        value = value
+            .checked_mul(8)
+            .and_then(|value| value.checked_add(usize::from(digit - b'0')))
+            .ok_or(Error::BadNumber)?;
+        has_digits = true;
+    }
+
+    if has_digits {
+        Ok(value)
+    } else {
+        Err(Error::BadNumber)
+    }
+}
+
+crate::kernel_test! {
+    fn tar_reads_entries() {
+        use alloc::vec::Vec;
+
+        /// Builds an entry header (with a valid checksum).
+        fn test_header(name: &str, kind: u8, size: &[u8]) -> [u8; BLOCK_SIZE] {
+            let mut header = [0u8; BLOCK_SIZE];
+            header[..name.len()].copy_from_slice(name.as_bytes());
+            header[SIZE_FIELD][..size.len()].copy_from_slice(size);
+            header[TYPE_FIELD] = kind;
+            header[CHECKSUM_FIELD].fill(b' ');
+
+            let checksum = header.iter().copied().map(usize::from).sum::<usize>();
+            header[CHECKSUM_FIELD].copy_from_slice(alloc::format!("{checksum:06o}\0 ").as_bytes());
+
+            header
+        }
+
+        let long_name = "drivers/a-name-longer-than-the-one-hundred-bytes-the-header-name-field-can-hold-so-it-needs-the-gnu-extension";
+
+        let mut archive = Vec::new();
+        archive.extend_from_slice(&test_header("hello", TYPE_REGULAR, b"00000000005 "));
+        archive.extend_from_slice(b"hello");
+        archive.resize(archive.len().next_multiple_of(BLOCK_SIZE), 0);
+        archive.extend_from_slice(&test_header("dir/", b'5', b"0 "));
+        archive.extend_from_slice(&test_header(
+            "././@LongLink",
+            TYPE_GNU_LONG_NAME,
+            alloc::format!("{:o} ", long_name.len()).as_bytes(),
+        ));
+        archive.extend_from_slice(long_name.as_bytes());
+        archive.resize(archive.len().next_multiple_of(BLOCK_SIZE), 0);
+        archive.extend_from_slice(&test_header("empty", TYPE_REGULAR_OLD, b"0 "));
+        // End-of-archive marker.
+        archive.resize(archive.len() + (BLOCK_SIZE * 2), 0);
+
+        let entries = Archive::new(&archive)
+            .entries()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|error| alloc::format!("archive failed to parse: {error}"))?;
+
+        crate::ktest_assert_eq!(entries.len(), 2);
+        crate::ktest_assert_eq!(entries[0].name(), "hello");
+        crate::ktest_assert_eq!(entries[0].data(), b"hello");
+        crate::ktest_assert_eq!(entries[1].name(), long_name);
+        crate::ktest_assert!(entries[1].data().is_empty());
+
+        Ok(())
+    }
+}
+
+crate::kernel_test! {
+    fn tar_rejects_malformed_archives() {
+        /// Builds an entry header (with a valid checksum).
+        fn test_header(name: &str, kind: u8, size: &[u8]) -> [u8; BLOCK_SIZE] {
+            let mut header = [0u8; BLOCK_SIZE];
+            header[..name.len()].copy_from_slice(name.as_bytes());
+            header[SIZE_FIELD][..size.len()].copy_from_slice(size);
+            header[TYPE_FIELD] = kind;
+            header[CHECKSUM_FIELD].fill(b' ');
+
+            let checksum = header.iter().copied().map(usize::from).sum::<usize>();
+            header[CHECKSUM_FIELD].copy_from_slice(alloc::format!("{checksum:06o}\0 ").as_bytes());
+
+            header
+        }
+
+        let first_error = |archive: &[u8]| {
+            let mut entries = Archive::new(archive).entries();
+            let error = entries.next().and_then(Result::err);
+
+            // Iteration ends after the first error.
+            entries.next().is_none().then_some(error).flatten()
+        };
+
+        let header = test_header("file", TYPE_REGULAR, b"00000000010 ");
+
+        // The archive ends partway through a header.
+        crate::ktest_assert_eq!(first_error(&header[..BLOCK_SIZE / 2]), Some(Error::TruncatedHeader));
+
+        // The header was altered after its checksum was calculated.
+        let mut corrupted = header;
+        corrupted[0] = b'g';
+        crate::ktest_assert_eq!(first_error(&corrupted), Some(Error::BadChecksum));
+
+        // The entry's data extends past the end of the archive.
+        crate::ktest_assert_eq!(first_error(&header), Some(Error::TruncatedData));
+        let largest = test_header("largest", TYPE_REGULAR, b"777777777777");
+        crate::ktest_assert_eq!(first_error(&largest), Some(Error::TruncatedData));
+
+        // Numeric fields which overflow are rejected, rather than wrapping.
+        crate::ktest_assert_eq!(
+            parse_octal(b"7777777777777777777777777 "),
+            Err(Error::BadNumber)
+        );
+        crate::ktest_assert_eq!(parse_octal(b"0000017 "), Ok(0o17));
+
+        Ok(())
+    }
+}
@@ -1,7 +1,11 @@
-use crate::mem::{
-    mapper::Mapper,
-    paging,
-    paging::{TableDepth, TableEntryFlags},
+use crate::{
+    mem::{
+        mapper::Mapper,
+        paging,
+        paging::{CacheMode, TableDepth, TableEntryFlags},
+        pmm::FrameKind,
+    },
+    task::{Region, RegionKind, Regions},
 };
 use core::{num::NonZeroUsize, ptr::NonNull};
 use libsys::{Address, Page, Virtual, page_size};
@@ -62,12 +66,18 @@ impl From<MmapPermissions> for TableEntryFlags {
 
 pub const DEFAULT_USERSPACE_SIZE: NonZeroUsize = NonZeroUsize::new(1 << 47).unwrap();
 
-pub struct AddressSpace(Mapper);
+pub struct AddressSpace {
+    mapper: Mapper,
+    regions: Regions,
+}
 
 impl AddressSpace {
     #[inline]
     pub const fn new(mapper: Mapper) -> Self {
-        Self(mapper)
+        Self {
+            mapper,
+            regions: Regions::new(),
+        }
     }
 
     pub fn new_userspace() -> Self {
@@ -80,12 +90,19 @@ impl AddressSpace {
     }
 
     pub fn is_current(&self) -> bool {
-        let root_frame = self.0.root_frame();
+        let root_frame = self.mapper.root_frame();
         let cr3_frame = crate::mem::PagingRegister::read().frame();
 
         root_frame == cr3_frame
     }
 
+    /// Maps a new region of `page_count` pages, backed by anonymous memory with the given memory type. If
+    /// no `address` is provided, the lowest free range of pages is used.
+    ///
+    /// # Remark
+    ///
+    /// [`RegionKind::File`] and [`RegionKind::Device`] regions aren't backed by anonymous memory,
+    /// so they should be added with [`Self::reserve`] instead.
     pub fn mmap(
         &mut self,
         address: Option<Address<Page>>,
@@ -93,77 +110,71 @@ impl AddressSpace {
         // TODO support lazy mapping
         // lazy: bool,
         permissions: MmapPermissions,
+        kind: RegionKind,
+        cache_mode: CacheMode,
     ) -> Result<NonNull<[u8]>> {
-        if let Some(address) = address {
-            self.map_exact(address, page_count, permissions)
-        } else {
-            self.map_any(page_count, permissions)
-        }
-    }
+        debug_assert!(matches!(kind, RegionKind::Anonymous | RegionKind::Stack));
 
-    #[cfg_attr(debug_assertions, inline(never))]
-    fn map_any(
-        &mut self,
-        page_count: NonZeroUsize,
-        permissions: MmapPermissions,
-    ) -> Result<NonNull<[u8]>> {
-        let walker = unsafe {
-            paging::walker::Walker::new(
-                self.0.view_page_table(),
-                TableDepth::max(),
-                TableDepth::min(),
-            )
-            .unwrap()
+        let address = match address {
+            Some(address) => address,
+            None => self.find_free(page_count)?,
         };
 
-        let mut index = 0;
-        let mut run = 0;
-        walker.walk(|entry| {
-            use core::ops::ControlFlow;
-
-            if entry.is_none() {
-                run += 1;
-
-                if run == page_count.get() {
-                    return ControlFlow::Break(());
-                }
-            } else {
-                run = 0;
-            }
+        self.reserve(Region::new(address, page_count, permissions, kind))?;
 
-            index += 1;
+        let flags =
+            TableEntryFlags::PRESENT | TableEntryFlags::USER | TableEntryFlags::from(permissions);
+        // Safety: The pages were just reserved, so nothing else can be using them.
+        let result = unsafe { self.invoke_mapper(address, page_count, flags, cache_mode) };
+        if result.is_err() {
+            self.regions.remove(address);
+        }
 
-            ControlFlow::Continue(())
-        });
+        result
+    }
 
-        match run.cmp(&page_count.get()) {
-            core::cmp::Ordering::Equal => {
-                let address = Address::<Page>::new(index << libsys::page_shift().get()).unwrap();
-                let flags = TableEntryFlags::PRESENT
-                    | TableEntryFlags::USER
-                    | TableEntryFlags::from(permissions);
+    /// Finds the lowest free range of `page_count` pages (the null page is never used).
+    fn find_free(&self, page_count: NonZeroUsize) -> Result<Address<Page>> {
+        self.regions
+            .find_gap(page_count, page_size()..DEFAULT_USERSPACE_SIZE.get())
+            .ok_or(Error::AllocError)
+    }
 
-                unsafe { self.invoke_mapper(address, page_count, flags) }
-            }
-            core::cmp::Ordering::Less => Err(Error::AllocError),
-            core::cmp::Ordering::Greater => unreachable!(),
+    /// Adds `region` to the address space, without mapping any of its pages.
+    pub fn reserve(&mut self, region: Region) -> Result<()> {
+        let end = region.range().end;
+        if end > DEFAULT_USERSPACE_SIZE.get() {
+            return Err(Error::AddressOverrun { value: end });
         }
+
+        self.regions
+            .insert(region)
+            .map_err(|_| Error::OverlappingAddress)
     }
 
-    #[cfg_attr(debug_assertions, inline(never))]
-    fn map_exact(
+    /// Maps `page_count` pages from `address` (which must be within an existing region) to anonymous
+    /// memory, with the kernel able to write to them.
+    pub fn populate(
         &mut self,
         address: Address<Page>,
         page_count: NonZeroUsize,
-        permissions: MmapPermissions,
     ) -> Result<NonNull<[u8]>> {
+        let end = address.get().get() + (page_count.get() * page_size());
+        if !self
+            .regions
+            .find(address.get())
+            .is_some_and(|region| region.range().end >= end)
+        {
+            return Err(Error::InvalidAddress);
+        }
+
+        // Safety: The pages are within a region, so they're only used by this address space.
         unsafe {
             self.invoke_mapper(
                 address,
                 page_count,
-                TableEntryFlags::PRESENT
-                    | TableEntryFlags::USER
-                    | TableEntryFlags::from(permissions),
+                TableEntryFlags::PRESENT | TableEntryFlags::USER | TableEntryFlags::RW,
+                CacheMode::WriteBack,
             )
         }
     }
@@ -176,12 +187,17 @@ impl AddressSpace {
         address: Address<Page>,
         page_count: NonZeroUsize,
         flags: TableEntryFlags,
+        cache_mode: CacheMode,
     ) -> Result<NonNull<[u8]>> {
         let mapping_size = page_count.get() * page_size();
-        (0..mapping_size)
-            .step_by(page_size())
-            .map(|offset| Address::new_truncate(address.get().get() + offset))
-            .try_for_each(|offset_page| self.0.auto_map(offset_page, flags))
+        self.mapper
+            .auto_map_range(
+                address,
+                page_count.get(),
+                FrameKind::UserAnon,
+                flags,
+                cache_mode,
+            )
             .map_err(Error::from)?;
 
         Ok(NonNull::slice_from_raw_parts(
@@ -196,43 +212,174 @@ impl AddressSpace {
         page_count: NonZeroUsize,
         flags: TableEntryFlags,
     ) -> Result<()> {
-        for index_offset in 0..page_count.get() {
-            let offset_index = address.index() + index_offset;
-            let offset_address =
-                Address::from_index(offset_index).ok_or(Error::AddressIndexOverrun {
-                    index: offset_index,
-                })?;
-
-            self.0
-                .set_page_attributes(offset_address, None, flags, paging::FlagsModify::Set)
-                .map_err(|err| Error::Paging { err })?;
-        }
-
-        Ok(())
+        self.mapper
+            .set_range_attributes(address, page_count.get(), flags, paging::FlagsModify::Set)
+            .map_err(|err| Error::Paging { err })
     }
 
     pub fn get_flags(&self, address: Address<Page>) -> Result<TableEntryFlags> {
-        self.0.get_page_attributes(address).ok_or(Error::NotMapped {
-            addr: address.get(),
-        })
+        self.mapper
+            .get_page_attributes(address)
+            .ok_or(Error::NotMapped {
+                addr: address.get(),
+            })
+    }
+
+    /// Finds the region which contains `address`.
+    pub fn find_region(&self, address: Address<Virtual>) -> Option<&Region> {
+        self.regions.find(address)
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter()
     }
 
+    /// Whether `address` is within a region (though it may not be mapped yet).
     pub fn is_mmapped(&self, address: Address<Page>) -> bool {
-        self.0.is_mapped(address, None)
+        self.regions.find(address.get()).is_some()
+    }
+
+    /// Whether `address` is mapped to memory.
+    pub fn is_resident(&self, address: Address<Page>) -> bool {
+        self.mapper.is_mapped(address, None)
+    }
+
+    /// Number of pages mapped into the userspace half of the address space.
+    pub fn resident_pages(&self) -> usize {
+        fn count_pages(table: &[paging::PageTableEntry], entry_depth: TableDepth) -> usize {
+            table
+                .iter()
+                .filter(|entry| entry.is_present())
+                .map(|entry| {
+                    if entry_depth.is_min() || entry.is_huge() {
+                        entry_depth.align() / page_size()
+                    } else {
+                        let table_ptr = core::ptr::with_exposed_provenance(
+                            crate::mem::Hhdm::offset().get() + entry.get_frame().get().get(),
+                        );
+                        // Safety: Present non-leaf entries point to valid page tables, within the HHDM.
+                        let table = unsafe {
+                            core::slice::from_raw_parts(table_ptr, libsys::table_index_size())
+                        };
+
+                        count_pages(table, entry_depth.next())
+                    }
+                })
+                .sum()
+        }
+
+        // The upper half of the root table is shared with the kernel.
+        let root_table = self.mapper.view_page_table();
+        let userspace_entries = &root_table[..(root_table.len() / 2)];
+
+        count_pages(userspace_entries, TableDepth::max().next())
+    }
+
+    /// Frees every frame owned by the userspace half of the address space (the anonymous frames it maps,
+    /// and the page tables which map them), along with its root table. Returns the number of frames freed.
+    ///
+    /// ## Safety
+    ///
+    /// - No hardware thread may have the address space loaded.
+    /// - Nothing may reference memory which is only mapped by the address space.
+    pub unsafe fn teardown(self) -> usize {
+        use crate::mem::pmm::PhysicalMemoryManager;
+        use core::ops::ControlFlow;
+
+        // Drops the address space's reference to `frame`, returning whether it was freed.
+        fn put_frame(frame: Address<libsys::Frame>) -> bool {
+            PhysicalMemoryManager::put_frame(frame)
+                .inspect_err(|err| warn!("Failed to free frame {frame:X?}: {err:?}"))
+                .unwrap_or(false)
+        }
+
+        let root_table = self.mapper.view_page_table();
+        // The higher half is shared with the kernel, so it must be left alone.
+        let userspace_entries = &root_table[..(root_table.len() / 2)];
+
+        // Safety: The root table is a valid root-level table.
+        let walker = unsafe {
+            paging::walker::Walker::new(
+                userspace_entries,
+                TableDepth::max(),
+                TableDepth::min(),
+                self.mapper.memory(),
+            )
+            .unwrap()
+        };
+
+        let mut freed_frames = 0;
+        walker.walk_present(|entry, depth| {
+            if depth.is_min() || entry.is_huge() {
+                let frame = entry.get_leaf_frame(depth);
+                // Only anonymous frames belong to the address space (e.g. MMIO frames don't).
+                let frame_count = depth.align() / page_size();
+                freed_frames += (frame.index()..(frame.index() + frame_count))
+                    .filter_map(Address::from_index)
+                    .filter(|&frame| {
+                        PhysicalMemoryManager::frame_info(frame)
+                            .is_ok_and(|info| info.kind == FrameKind::UserAnon)
+                    })
+                    .filter(|&frame| put_frame(frame))
+                    .count();
+            } else if put_frame(entry.get_frame()) {
+                // Every entry within the table has already been walked.
+                freed_frames += 1;
+            }
+
+            ControlFlow::<()>::Continue(())
+        });
+
+        if put_frame(self.mapper.root_frame()) {
+            freed_frames += 1;
+        }
+
+        freed_frames
     }
 
     /// ## Safety
     ///
     /// Caller must ensure that switching the currently active address space will not cause undefined behaviour.
     pub unsafe fn swap_into(&self) {
-        self.0.swap_into();
+        self.mapper.swap_into();
     }
 }
 
 impl core::fmt::Debug for AddressSpace {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        f.debug_tuple("AddressSpace")
-            .field(&self.0.view_page_table().as_ptr())
+        f.debug_struct("AddressSpace")
+            .field("Root Table", &self.mapper.view_page_table().as_ptr())
+            .field("Regions", &self.regions)
             .finish()
     }
 }
+
+crate::kernel_test! {
+    fn address_space_teardown() {
+        use crate::mem::pmm::PhysicalMemoryManager;
+
+        let mut address_space = AddressSpace::new_userspace();
+        let free_frames = PhysicalMemoryManager::free_frames();
+
+        // Map a huge page's worth of pages, and one more, so both leaf sizes are torn down.
+        let page_count = (TableDepth::new(1).unwrap().align() / page_size()) + 1;
+        address_space
+            .mmap(
+                Some(Address::new_truncate(TableDepth::new(1).unwrap().align())),
+                NonZeroUsize::new(page_count).unwrap(),
+                MmapPermissions::ReadWrite,
+                RegionKind::Anonymous,
+                CacheMode::WriteBack,
+            )
+            .map_err(|err| alloc::format!("{err}"))?;
+        crate::ktest_assert_eq!(address_space.resident_pages(), page_count);
+
+        // Safety: The address space was never loaded.
+        let freed_frames = unsafe { address_space.teardown() };
+        crate::ktest_assert!(freed_frames > page_count);
+        // The root frame was allocated before counting the free frames.
+        crate::ktest_assert!(PhysicalMemoryManager::free_frames() > free_frames);
+
+        Ok(())
+    }
+}
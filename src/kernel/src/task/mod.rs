@@ -7,6 +7,12 @@ pub use scheduling::*;
 mod address_space;
 pub use address_space::*;
 
+mod reaper;
+pub use reaper::*;
+
+mod region;
+pub use region::*;
+
 use alloc::{boxed::Box, string::String, vec::Vec};
 use bit_field::BitField;
 use core::num::NonZeroUsize;
@@ -104,9 +110,48 @@ impl Task {
                 Some(Address::new_truncate(STACK_START.get())),
                 STACK_PAGES,
                 MmapPermissions::ReadWrite,
+                RegionKind::Stack,
+                crate::mem::paging::CacheMode::WriteBack,
             )
             .unwrap();
 
+        trace!("Reserving loadable segments for task: {:?}.", id);
+        let mut segment_ranges = elf_segments
+            .iter()
+            .filter(|phdr| phdr.p_type == elf::abi::PT_LOAD && phdr.p_memsz > 0)
+            .map(|phdr| {
+                let start = load_offset + usize::try_from(phdr.p_vaddr).unwrap();
+                let end = start + usize::try_from(phdr.p_memsz).unwrap();
+
+                (
+                    libsys::align_down(start, libsys::page_shift()),
+                    libsys::align_up_div(end, libsys::page_shift()) * page_size(),
+                    segment_to_mmap_permissions(phdr.p_flags),
+                )
+            })
+            .collect::<Vec<_>>();
+        segment_ranges.sort_unstable_by_key(|&(start, _, _)| start);
+
+        // Segments may share a page at their boundary, which then belongs to the first segment's region.
+        let mut reserved_end = 0;
+        for (start, end, permissions) in segment_ranges {
+            let start = usize::max(start, reserved_end);
+            let Some(page_count) = NonZeroUsize::new(end.saturating_sub(start) / page_size())
+            else {
+                continue;
+            };
+
+            address_space
+                .reserve(Region::new(
+                    Address::new(start).unwrap(),
+                    page_count,
+                    permissions,
+                    RegionKind::File,
+                ))
+                .unwrap();
+            reserved_end = end;
+        }
+
         Self {
             id,
             priority,
@@ -180,10 +225,19 @@ impl Task {
 
         let fault_page = Address::new_truncate(address.get());
 
-        if self.address_space().is_mmapped(fault_page) {
+        if self.address_space().is_resident(fault_page) {
             return Err(Error::AlreadyMapped);
         }
 
+        // Only file-backed regions are mapped on demand.
+        if self
+            .address_space()
+            .find_region(address)
+            .is_none_or(|region| region.kind() != RegionKind::File)
+        {
+            return Err(Error::UnhandledAddress { addr: address });
+        }
+
         let fault_unoffset = address
             .get()
             .checked_sub(self.load_offset())
@@ -230,11 +284,7 @@ impl Task {
         trace!("Mapping the demand page RW so data can be copied.");
         let mapped_memory = self
             .address_space_mut()
-            .mmap(
-                Some(fault_page),
-                core::num::NonZeroUsize::MIN,
-                crate::task::MmapPermissions::ReadWrite,
-            )
+            .populate(fault_page, core::num::NonZeroUsize::MIN)
             .unwrap();
         // Safety: Address space allocator fulfills all required invariants.
         let mapped_memory = unsafe { mapped_memory.as_uninit_slice_mut() };
@@ -305,7 +355,7 @@ impl Task {
                     TableEntryFlags::PRESENT
                         | TableEntryFlags::USER
                         | TableEntryFlags::from(crate::task::segment_to_mmap_permissions(
-                            segment.p_type,
+                            segment.p_flags,
                         )),
                 )
                 .unwrap();
@@ -0,0 +1,74 @@
+//! Reclaims the memory held by tasks which have exited, or been killed.
+
+use crate::{interrupts::InterruptCell, task::Task};
+use spin::Mutex;
+
+/// Maximum number of killed tasks which can be awaiting [`reap_pending`] at once.
+const MAX_PENDING: usize = 8;
+
+/// Tasks killed from contexts which can't tear them down (i.e. from within an allocation, where the
+/// allocator's callers may hold the locks freeing the task's memory requires).
+static PENDING: InterruptCell<Mutex<[Option<Task>; MAX_PENDING]>> =
+    InterruptCell::new(Mutex::new([const { None }; MAX_PENDING]));
+
+/// Tears down the address space of `task`, returning the number of frames freed.
+///
+/// # Remark
+///
+/// This must only be called once no hardware thread has the task's address space loaded (i.e.
+/// after switching away from it), as the page tables it's using are freed.
+pub fn reap(task: Task) -> usize {
+    debug_assert!(
+        !task.address_space.is_current(),
+        "cannot reap a task whose address space is loaded"
+    );
+
+    let id = task.id();
+    // Safety: The task is dead, so nothing references its memory, and its address space isn't loaded.
+    let freed_frames = unsafe { task.address_space.teardown() };
+    trace!("Reaped task {id:?}, freeing {freed_frames} frames.");
+
+    freed_frames
+}
+
+/// Queues `task` to be reaped by the next call to [`reap_pending`], returning it if the queue is full.
+///
+/// Unlike [`reap`], this neither allocates nor frees memory, so it can be called from within an allocation.
+pub fn defer_reap(task: Task) -> Result<(), Task> {
+    PENDING.with(
+        |pending| match pending.lock().iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(task);
+
+                Ok(())
+            }
+
+            None => Err(task),
+        },
+    )
+}
+
+/// Reaps every task queued by [`defer_reap`], returning the number of frames freed.
+///
+/// # Remark
+///
+/// This frees the tasks' memory, so it must not be called with any allocator (or the kernel mapper)
+/// locked, e.g. from the scheduler once it's switched tasks.
+pub fn reap_pending() -> usize {
+    let mut freed_frames = 0;
+
+    while let Some(task) = PENDING.with(|pending| pending.lock().iter_mut().find_map(Option::take))
+    {
+        // The idle task doesn't switch address spaces, so the task's may still be loaded.
+        if task.address_space.is_current() {
+            crate::mem::with_kmapper(|kmapper| {
+                // Safety: The kernel address space maps everything the kernel uses.
+                unsafe { kmapper.swap_into() };
+            });
+        }
+
+        freed_frames += reap(task);
+    }
+
+    freed_frames
+}
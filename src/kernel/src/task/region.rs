@@ -0,0 +1,169 @@
+use crate::task::MmapPermissions;
+use alloc::collections::BTreeMap;
+use core::{num::NonZeroUsize, ops::Range};
+use libsys::{Address, Page, Virtual, page_size};
+
+/// What backs the memory of a region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Zero-initialized memory, which is mapped eagerly.
+    Anonymous,
+    /// Memory loaded from a file (e.g. an ELF segment), which is mapped when first accessed.
+    File,
+    /// Device memory (e.g. MMIO).
+    Device,
+    /// A task's stack.
+    Stack,
+}
+
+/// A contiguous range of pages within an address space, which share permissions and backing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    start: Address<Page>,
+    page_count: NonZeroUsize,
+    permissions: MmapPermissions,
+    kind: RegionKind,
+}
+
+impl Region {
+    pub const fn new(
+        start: Address<Page>,
+        page_count: NonZeroUsize,
+        permissions: MmapPermissions,
+        kind: RegionKind,
+    ) -> Self {
+        Self {
+            start,
+            page_count,
+            permissions,
+            kind,
+        }
+    }
+
+    #[inline]
+    pub const fn start(&self) -> Address<Page> {
+        self.start
+    }
+
+    #[inline]
+    pub const fn page_count(&self) -> NonZeroUsize {
+        self.page_count
+    }
+
+    #[inline]
+    pub const fn permissions(&self) -> MmapPermissions {
+        self.permissions
+    }
+
+    #[inline]
+    pub const fn kind(&self) -> RegionKind {
+        self.kind
+    }
+
+    /// Range of virtual addresses the region spans.
+    pub fn range(&self) -> Range<usize> {
+        let start = self.start.get().get();
+
+        start..(start + (self.page_count.get() * page_size()))
+    }
+
+    pub fn contains(&self, address: Address<Virtual>) -> bool {
+        self.range().contains(&address.get())
+    }
+}
+
+/// The regions of an address space, ordered by their start address.
+#[derive(Debug, Default)]
+pub struct Regions(BTreeMap<usize, Region>);
+
+impl Regions {
+    pub const fn new() -> Self {
+        Self(BTreeMap::new())
+    }
+
+    /// Finds the region which contains `address`.
+    pub fn find(&self, address: Address<Virtual>) -> Option<&Region> {
+        self.0
+            .range(..=address.get())
+            .next_back()
+            .map(|(_, region)| region)
+            .filter(|region| region.contains(address))
+    }
+
+    /// Whether any region overlaps `range`.
+    pub fn overlaps(&self, range: &Range<usize>) -> bool {
+        // Only the last region starting before the range ends can overlap it, as regions never overlap.
+        self.0
+            .range(..range.end)
+            .next_back()
+            .is_some_and(|(_, region)| region.range().end > range.start)
+    }
+
+    /// Inserts `region`, returning it back if it overlaps an existing region.
+    pub fn insert(&mut self, region: Region) -> Result<(), Region> {
+        if self.overlaps(&region.range()) {
+            Err(region)
+        } else {
+            self.0.insert(region.start().get().get(), region);
+
+            Ok(())
+        }
+    }
+
+    pub fn remove(&mut self, start: Address<Page>) -> Option<Region> {
+        self.0.remove(&start.get().get())
+    }
+
+    /// Finds the lowest gap between regions within `bounds` which can fit `page_count` pages.
+    pub fn find_gap(
+        &self,
+        page_count: NonZeroUsize,
+        bounds: Range<usize>,
+    ) -> Option<Address<Page>> {
+        let len = page_count.get() * page_size();
+
+        let mut gap_start = bounds.start;
+        for region in self.0.values() {
+            let region_range = region.range();
+
+            if region_range.start >= gap_start.checked_add(len)? {
+                break;
+            }
+
+            gap_start = usize::max(gap_start, region_range.end);
+        }
+
+        (gap_start.checked_add(len)? <= bounds.end)
+            .then(|| Address::new(gap_start))
+            .flatten()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Region> {
+        self.0.values()
+    }
+}
+
+crate::kernel_test! {
+    fn regions_find_gap() {
+        let page = |index: usize| Address::<Page>::new(index * page_size()).unwrap();
+        let pages = |count: usize| NonZeroUsize::new(count).unwrap();
+        let region = |index, count| {
+            Region::new(page(index), pages(count), MmapPermissions::ReadWrite, RegionKind::Anonymous)
+        };
+
+        let mut regions = Regions::new();
+        crate::ktest_assert!(regions.insert(region(1, 2)).is_ok());
+        crate::ktest_assert!(regions.insert(region(5, 1)).is_ok());
+        crate::ktest_assert!(regions.insert(region(2, 2)).is_err());
+
+        let bounds = page_size()..(16 * page_size());
+        crate::ktest_assert_eq!(regions.find_gap(pages(2), bounds.clone()), Some(page(3)));
+        crate::ktest_assert_eq!(regions.find_gap(pages(3), bounds.clone()), Some(page(6)));
+        crate::ktest_assert_eq!(regions.find_gap(pages(11), bounds), None);
+
+        crate::ktest_assert!(regions.find(page(2).get()).is_some());
+        crate::ktest_assert!(regions.find(page(3).get()).is_none());
+
+        Ok(())
+    }
+}
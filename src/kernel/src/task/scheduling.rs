@@ -67,6 +67,9 @@ impl Scheduler {
         }
 
         self.next_task(&mut processes, state, regs);
+        drop(processes);
+
+        crate::task::reap_pending();
     }
 
     /// Attempts to schedule the next task in the local task queue.
@@ -89,12 +92,23 @@ impl Scheduler {
     pub fn kill_task(&mut self, isf: &mut InterruptStackFrame, regs: &mut Registers) {
         debug_assert!(!crate::interrupts::is_enabled());
 
-        // TODO add process to reap queue to reclaim address space memory
         let process = self.task.take().expect("cannot exit without process");
         trace!("Exiting process: {:?}", process.id());
 
         let mut processes = PROCESSES.lock();
         self.next_task(&mut processes, isf, regs);
+        drop(processes);
+
+        // The idle task doesn't switch address spaces, so the dead task's may still be loaded.
+        if process.address_space.is_current() {
+            crate::mem::with_kmapper(|kmapper| {
+                // Safety: The kernel address space maps everything the idle task uses.
+                unsafe { kmapper.swap_into() };
+            });
+        }
+
+        crate::task::reap(process);
+        crate::task::reap_pending();
     }
 
     fn next_task(
@@ -0,0 +1,307 @@
+//! Boot timeline, recording the TSC timestamps of named init phases on each
+//! hardware thread, and printing a summary report once init completes.
+
+use crate::interrupts::InterruptCell;
+use alloc::string::String;
+use core::fmt::Write;
+use spin::Mutex;
+
+/// Maximum number of phases the timeline can record.
+const MAX_PHASES: usize = 64;
+
+/// Line prefix for the machine-readable (JSON) form of the boot report.
+pub const REPORT_JSON_PREFIX: &str = "BOOT-REPORT-JSON ";
+
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    name: &'static str,
+    hwthread: u32,
+    start: u64,
+    end: Option<u64>,
+}
+
+struct BootloaderInfo {
+    name: String,
+    version: String,
+    revision: u64,
+}
+
+struct Timeline {
+    records: [Option<Record>; MAX_PHASES],
+    len: usize,
+    bootloader: Option<BootloaderInfo>,
+}
+
+static TIMELINE: InterruptCell<Mutex<Timeline>> = InterruptCell::new(Mutex::new(Timeline {
+    records: [None; MAX_PHASES],
+    len: 0,
+    bootloader: None,
+}));
+
+fn read_tsc() -> u64 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        // Safety: I don't know why `_rdtsc()` is unsafe (it has no side effects).
+        unsafe { core::arch::x86_64::_rdtsc() }
+    }
+}
+
+/// Frequency of the TSC in Hz, if it can be determined without a calibrated clock.
+fn tsc_frequency() -> Option<u64> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        use crate::arch::x86_64::cpuid::{CPUID, TscInfo};
+
+        CPUID
+            .get_tsc_info()
+            .as_ref()
+            .and_then(TscInfo::tsc_frequency)
+            .or_else(|| {
+                CPUID
+                    .get_processor_frequency_info()
+                    .map(|info| u64::from(info.processor_base_frequency()) * 1_000_000)
+            })
+            .filter(|frequency| *frequency > 0)
+    }
+}
+
+/// Guard for a phase of the boot timeline. The phase ends when the guard is dropped.
+pub struct Phase {
+    index: Option<usize>,
+}
+
+impl Phase {
+    /// Records the start of a new named phase on the current hardware thread.
+    pub fn begin(name: &'static str) -> Self {
+        let hwthread = crate::cpu::get_id();
+
+        let index = TIMELINE.with(|timeline| {
+            let mut timeline = timeline.lock();
+
+            let index = timeline.len;
+            let record = timeline.records.get_mut(index)?;
+            *record = Some(Record {
+                name,
+                hwthread,
+                start: read_tsc(),
+                end: None,
+            });
+            timeline.len += 1;
+
+            Some(index)
+        });
+
+        if index.is_none() {
+            libsys::do_once!({
+                warn!("Boot timeline is full; further phases will not be recorded.");
+            });
+        }
+
+        Self { index }
+    }
+}
+
+impl Drop for Phase {
+    fn drop(&mut self) {
+        let end = read_tsc();
+
+        if let Some(index) = self.index {
+            TIMELINE.with(|timeline| {
+                if let Some(record) = timeline.lock().records[index].as_mut() {
+                    record.end = Some(end);
+                }
+            });
+        }
+    }
+}
+
+/// Runs `func` as a named phase of the boot timeline.
+pub fn phase<T>(name: &'static str, func: impl FnOnce() -> T) -> T {
+    let _phase = Phase::begin(name);
+    func()
+}
+
+/// Copies the bootloader info into the timeline, so it can be reported after bootloader
+/// memory has been reclaimed.
+pub fn set_bootloader_info(name: &str, version: &str, revision: u64) {
+    TIMELINE.with(|timeline| {
+        timeline.lock().bootloader = Some(BootloaderInfo {
+            name: String::from(name),
+            version: String::from(version),
+            revision,
+        });
+    });
+}
+
+/// Prints the boot report, both as a table and as a single line of JSON prefixed
+/// with [`REPORT_JSON_PREFIX`].
+pub fn report() {
+    use crate::mem::pmm::PhysicalMemoryManager;
+
+    let tsc_frequency = tsc_frequency();
+    let total_memory = PhysicalMemoryManager::total_memory();
+    let usable_frames = PhysicalMemoryManager::free_frames();
+    let hwthreads_online = crate::cpu::hwthreads_online();
+
+    TIMELINE.with(|timeline| {
+        let timeline = timeline.lock();
+        let records = timeline.records[..timeline.len].iter().flatten();
+
+        let epoch = records
+            .clone()
+            .map(|record| record.start)
+            .min()
+            .unwrap_or(0);
+        let boot_cycles = records
+            .clone()
+            .filter_map(|record| record.end)
+            .max()
+            .map_or(0, |end| end - epoch);
+        let to_us =
+            |cycles: u64| tsc_frequency.map(|frequency| cycles / (frequency / 1_000_000).max(1));
+
+        let mut table = String::new();
+        let mut json = String::new();
+
+        writeln!(table, "Boot Report").unwrap();
+        writeln!(
+            table,
+            "  {: <36}{: >10}{: >16}{: >14}",
+            "PHASE", "HWTHREAD", "CYCLES", "TIME (us)"
+        )
+        .unwrap();
+
+        write!(json, "{REPORT_JSON_PREFIX}{{\"phases\":[").unwrap();
+        for (index, record) in records.enumerate() {
+            let cycles = record.end.map(|end| end - record.start);
+            let us = cycles.and_then(to_us);
+
+            writeln!(
+                table,
+                "  {: <36}{: >10}{: >16}{: >14}",
+                record.name,
+                record.hwthread,
+                OptionDisplay(cycles),
+                OptionDisplay(us)
+            )
+            .unwrap();
+
+            if index > 0 {
+                json.push(',');
+            }
+            write!(
+                json,
+                "{{\"name\":{},\"hwthread\":{},\"start_cycles\":{},\"cycles\":{},\"us\":{}}}",
+                JsonStr(record.name),
+                record.hwthread,
+                record.start - epoch,
+                JsonOption(cycles),
+                JsonOption(us)
+            )
+            .unwrap();
+        }
+        write!(
+            json,
+            "],\"boot_cycles\":{boot_cycles},\"boot_us\":{},\"tsc_hz\":{}",
+            JsonOption(to_us(boot_cycles)),
+            JsonOption(tsc_frequency)
+        )
+        .unwrap();
+        write!(
+            json,
+            ",\"total_memory\":{total_memory},\"usable_frames\":{usable_frames},\"page_size\":{}",
+            libsys::page_size()
+        )
+        .unwrap();
+        write!(
+            json,
+            ",\"hwthreads_online\":{hwthreads_online},\"bootloader\":"
+        )
+        .unwrap();
+
+        writeln!(
+            table,
+            "  {: <36}{: >10}{: >16}{: >14}",
+            "TOTAL",
+            "",
+            boot_cycles,
+            OptionDisplay(to_us(boot_cycles))
+        )
+        .unwrap();
+        writeln!(
+            table,
+            "  Total Memory        {} MiB",
+            total_memory / (libsys::MIBIBYTE as usize)
+        )
+        .unwrap();
+        writeln!(table, "  Usable Frames       {usable_frames}").unwrap();
+        writeln!(table, "  Hardware Threads    {hwthreads_online}").unwrap();
+
+        if let Some(bootloader) = timeline.bootloader.as_ref() {
+            writeln!(
+                table,
+                "  Bootloader          {} v{} (rev {})",
+                bootloader.name, bootloader.version, bootloader.revision
+            )
+            .unwrap();
+            write!(
+                json,
+                "{{\"name\":{},\"version\":{},\"revision\":{}}}}}",
+                JsonStr(&bootloader.name),
+                JsonStr(&bootloader.version),
+                bootloader.revision
+            )
+            .unwrap();
+        } else {
+            writeln!(table, "  Bootloader          UNKNOWN").unwrap();
+            json.push_str("null}");
+        }
+
+        info!("{table}");
+        info!("{json}");
+    });
+}
+
+/// Displays `-` in place of `None`.
+struct OptionDisplay<T>(Option<T>);
+
+impl<T: core::fmt::Display> core::fmt::Display for OptionDisplay<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match &self.0 {
+            Some(value) => value.fmt(f),
+            None => f.pad("-"),
+        }
+    }
+}
+
+/// Displays a JSON `null` in place of `None`.
+struct JsonOption<T>(Option<T>);
+
+impl<T: core::fmt::Display> core::fmt::Display for JsonOption<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match &self.0 {
+            Some(value) => value.fmt(f),
+            None => f.write_str("null"),
+        }
+    }
+}
+
+/// Displays a string as a quoted & escaped JSON string.
+struct JsonStr<'a>(&'a str);
+
+impl core::fmt::Display for JsonStr<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_char('"')?;
+
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                c if c.is_control() => write!(f, "\\u{:04x}", u32::from(c))?,
+                c => f.write_char(c)?,
+            }
+        }
+
+        f.write_char('"')
+    }
+}
@@ -1,3 +1,6 @@
+mod stats;
+pub use stats::*;
+
 mod volatile;
 pub use volatile::*;
 
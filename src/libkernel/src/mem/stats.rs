@@ -0,0 +1,78 @@
+/// Current version of [`MemoryStats`].
+pub const MEMORY_STATS_VERSION: u32 = 1;
+
+/// Vector of the memory statistics syscall, which isn't (yet) defined by `libsys`.
+///
+/// Arguments are a pointer to, and the length of, a buffer to copy a [`MemoryStats`] into.
+pub const MEMORY_STATS_VECTOR: usize = 0x1000;
+
+/// Physical memory statistics, as written by the kernel's memory statistics syscall.
+///
+/// Fields are only ever appended to the struct (with [`MEMORY_STATS_VERSION`] incremented),
+/// so a caller built against an older version can read the prefix it knows about. The
+/// kernel writes as much of the struct as fits in the caller's buffer, and reports the
+/// full size it would have written in `size`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Version of the struct's layout.
+    pub version: u32,
+    /// Size of the struct (in bytes) for this version.
+    pub size: u32,
+
+    /// Size of a frame (in bytes).
+    pub frame_size: u64,
+
+    pub total_frames: u64,
+    pub free_frames: u64,
+    pub used_frames: u64,
+    pub reserved_frames: u64,
+
+    // Used frames, broken down by consumer.
+    pub kernel_frames: u64,
+    pub kernel_heap_frames: u64,
+    pub page_table_frames: u64,
+    pub user_frames: u64,
+    pub dma_frames: u64,
+    pub mmio_frames: u64,
+    pub framebuffer_frames: u64,
+}
+
+impl MemoryStats {
+    /// Returns empty statistics, with the header fields set for the current version.
+    pub const fn new() -> Self {
+        Self {
+            version: MEMORY_STATS_VERSION,
+            size: core::mem::size_of::<Self>() as u32,
+            frame_size: 0,
+            total_frames: 0,
+            free_frames: 0,
+            used_frames: 0,
+            reserved_frames: 0,
+            kernel_frames: 0,
+            kernel_heap_frames: 0,
+            page_table_frames: 0,
+            user_frames: 0,
+            dma_frames: 0,
+            mmio_frames: 0,
+            framebuffer_frames: 0,
+        }
+    }
+
+    /// Views the statistics as their raw bytes, as they're copied to the caller.
+    pub fn as_bytes(&self) -> &[u8] {
+        // Safety: `Self` is `repr(C)`, and composed only of integers with no padding between them.
+        unsafe {
+            core::slice::from_raw_parts(
+                (self as *const Self).cast::<u8>(),
+                core::mem::size_of::<Self>(),
+            )
+        }
+    }
+}
+
+impl Default for MemoryStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -200,6 +200,9 @@ impl IA32_FMASK {
     }
 }
 
+// Page attribute table, which maps the PAT, PCD, and PWT bits of page table entries to memory types.
+generic_msr!(IA32_PAT, 0x277);
+
 pub struct IA32_TSC_DEADLINE;
 impl IA32_TSC_DEADLINE {
     /// Sets the timestamp counter deadline.
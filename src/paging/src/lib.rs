@@ -1,13 +1,60 @@
+//! Page table structures, and the logic which walks and modifies them.
+//!
+//! Page tables are only ever reached through a [`PhysicalMemory`], which also provides the frames they're
+//! allocated from, so none of this depends on the kernel's memory layout (and can be tested on the host).
+
+#![cfg_attr(not(test), no_std)]
+#![feature(step_trait)]
+
+#[cfg(test)]
+mod tests;
+
+mod mapper;
 pub mod walker;
+pub use mapper::*;
+
 use bit_field::BitField;
-use core::{fmt, iter::Step};
+use bitflags::bitflags;
+use core::{fmt, iter::Step, num::NonZeroUsize, ptr::NonNull};
 use libkernel::mem::{InteriorRef, Mut, Ref};
 use libsys::{
     Address, Frame, Page, Virtual, page_shift, table_index_mask, table_index_shift,
     table_index_size,
 };
 
-use crate::mem::{Hhdm, pmm::PhysicalMemoryManager};
+/// Physical memory, as seen by the page table code: a way to access frames (which page tables are stored in),
+/// to allocate & free them, and to invalidate translations once entries change.
+///
+/// ## Safety
+///
+/// - [`Self::frame_ptr`] must return a pointer which is valid for reads and writes of a whole frame.
+/// - Allocated frames must not be in use elsewhere until they're freed.
+pub unsafe trait PhysicalMemory {
+    /// Consumer which frames are allocated for.
+    type FrameKind: Copy;
+
+    /// Returns a pointer through which the memory of `frame` is accessed.
+    fn frame_ptr(&self, frame: Address<Frame>) -> NonNull<u8>;
+
+    /// Allocates a frame for a page table. The frame needn't be zeroed.
+    fn allocate_table(&self) -> Option<Address<Frame>>;
+
+    /// Allocates `count` contiguous frames, aligned to their total size (rounded up to a power of two).
+    fn allocate_frames(&self, count: NonZeroUsize, kind: Self::FrameKind)
+    -> Option<Address<Frame>>;
+
+    fn free_frame(&self, frame: Address<Frame>);
+
+    /// Marks `frame` as in use by a mapping, as it wasn't allocated with [`Self::allocate_frames`].
+    fn lock_frame(&self, frame: Address<Frame>) -> Result<()>;
+
+    /// Invalidates any cached translations of the `page_count` pages from `page`, in the address space whose
+    /// root table is `root_frame`.
+    fn invalidate(&self, root_frame: Address<Frame>, page: Address<Page>, page_count: usize);
+
+    /// Whether leaf entries can map 1 GiB pages.
+    fn has_1gib_pages(&self) -> bool;
+}
 
 #[repr(transparent)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -22,15 +69,23 @@ impl TableDepth {
     #[inline]
     pub fn max() -> Self {
         Self({
-            #[cfg(target_arch = "x86_64")]
+            #[cfg(all(target_arch = "x86_64", target_os = "none"))]
             {
-                use crate::arch::x86_64::registers::control::{CR4, CR4Flags};
+                const LA57_BIT: usize = 12;
 
-                if CR4::read().contains(CR4Flags::LA57) {
-                    5
-                } else {
-                    4
+                let cr4: u64;
+                // Safety: Reading CR4 has no side effects.
+                unsafe {
+                    core::arch::asm!("mov {}, cr4", out(reg) cr4, options(nostack, nomem));
                 }
+
+                if cr4.get_bit(LA57_BIT) { 5 } else { 4 }
+            }
+
+            // Hosted builds (i.e. tests) model 4-level paging.
+            #[cfg(not(target_os = "none"))]
+            {
+                4
             }
         })
     }
@@ -114,24 +169,32 @@ impl Step for TableDepth {
     }
 }
 
-crate::error_impl! {
-    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-    #[allow(clippy::enum_variant_names)]
-    pub enum Error {
-        /// The underlying allocator is out of memory.
-        AllocError => None,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    /// The underlying allocator is out of memory.
+    AllocError,
 
-        /// An attempted mapping sits outside the physical memory bounds.
-        FrameBounds => None,
+    /// An attempted mapping sits outside the physical memory bounds.
+    FrameBounds,
 
-        /// Unexpected huge page was encountered.
-        HugePage => None,
+    /// Unexpected huge page was encountered.
+    HugePage,
 
-        /// The specified page is not mapped.
-        NotMapped { addr: Address<Virtual> } => None
+    /// The specified page is not mapped.
+    NotMapped { addr: Address<Virtual> },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        <Self as fmt::Debug>::fmt(self, f)
     }
 }
 
+impl core::error::Error for Error {}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
 #[cfg(target_arch = "x86_64")]
 bitflags! {
     #[repr(transparent)]
@@ -140,8 +203,6 @@ bitflags! {
         const PRESENT = 1 << 0;
         const WRITABLE = 1 << 1;
         const USER = 1 << 2;
-        const WRITE_THROUGH = 1 << 3;
-        const UNCACHEABLE = 1 << 4;
         const ACCESSED = 1 << 5;
         const DIRTY = 1 << 6;
         const HUGE = 1 << 7;
@@ -153,8 +214,6 @@ bitflags! {
         const RW = Self::PRESENT.bits() | Self::WRITABLE.bits() | Self::NO_EXECUTE.bits();
         const RX = Self::PRESENT.bits();
         const PTE = Self::PRESENT.bits() | Self::WRITABLE.bits() | Self::USER.bits();
-
-        const MMIO = Self::RW.bits() | Self::UNCACHEABLE.bits();
     }
 }
 
@@ -175,14 +234,59 @@ bitflags! {
         const RW = Self::VALID.bits() | Self::READ.bits() | Self::WRITE.bits();
         const RX = Self::VALID.bits() | Self::READ.bits() | Self::EXECUTE.bits();
         const PTE = Self::VALID.bits() | Self::READ.bits() | Self::WRITE.bits();
-
-        const MMIO = Self::RW.bits();
     }
 }
 
 #[cfg(target_arch = "riscv64")]
 pub const PTE_FRAME_ADDRESS_MASK: u64 = 0x003FFFFF_FFFFFC00;
 
+/// Memory type used for accesses through a leaf entry. It's selected by the entry's PAT, PCD, and PWT bits,
+/// which index into the page attribute table (programmed with [`CacheMode::PAT_LAYOUT`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Write-back (WB), for ordinary memory.
+    WriteBack,
+    /// Write-through (WT).
+    WriteThrough,
+    /// Uncached (UC), for device registers, which must see every access, in order.
+    Uncached,
+    /// Uncached, unless the MTRRs select write-combining (UC-).
+    UncachedMinus,
+    /// Write-combining (WC), for memory which is written in bulk (e.g. the framebuffer).
+    WriteCombining,
+}
+
+impl CacheMode {
+    /// Value programmed into `IA32_PAT` on every hardware thread: WB, WT, UC-, UC, WP, WC, UC-, UC.
+    ///
+    /// The first four entries are the power-on defaults, and the layout as a whole matches the one the
+    /// bootloader programs, so mappings made before it's programmed keep their memory types.
+    pub const PAT_LAYOUT: u64 = 0x0007_0105_0007_0406;
+
+    /// Index of the page attribute table entry which selects this memory type.
+    const fn pat_index(self) -> u64 {
+        match self {
+            Self::WriteBack => 0,
+            Self::WriteThrough => 1,
+            Self::UncachedMinus => 2,
+            Self::Uncached => 3,
+            Self::WriteCombining => 5,
+        }
+    }
+
+    const fn from_pat_index(index: u64) -> Option<Self> {
+        match index {
+            0 => Some(Self::WriteBack),
+            1 => Some(Self::WriteThrough),
+            2 | 6 => Some(Self::UncachedMinus),
+            3 | 7 => Some(Self::Uncached),
+            5 => Some(Self::WriteCombining),
+            // Write-protect (WP) isn't used.
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FlagsModify {
     Set,
@@ -199,6 +303,16 @@ pub struct PageTableEntry(u64);
 impl PageTableEntry {
     #[cfg(target_arch = "x86_64")]
     const FRAME_ADDRESS_RANGE: core::ops::Range<usize> = 12..51;
+    #[cfg(target_arch = "x86_64")]
+    const PWT_BIT: usize = 3;
+    #[cfg(target_arch = "x86_64")]
+    const PCD_BIT: usize = 4;
+    /// The PAT bit of 4 KiB entries, which is the huge bit of higher-level entries.
+    #[cfg(target_arch = "x86_64")]
+    const PAT_BIT: usize = 7;
+    /// The PAT bit of huge entries, which is the lowest frame address bit of 4 KiB entries.
+    #[cfg(target_arch = "x86_64")]
+    const HUGE_PAT_BIT: usize = 12;
 
     /// Returns an empty `Self`. All bits of this entry will be 0.
     #[inline]
@@ -224,6 +338,46 @@ impl PageTableEntry {
             .unwrap()
     }
 
+    /// Gets the frame mapped by this entry, given it's a leaf entry at `depth`. Unlike [`Self::get_frame`],
+    /// this excludes the PAT bit of huge entries, which sits within the frame address bits.
+    pub fn get_leaf_frame(self, depth: TableDepth) -> Address<Frame> {
+        Address::new(self.get_frame().get().get() & !(depth.align() - 1)).unwrap()
+    }
+
+    const fn pat_bit(depth: TableDepth) -> usize {
+        if depth.get() == TableDepth::min().get() {
+            Self::PAT_BIT
+        } else {
+            Self::HUGE_PAT_BIT
+        }
+    }
+
+    fn pat_index(self, depth: TableDepth) -> u64 {
+        u64::from(self.0.get_bit(Self::PWT_BIT))
+            | (u64::from(self.0.get_bit(Self::PCD_BIT)) << 1)
+            | (u64::from(self.0.get_bit(Self::pat_bit(depth))) << 2)
+    }
+
+    fn set_pat_index(&mut self, index: u64, depth: TableDepth) {
+        self.0.set_bit(Self::PWT_BIT, index.get_bit(0));
+        self.0.set_bit(Self::PCD_BIT, index.get_bit(1));
+        self.0.set_bit(Self::pat_bit(depth), index.get_bit(2));
+    }
+
+    /// Gets the memory type of this entry, given it's a leaf entry at `depth`.
+    pub fn cache_mode(self, depth: TableDepth) -> Option<CacheMode> {
+        CacheMode::from_pat_index(self.pat_index(depth))
+    }
+
+    /// Sets the memory type of this entry, given it's a leaf entry at `depth`.
+    ///
+    /// ## Safety
+    ///
+    /// Caller must ensure the memory isn't mapped elsewhere with a conflicting memory type.
+    pub unsafe fn set_cache_mode(&mut self, cache_mode: CacheMode, depth: TableDepth) {
+        self.set_pat_index(cache_mode.pat_index(), depth);
+    }
+
     /// Sets the entry's frame index.
     ///
     /// ## Safety
@@ -264,8 +418,8 @@ impl PageTableEntry {
             FlagsModify::Toggle => attributes.toggle(new_attributes),
         }
 
-        #[cfg(target_arch = "x86_64")]
-        if !crate::arch::x86_64::registers::msr::IA32_EFER::get_nxe() {
+        #[cfg(all(target_arch = "x86_64", target_os = "none"))]
+        if !msr::IA32_EFER::get_nxe() {
             // This bit is reserved if NXE is not supported. For now, this means silently removing it for compatability.
             attributes.remove(TableEntryFlags::NO_EXECUTE);
         }
@@ -295,12 +449,13 @@ impl fmt::Debug for PageTableEntry {
     }
 }
 
-pub struct PageTable<'a, RefKind: InteriorRef> {
+pub struct PageTable<'a, RefKind: InteriorRef, M: PhysicalMemory> {
     depth: TableDepth,
     entry: <RefKind as InteriorRef>::RefType<'a, PageTableEntry>,
+    memory: &'a M,
 }
 
-impl<RefKind: InteriorRef> core::ops::Deref for PageTable<'_, RefKind> {
+impl<RefKind: InteriorRef, M: PhysicalMemory> core::ops::Deref for PageTable<'_, RefKind, M> {
     type Target = PageTableEntry;
 
     fn deref(&self) -> &Self::Target {
@@ -308,20 +463,20 @@ impl<RefKind: InteriorRef> core::ops::Deref for PageTable<'_, RefKind> {
     }
 }
 
-impl core::ops::DerefMut for PageTable<'_, Mut> {
+impl<M: PhysicalMemory> core::ops::DerefMut for PageTable<'_, Mut, M> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut *self.entry
     }
 }
 
-impl<RefKind: InteriorRef> PageTable<'_, RefKind> {
+impl<RefKind: InteriorRef, M: PhysicalMemory> PageTable<'_, RefKind, M> {
     #[inline]
     pub const fn depth(&self) -> TableDepth {
         self.depth
     }
 
     fn table_ptr(&self) -> *mut PageTableEntry {
-        core::ptr::with_exposed_provenance_mut(Hhdm::offset().get() + self.get_frame().get().get())
+        self.memory.frame_ptr(self.get_frame()).cast().as_ptr()
     }
 
     pub fn entries(&self) -> &[PageTableEntry] {
@@ -330,13 +485,17 @@ impl<RefKind: InteriorRef> PageTable<'_, RefKind> {
     }
 }
 
-impl<'a> PageTable<'a, Ref> {
+impl<'a, M: PhysicalMemory> PageTable<'a, Ref, M> {
     /// ## Safety
     ///
-    /// - Page table entry must point to a valid page table.
+    /// - Page table entry must point to a valid page table, within `memory`.
     /// - Page table depth must be correct for the provided table.
-    pub const unsafe fn new(depth: TableDepth, entry: &'a PageTableEntry) -> Self {
-        Self { depth, entry }
+    pub const unsafe fn new(depth: TableDepth, entry: &'a PageTableEntry, memory: &'a M) -> Self {
+        Self {
+            depth,
+            entry,
+            memory,
+        }
     }
 
     pub fn with_entry<T>(
@@ -356,7 +515,7 @@ impl<'a> PageTable<'a, Ref> {
                 // Safety: Since the state of the page tables can not be fully modelled or controlled within the kernel itself,
                 //          we can't be 100% certain this is safe. However, in the case that it isn't, there's a near-certain
                 //          chance that the entire kernel will explode shortly after reading bad data like this as a page table.
-                (unsafe { PageTable::<Ref>::new(next_depth, sub_entry) })
+                (unsafe { PageTable::<Ref, M>::new(next_depth, sub_entry, self.memory) })
                     .with_entry(page, to_depth, with_fn)
             } else {
                 Err(Error::NotMapped { addr: page.get() })
@@ -365,15 +524,42 @@ impl<'a> PageTable<'a, Ref> {
             Err(Error::HugePage)
         }
     }
+
+    /// Finds the leaf entry (of any size) which maps `page`, returning a copy of it & its depth.
+    pub fn leaf_entry(&self, page: Address<Page>) -> Result<(PageTableEntry, TableDepth)> {
+        if self.depth().is_min() || self.is_huge() {
+            if self.is_present() {
+                Ok((**self, self.depth()))
+            } else {
+                Err(Error::NotMapped { addr: page.get() })
+            }
+        } else {
+            let next_depth = self.depth().next_checked().unwrap();
+            let entry_index = self.depth().index_of(page.get()).unwrap();
+            let sub_entry = self.entries().get(entry_index).unwrap();
+
+            if sub_entry.is_present() {
+                // Safety: See `Self::with_entry()`.
+                (unsafe { PageTable::<Ref, M>::new(next_depth, sub_entry, self.memory) })
+                    .leaf_entry(page)
+            } else {
+                Err(Error::NotMapped { addr: page.get() })
+            }
+        }
+    }
 }
 
-impl<'a> PageTable<'a, Mut> {
+impl<'a, M: PhysicalMemory> PageTable<'a, Mut, M> {
     /// ## Safety
     ///
-    /// - Page table entry must point to a valid page table.
+    /// - Page table entry must point to a valid page table, within `memory`.
     /// - Page table depth must be correct for the provided table.
-    pub unsafe fn new(depth: TableDepth, entry: &'a mut PageTableEntry) -> Self {
-        Self { depth, entry }
+    pub unsafe fn new(depth: TableDepth, entry: &'a mut PageTableEntry, memory: &'a M) -> Self {
+        Self {
+            depth,
+            entry,
+            memory,
+        }
     }
 
     pub fn entries_mut(&mut self) -> &mut [PageTableEntry] {
@@ -381,6 +567,44 @@ impl<'a> PageTable<'a, Mut> {
         unsafe { core::slice::from_raw_parts_mut(self.table_ptr(), table_index_size()) }
     }
 
+    /// Splits this huge entry into a new table of entries one depth smaller, which map the same
+    /// frames with the same attributes.
+    fn split(&mut self) -> Result<()> {
+        debug_assert!(self.is_huge());
+
+        let next_depth = self.depth().next_checked().unwrap();
+        let frame = self.get_leaf_frame(self.depth());
+        // The PAT bit moves when splitting down to 4 KiB entries, so the index is carried over instead.
+        let pat_index = self.pat_index(self.depth());
+
+        let mut attributes = self.get_attributes();
+        // At the lowest depth, this bit instead selects the page attribute table entry.
+        if next_depth.is_min() {
+            attributes.remove(TableEntryFlags::HUGE);
+        }
+
+        let table_frame = self.memory.allocate_table().ok_or(Error::AllocError)?;
+        let table_ptr = self.memory.frame_ptr(table_frame).cast().as_ptr();
+        // Safety: Frame was just allocated as a page table, and the memory provides a pointer to all of it.
+        let table: &mut [PageTableEntry] =
+            unsafe { core::slice::from_raw_parts_mut(table_ptr, table_index_size()) };
+
+        for (index, entry) in table.iter_mut().enumerate() {
+            let entry_frame =
+                Address::new(frame.get().get() + (index * next_depth.align())).unwrap();
+            *entry = PageTableEntry::new(entry_frame, attributes);
+            entry.set_pat_index(pat_index, next_depth);
+        }
+
+        // The sub-entries map exactly what the huge entry did, so no TLB invalidation is required.
+        *self.entry =
+            PageTableEntry::new(table_frame, TableEntryFlags::PTE | TableEntryFlags::USER);
+
+        Ok(())
+    }
+
+    /// Passes the entry at `to_depth` (or the lowest depth) which maps `page` to `with_fn`,
+    /// splitting any huge entries along the way.
     pub fn with_entry_mut<T>(
         &mut self,
         page: Address<Page>,
@@ -389,7 +613,12 @@ impl<'a> PageTable<'a, Mut> {
     ) -> Result<T> {
         if self.depth() == to_depth.unwrap_or(TableDepth::min()) {
             Ok(with_fn(self.entry))
-        } else if !self.is_huge() {
+        } else {
+            if self.is_huge() {
+                self.split()?;
+            }
+
+            let memory = self.memory;
             let next_depth = self.depth().next_checked().unwrap();
             let entry_index = self.depth().index_of(page.get()).unwrap();
             let sub_entry = self.entries_mut().get_mut(entry_index).unwrap();
@@ -398,19 +627,17 @@ impl<'a> PageTable<'a, Mut> {
                 // Safety: Since the state of the page tables can not be fully modelled or controlled within the kernel itself,
                 //          we can't be 100% certain this is safe. However, in the case that it isn't, there's a near-certain
                 //          chance that the entire kernel will explode shortly after reading bad data like this as a page table.
-                (unsafe { PageTable::<Mut>::new(next_depth, sub_entry) })
+                (unsafe { PageTable::<Mut, M>::new(next_depth, sub_entry, memory) })
                     .with_entry_mut(page, to_depth, with_fn)
             } else {
                 Err(Error::NotMapped { addr: page.get() })
             }
-        } else {
-            Err(Error::HugePage)
         }
     }
 
     /// Attempts to get a mutable reference to the page table that lies in the given entry index's frame, or
-    /// creates the sub page table if it doesn't exist. This function returns `None` if it was unable to allocate
-    /// a frame for the requested page table.
+    /// creates the sub page table if it doesn't exist (splitting any huge entries along the way). This function
+    /// returns `None` if it was unable to allocate a frame for the requested page table.
     pub fn with_entry_create<T>(
         &mut self,
         page: Address<Page>,
@@ -419,8 +646,10 @@ impl<'a> PageTable<'a, Mut> {
     ) -> Result<T> {
         if self.depth() == to_depth {
             Ok(with_fn(self.entry))
-        } else if !self.is_huge() {
-            if !self.is_present() {
+        } else {
+            if self.is_huge() {
+                self.split()?;
+            } else if !self.is_present() {
                 debug_assert!(
                     self.get_frame() == Address::default(),
                     "page table entry is non-present, but has a present frame address: {:?} {:?}",
@@ -437,7 +666,7 @@ impl<'a> PageTable<'a, Mut> {
 
                 // Set the entry frame and set attributes to make a valid PTE.
                 *self.entry = PageTableEntry::new(
-                    PhysicalMemoryManager::next_frame().map_err(|_| Error::AllocError)?,
+                    self.memory.allocate_table().ok_or(Error::AllocError)?,
                     flags,
                 );
 
@@ -445,14 +674,13 @@ impl<'a> PageTable<'a, Mut> {
                 self.entries_mut().fill(PageTableEntry::empty());
             }
 
+            let memory = self.memory;
             let next_depth = self.depth().next_checked().unwrap();
             let entry_index = self.depth().index_of(page.get()).unwrap();
             let sub_entry = self.entries_mut().get_mut(entry_index).unwrap();
             // Safety: If the page table entry is present, then it's a valid entry, all bits accounted.
-            (unsafe { PageTable::<Mut>::new(next_depth, sub_entry) })
+            (unsafe { PageTable::<Mut, M>::new(next_depth, sub_entry, memory) })
                 .with_entry_create(page, to_depth, with_fn)
-        } else {
-            Err(Error::HugePage)
         }
     }
 }
@@ -0,0 +1,489 @@
+use crate::{
+    CacheMode, Error, FlagsModify, PageTable, PageTableEntry, PhysicalMemory, Result, TableDepth,
+    TableEntryFlags,
+};
+use core::num::NonZeroUsize;
+use libkernel::mem::{Mut, Ref};
+use libsys::{Address, Frame, Page, page_size, table_index_size};
+
+pub struct Mapper<M: PhysicalMemory> {
+    depth: TableDepth,
+    root_frame: Address<Frame>,
+    entry: PageTableEntry,
+    memory: M,
+}
+
+impl<M: PhysicalMemory> Mapper<M> {
+    /// Attempts to construct a new page manager. Returns `None` if `memory` could not provide a root frame.
+    pub fn new(depth: TableDepth, memory: M) -> Option<Self> {
+        let root_frame = memory.allocate_table()?;
+
+        // Safety: The memory provides a pointer to the whole of the root frame.
+        unsafe {
+            core::ptr::write_bytes(memory.frame_ptr(root_frame).as_ptr(), 0u8, page_size());
+        }
+
+        Some(Self {
+            depth,
+            root_frame,
+            entry: PageTableEntry::new(root_frame, TableEntryFlags::PRESENT),
+            memory,
+        })
+    }
+
+    /// Safety
+    ///
+    /// - The root frame must point to a valid top-level page table, within `memory`.
+    /// - There must only exist one copy of provided page table tree at any time.
+    pub unsafe fn new_unsafe(depth: TableDepth, root_frame: Address<Frame>, memory: M) -> Self {
+        Self {
+            depth,
+            root_frame,
+            entry: PageTableEntry::new(root_frame, TableEntryFlags::PRESENT),
+            memory,
+        }
+    }
+
+    /// Allocates a page table for every empty root entry in the higher half. The kernel's root entries then
+    /// never change, so they can be shared by every address space.
+    ///
+    /// # Remark
+    ///
+    /// The root table has the same number of entries with both 4- and 5-level paging, so the higher half
+    /// always begins at its middle entry.
+    pub fn preallocate_higher_half(&mut self) -> Result<()> {
+        let mut root_table = self.root_table_mut();
+        let root_entries = root_table.entries_mut();
+        let higher_half = (root_entries.len() / 2)..;
+
+        for entry in root_entries[higher_half]
+            .iter_mut()
+            .filter(|entry| !entry.is_present())
+        {
+            let table_frame = self.memory.allocate_table().ok_or(Error::AllocError)?;
+
+            // Safety: The memory provides a pointer to the whole of the table frame.
+            unsafe {
+                core::ptr::write_bytes(
+                    self.memory.frame_ptr(table_frame).as_ptr(),
+                    0u8,
+                    page_size(),
+                );
+            }
+
+            *entry = PageTableEntry::new(table_frame, TableEntryFlags::PTE | TableEntryFlags::USER);
+        }
+
+        Ok(())
+    }
+
+    pub const fn root_table(&self) -> PageTable<Ref, M> {
+        // Safety: `Self` requires that the entry be valid.
+        unsafe { PageTable::<Ref, M>::new(self.depth, &self.entry, &self.memory) }
+    }
+
+    fn root_table_mut(&mut self) -> PageTable<Mut, M> {
+        // Safety: `Self` requires that the entry be valid.
+        unsafe { PageTable::<Mut, M>::new(self.depth, &mut self.entry, &self.memory) }
+    }
+
+    /* MAP / UNMAP */
+
+    /// Maps the specified page to the specified frame index, with the given memory type.
+    pub fn map(
+        &mut self,
+        page: Address<Page>,
+        depth: TableDepth,
+        frame: Address<Frame>,
+        lock_frame: bool,
+        attributes: TableEntryFlags,
+        cache_mode: CacheMode,
+    ) -> Result<()> {
+        if lock_frame {
+            self.memory.lock_frame(frame)?;
+        }
+
+        // If acquisition of the frame is successful, attempt to map the page to the frame index.
+        let was_present = self
+            .root_table_mut()
+            // Safety: Frame does not contain any data.
+            .with_entry_create(page, depth, |entry| {
+                if depth > TableDepth::min() {
+                    debug_assert!(
+                        attributes.contains(TableEntryFlags::HUGE),
+                        "attributes missing huge bit for huge mapping"
+                    );
+                }
+
+                let was_present = entry.is_present();
+                *entry = PageTableEntry::new(frame, attributes);
+                // Safety: Caller is required to ensure the frame isn't mapped with a conflicting memory type.
+                unsafe { entry.set_cache_mode(cache_mode, depth) };
+
+                was_present
+            })?;
+
+        // Non-present entries are never cached, so only a replaced mapping needs to be invalidated.
+        if was_present {
+            self.invalidate(page, depth.align() / page_size());
+        }
+
+        Ok(())
+    }
+
+    /// Unmaps the given page, optionally freeing the frame the page points to (every frame of it, if the
+    /// page is huge).
+    ///
+    /// Safety
+    ///
+    /// Caller must ensure calling this function does not cause memory corruption.
+    pub unsafe fn unmap(
+        &mut self,
+        page: Address<Page>,
+        to_depth: Option<TableDepth>,
+        free_frame: bool,
+    ) -> Result<()> {
+        let depth = to_depth.unwrap_or(TableDepth::min());
+        let frame = self
+            .root_table_mut()
+            .with_entry_mut(page, to_depth, |entry| {
+                // Safety: We've got an explicit directive from the caller to unmap this page, so the caller must ensure that's a valid operation.
+                unsafe { entry.set_attributes(TableEntryFlags::PRESENT, FlagsModify::Remove) };
+
+                let frame = entry.get_leaf_frame(depth);
+                // Safety: See above.
+                unsafe { entry.set_frame(Address::new_truncate(0)) };
+
+                frame
+            })?;
+
+        let frame_count = depth.align() / page_size();
+
+        // Invalidate the page in every TLB, before its frames can be reused.
+        self.invalidate(page, frame_count);
+
+        if free_frame {
+            (frame.index()..(frame.index() + frame_count))
+                .filter_map(Address::from_index)
+                .for_each(|frame| self.memory.free_frame(frame));
+        }
+
+        Ok(())
+    }
+
+    /// Unmaps the `page_count` pages starting at `page` (which must all be mapped), optionally freeing
+    /// their frames. Only a single invalidation is issued for the whole range.
+    ///
+    /// Safety
+    ///
+    /// Caller must ensure calling this function does not cause memory corruption.
+    pub unsafe fn unmap_range(
+        &mut self,
+        page: Address<Page>,
+        page_count: usize,
+        free_frames: bool,
+    ) -> Result<()> {
+        let pages = (0..page_count)
+            .map(|offset| Address::new(page.get().get() + (offset * page_size())).unwrap());
+
+        // Entries keep their frame until the invalidation completes, so the frames can be freed afterwards
+        // without having to be collected anywhere.
+        let mut unmapped_count = 0;
+        let result = pages.clone().try_for_each(|page| {
+            let was_present = self.root_table_mut().with_entry_mut(page, None, |entry| {
+                let was_present = entry.is_present();
+                // Safety: Caller is required to ensure unmapping the pages is valid.
+                unsafe {
+                    entry.set_attributes(TableEntryFlags::PRESENT, FlagsModify::Remove);
+                }
+
+                was_present
+            })?;
+
+            if was_present {
+                unmapped_count += 1;
+                Ok(())
+            } else {
+                Err(Error::NotMapped { addr: page.get() })
+            }
+        });
+
+        // Invalidate the pages in every TLB, before their frames can be reused.
+        self.invalidate(page, page_count);
+
+        for page in pages.take(unmapped_count) {
+            let frame = self
+                .root_table_mut()
+                .with_entry_mut(page, None, |entry| {
+                    let frame = entry.get_frame();
+                    *entry = PageTableEntry::empty();
+
+                    frame
+                })
+                .unwrap();
+
+            if free_frames {
+                self.memory.free_frame(frame);
+            }
+        }
+
+        result
+    }
+
+    pub fn auto_map(
+        &mut self,
+        page: Address<Page>,
+        kind: M::FrameKind,
+        flags: TableEntryFlags,
+        cache_mode: CacheMode,
+    ) -> Result<()> {
+        let frame = self
+            .memory
+            .allocate_frames(NonZeroUsize::MIN, kind)
+            .ok_or(Error::AllocError)?;
+
+        self.map(page, TableDepth::min(), frame, false, flags, cache_mode)
+    }
+
+    /// Selects the depth of the largest leaf entry which can map `page` to `frame`, given both
+    /// are aligned to it, it's no larger than `len` bytes, and its slot isn't already in use.
+    fn leaf_depth_for(&self, page: Address<Page>, frame: Address<Frame>, len: usize) -> TableDepth {
+        [
+            TableDepth::new(2).filter(|_| self.memory.has_1gib_pages()),
+            TableDepth::new(1),
+        ]
+        .into_iter()
+        .flatten()
+        .find(|&depth| {
+            (page.get().get() % depth.align()) == 0
+                && (frame.get().get() % depth.align()) == 0
+                && len >= depth.align()
+                // Don't replace an existing table (or mapping) with a huge entry.
+                && matches!(
+                    self.root_table()
+                        .with_entry(page, Some(depth), |entry| entry.is_present()),
+                    Ok(false) | Err(Error::NotMapped { .. })
+                )
+        })
+        .unwrap_or(TableDepth::min())
+    }
+
+    /// Maps the `page_count` pages starting at `page` to the physically contiguous frames starting
+    /// at `frame`, using the largest leaf entries the alignment of both addresses allows.
+    pub fn map_range(
+        &mut self,
+        page: Address<Page>,
+        frame: Address<Frame>,
+        page_count: usize,
+        attributes: TableEntryFlags,
+        cache_mode: CacheMode,
+    ) -> Result<()> {
+        let len = page_count * page_size();
+        let mut offset = 0;
+
+        while offset < len {
+            let offset_page = Address::new(page.get().get() + offset).unwrap();
+            let offset_frame = Address::new(frame.get().get() + offset).unwrap();
+
+            let depth = self.leaf_depth_for(offset_page, offset_frame, len - offset);
+            let attributes = if depth.is_min() {
+                attributes
+            } else {
+                attributes | TableEntryFlags::HUGE
+            };
+
+            self.map(
+                offset_page,
+                depth,
+                offset_frame,
+                false,
+                attributes,
+                cache_mode,
+            )?;
+
+            offset += depth.align();
+        }
+
+        Ok(())
+    }
+
+    /// Maps the `page_count` pages starting at `page` to newly allocated frames. Where the pages are
+    /// suitably aligned, physically contiguous frames are allocated, so huge leaf entries can be used.
+    pub fn auto_map_range(
+        &mut self,
+        page: Address<Page>,
+        page_count: usize,
+        kind: M::FrameKind,
+        attributes: TableEntryFlags,
+        cache_mode: CacheMode,
+    ) -> Result<()> {
+        let len = page_count * page_size();
+        let mut offset = 0;
+
+        while offset < len {
+            let offset_page = Address::new(page.get().get() + offset).unwrap();
+
+            // Frames are allocated aligned to the leaf size, so only the page's alignment matters here.
+            let depth = self.leaf_depth_for(offset_page, Address::default(), len - offset);
+            let frame_count = depth.align() / page_size();
+            let huge_frames = (!depth.is_min())
+                .then(|| {
+                    self.memory
+                        .allocate_frames(NonZeroUsize::new(frame_count).unwrap(), kind)
+                })
+                .flatten();
+
+            if let Some(frames) = huge_frames {
+                let huge_attributes = attributes | TableEntryFlags::HUGE;
+                if let Err(error) = self.map(
+                    offset_page,
+                    depth,
+                    frames,
+                    false,
+                    huge_attributes,
+                    cache_mode,
+                ) {
+                    (frames.index()..(frames.index() + frame_count))
+                        .map(Address::from_index)
+                        .map(Option::unwrap)
+                        .for_each(|frame| self.memory.free_frame(frame));
+
+                    return Err(error);
+                }
+
+                offset += depth.align();
+            } else {
+                // Either the pages aren't suitably aligned, or memory is too fragmented for a huge frame.
+                self.auto_map(offset_page, kind, attributes, cache_mode)?;
+
+                offset += page_size();
+            }
+        }
+
+        Ok(())
+    }
+
+    /* STATE QUERYING */
+
+    pub fn is_mapped(&self, page: Address<Page>, depth: Option<TableDepth>) -> bool {
+        match depth {
+            Some(depth) => self
+                .root_table()
+                .with_entry(page, Some(depth), |_| ())
+                .is_ok(),
+            None => self.root_table().leaf_entry(page).is_ok(),
+        }
+    }
+
+    pub fn is_mapped_to(&self, page: Address<Page>, frame: Address<Frame>) -> bool {
+        self.get_mapped_to(page) == Some(frame)
+    }
+
+    /// Returns the frame `page` is mapped to (which may be part of a huge frame).
+    pub fn get_mapped_to(&self, page: Address<Page>) -> Option<Address<Frame>> {
+        let (entry, depth) = self.root_table().leaf_entry(page).ok()?;
+        let leaf_offset = page.get().get() & (depth.align() - 1);
+
+        Address::new(entry.get_leaf_frame(depth).get().get() + leaf_offset)
+    }
+
+    /* STATE CHANGING */
+
+    pub fn get_page_attributes(&self, page: Address<Page>) -> Option<TableEntryFlags> {
+        self.root_table()
+            .leaf_entry(page)
+            .ok()
+            .map(|(entry, _)| entry.get_attributes())
+    }
+
+    /// Sets the attributes of the page, leaving its memory type unchanged.
+    pub unsafe fn set_page_attributes(
+        &mut self,
+        page: Address<Page>,
+        depth: Option<TableDepth>,
+        attributes: TableEntryFlags,
+        modify_mode: FlagsModify,
+    ) -> Result<()> {
+        let depth = depth.unwrap_or(TableDepth::min());
+        self.root_table_mut()
+            .with_entry_mut(page, Some(depth), |entry| {
+                // Safety: Caller is required to ensure changing the attributes is valid.
+                unsafe { set_entry_attributes(entry, depth, attributes, modify_mode) };
+            })?;
+
+        self.invalidate(page, 1);
+
+        Ok(())
+    }
+
+    /// Sets the attributes of the `page_count` pages starting at `page` (leaving their memory types
+    /// unchanged), invalidating them together.
+    pub unsafe fn set_range_attributes(
+        &mut self,
+        page: Address<Page>,
+        page_count: usize,
+        attributes: TableEntryFlags,
+        modify_mode: FlagsModify,
+    ) -> Result<()> {
+        let result = (0..page_count)
+            .map(|offset| Address::new_truncate(page.get().get() + (offset * page_size())))
+            .try_for_each(|offset_page| {
+                self.root_table_mut()
+                    .with_entry_mut(offset_page, None, |entry| {
+                        // Safety: Caller is required to ensure changing the attributes is valid.
+                        unsafe {
+                            set_entry_attributes(entry, TableDepth::min(), attributes, modify_mode);
+                        }
+                    })
+            });
+
+        // Pages before an error have already been modified, so they must be invalidated regardless.
+        self.invalidate(page, page_count);
+
+        result
+    }
+
+    /// Invalidates the `page_count` pages starting at `page` wherever this address space may be loaded.
+    fn invalidate(&self, page: Address<Page>, page_count: usize) {
+        self.memory.invalidate(self.root_frame, page, page_count);
+    }
+
+    pub const fn root_frame(&self) -> Address<Frame> {
+        self.root_frame
+    }
+
+    pub const fn memory(&self) -> &M {
+        &self.memory
+    }
+
+    pub fn view_page_table(&self) -> &[PageTableEntry; table_index_size()] {
+        let table_ptr = self.memory.frame_ptr(self.root_frame).cast().as_ptr();
+        // Safety: Root frame is guaranteed to be valid for PTEs for the length of the table index size.
+        let table = unsafe { core::slice::from_raw_parts(table_ptr, table_index_size()) };
+        // Safety: Table was created to match the size required by return type.
+        unsafe { table.try_into().unwrap_unchecked() }
+    }
+}
+
+/// Sets the attributes of the leaf `entry` at `depth`, preserving its memory type (whose bits overlap
+/// the attributes).
+///
+/// ## Safety
+///
+/// Caller must ensure changing the attributes does not cause memory corruption.
+unsafe fn set_entry_attributes(
+    entry: &mut PageTableEntry,
+    depth: TableDepth,
+    attributes: TableEntryFlags,
+    modify_mode: FlagsModify,
+) {
+    let cache_mode = entry.cache_mode(depth);
+
+    // Safety: Caller is required to ensure changing the attributes is valid.
+    unsafe { entry.set_attributes(attributes, modify_mode) };
+
+    if let Some(cache_mode) = cache_mode {
+        // Safety: The memory type is unchanged.
+        unsafe { entry.set_cache_mode(cache_mode, depth) };
+    }
+}
@@ -0,0 +1,471 @@
+use crate::{
+    CacheMode, Error, FlagsModify, Mapper, PhysicalMemory, Result, TableDepth, TableEntryFlags,
+    walker::Walker,
+};
+use core::{
+    cell::{Cell, RefCell},
+    num::NonZeroUsize,
+    ops::ControlFlow,
+    ptr::NonNull,
+};
+use libsys::{Address, Frame, Page, page_size};
+use std::collections::BTreeMap;
+
+#[repr(C, align(4096))]
+struct FrameMemory([u8; 0x1000]);
+
+/// Heap-backed physical memory. Frames are handed out by a bump allocator, and only given backing
+/// memory once they're accessed (so mapping huge frames doesn't allocate gigabytes on the host).
+struct FakeMemory {
+    frames: RefCell<BTreeMap<usize, NonNull<FrameMemory>>>,
+    next_address: Cell<usize>,
+    has_1gib_pages: bool,
+
+    tables: Cell<usize>,
+    freed: RefCell<Vec<Address<Frame>>>,
+    locked: RefCell<Vec<Address<Frame>>>,
+    invalidated: RefCell<Vec<(Address<Page>, usize)>>,
+}
+
+impl FakeMemory {
+    fn new(has_1gib_pages: bool) -> Self {
+        Self {
+            frames: RefCell::new(BTreeMap::new()),
+            // Leave the low frames free, so tests can map fixed frames there.
+            next_address: Cell::new(1 << 40),
+            has_1gib_pages,
+
+            tables: Cell::new(0),
+            freed: RefCell::new(Vec::new()),
+            locked: RefCell::new(Vec::new()),
+            invalidated: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn bump(&self, count: usize) -> Address<Frame> {
+        let align = count.next_power_of_two() * page_size();
+        let address = self.next_address.get().next_multiple_of(align);
+        self.next_address.set(address + (count * page_size()));
+
+        Address::new(address).unwrap()
+    }
+}
+
+impl Drop for FakeMemory {
+    fn drop(&mut self) {
+        for &memory in self.frames.get_mut().values() {
+            // Safety: Frame memory is only ever created by `Box::into_raw`.
+            drop(unsafe { Box::from_raw(memory.as_ptr()) });
+        }
+    }
+}
+
+// Safety: Frame memory is leaked until the memory is dropped, so it never moves, and the bump allocator
+//         never hands out a frame twice.
+unsafe impl PhysicalMemory for FakeMemory {
+    type FrameKind = ();
+
+    fn frame_ptr(&self, frame: Address<Frame>) -> NonNull<u8> {
+        // Frames are filled with garbage, so page tables which aren't cleared are caught.
+        let memory = *self
+            .frames
+            .borrow_mut()
+            .entry(frame.get().get())
+            .or_insert_with(|| {
+                NonNull::new(Box::into_raw(Box::new(FrameMemory([0xAA; 0x1000])))).unwrap()
+            });
+
+        memory.cast()
+    }
+
+    fn allocate_table(&self) -> Option<Address<Frame>> {
+        self.tables.set(self.tables.get() + 1);
+
+        Some(self.bump(1))
+    }
+
+    fn allocate_frames(&self, count: NonZeroUsize, (): Self::FrameKind) -> Option<Address<Frame>> {
+        Some(self.bump(count.get()))
+    }
+
+    fn free_frame(&self, frame: Address<Frame>) {
+        self.freed.borrow_mut().push(frame);
+    }
+
+    fn lock_frame(&self, frame: Address<Frame>) -> Result<()> {
+        self.locked.borrow_mut().push(frame);
+
+        Ok(())
+    }
+
+    fn invalidate(&self, _: Address<Frame>, page: Address<Page>, page_count: usize) {
+        self.invalidated.borrow_mut().push((page, page_count));
+    }
+
+    fn has_1gib_pages(&self) -> bool {
+        self.has_1gib_pages
+    }
+}
+
+fn new_mapper(has_1gib_pages: bool) -> Mapper<FakeMemory> {
+    Mapper::new(TableDepth::new(4).unwrap(), FakeMemory::new(has_1gib_pages)).unwrap()
+}
+
+/// Depth of the leaf entry which maps `page`, if any.
+fn leaf_depth(mapper: &Mapper<FakeMemory>, page: Address<Page>) -> Option<TableDepth> {
+    mapper
+        .root_table()
+        .leaf_entry(page)
+        .ok()
+        .map(|(_, depth)| depth)
+}
+
+fn page(address: usize) -> Address<Page> {
+    Address::new(address).unwrap()
+}
+
+fn frame(address: usize) -> Address<Frame> {
+    Address::new(address).unwrap()
+}
+
+#[test]
+fn map_unmap() {
+    let mut mapper = new_mapper(false);
+    let page = page(0x4000_0000);
+
+    mapper
+        .map(
+            page,
+            TableDepth::min(),
+            frame(0x5000),
+            true,
+            TableEntryFlags::RW,
+            CacheMode::WriteBack,
+        )
+        .unwrap();
+    assert!(mapper.is_mapped(page, None));
+    assert!(mapper.is_mapped_to(page, frame(0x5000)));
+    assert_eq!(mapper.get_page_attributes(page), Some(TableEntryFlags::RW));
+    assert_eq!(*mapper.memory().locked.borrow(), [frame(0x5000)]);
+    // Nothing was mapped before, so nothing can be cached.
+    assert!(mapper.memory().invalidated.borrow().is_empty());
+
+    mapper
+        .map(
+            page,
+            TableDepth::min(),
+            frame(0x6000),
+            false,
+            TableEntryFlags::RW,
+            CacheMode::WriteBack,
+        )
+        .unwrap();
+    assert!(mapper.is_mapped_to(page, frame(0x6000)));
+    assert_eq!(*mapper.memory().invalidated.borrow(), [(page, 1)]);
+
+    // Safety: The mapper is never loaded.
+    unsafe { mapper.unmap(page, None, true) }.unwrap();
+    assert!(!mapper.is_mapped(page, None));
+    assert_eq!(mapper.get_mapped_to(page), None);
+    assert_eq!(*mapper.memory().freed.borrow(), [frame(0x6000)]);
+}
+
+#[test]
+fn unmap_huge_page() {
+    let mut mapper = new_mapper(false);
+    let mib_depth = TableDepth::new(1).unwrap();
+    let page = page(mib_depth.align());
+    let frame_count = mib_depth.align() / page_size();
+
+    mapper
+        .map(
+            page,
+            mib_depth,
+            frame(mib_depth.align()),
+            false,
+            TableEntryFlags::RW | TableEntryFlags::HUGE,
+            CacheMode::WriteBack,
+        )
+        .unwrap();
+    assert_eq!(leaf_depth(&mapper, page), Some(mib_depth));
+
+    // Safety: The mapper is never loaded.
+    unsafe { mapper.unmap(page, Some(mib_depth), true) }.unwrap();
+    assert!(!mapper.is_mapped(page, None));
+
+    // Every frame of the huge frame is freed, not only the first.
+    let expected = (0..frame_count)
+        .map(|index| frame(mib_depth.align() + (index * page_size())))
+        .collect::<Vec<_>>();
+    assert_eq!(*mapper.memory().freed.borrow(), expected);
+    assert_eq!(*mapper.memory().invalidated.borrow(), [(page, frame_count)]);
+}
+
+#[test]
+fn map_range_huge_pages() {
+    let mut mapper = new_mapper(true);
+    let gib_depth = TableDepth::new(2).unwrap();
+    let mib_depth = TableDepth::new(1).unwrap();
+    let base = gib_depth.align();
+
+    // One 1 GiB page, one 2 MiB page, then one 4 KiB page.
+    let page_count = (gib_depth.align() + mib_depth.align() + page_size()) / page_size();
+    mapper
+        .map_range(
+            page(base),
+            frame(base),
+            page_count,
+            TableEntryFlags::RW,
+            CacheMode::WriteBack,
+        )
+        .unwrap();
+
+    let mib_page = page(base + gib_depth.align());
+    let last_page = page(mib_page.get().get() + mib_depth.align());
+    assert_eq!(leaf_depth(&mapper, page(base)), Some(gib_depth));
+    assert_eq!(leaf_depth(&mapper, mib_page), Some(mib_depth));
+    assert_eq!(leaf_depth(&mapper, last_page), Some(TableDepth::min()));
+    assert!(!mapper.is_mapped(page(last_page.get().get() + page_size()), None));
+
+    // Pages within a huge page map to the corresponding frame within the huge frame.
+    let inner_page = page(base + (3 * page_size()));
+    assert!(mapper.is_mapped_to(inner_page, frame(base + (3 * page_size()))));
+    assert!(mapper.is_mapped_to(last_page, frame(last_page.get().get())));
+}
+
+#[test]
+fn map_range_without_1gib_pages() {
+    let mut mapper = new_mapper(false);
+    let gib_depth = TableDepth::new(2).unwrap();
+    let mib_depth = TableDepth::new(1).unwrap();
+    let base = gib_depth.align();
+
+    mapper
+        .map_range(
+            page(base),
+            frame(base),
+            gib_depth.align() / page_size(),
+            TableEntryFlags::RW,
+            CacheMode::WriteBack,
+        )
+        .unwrap();
+
+    assert_eq!(leaf_depth(&mapper, page(base)), Some(mib_depth));
+    assert_eq!(
+        leaf_depth(&mapper, page(base + gib_depth.align() - page_size())),
+        Some(mib_depth)
+    );
+}
+
+#[test]
+fn auto_map_range_huge_frames() {
+    let mut mapper = new_mapper(false);
+    let mib_depth = TableDepth::new(1).unwrap();
+    let base = page(mib_depth.align());
+
+    mapper
+        .auto_map_range(
+            base,
+            mib_depth.align() / page_size(),
+            (),
+            TableEntryFlags::RW,
+            CacheMode::WriteBack,
+        )
+        .unwrap();
+
+    assert_eq!(leaf_depth(&mapper, base), Some(mib_depth));
+    let huge_frame = mapper.get_mapped_to(base).unwrap();
+    assert_eq!(huge_frame.get().get() % mib_depth.align(), 0);
+}
+
+#[test]
+fn huge_page_split() {
+    let mut mapper = new_mapper(false);
+    let huge_depth = TableDepth::new(1).unwrap();
+    let page_count = huge_depth.align() / page_size();
+    let base = page(huge_depth.align());
+    let base_frame = frame(huge_depth.align());
+
+    mapper
+        .map_range(
+            base,
+            base_frame,
+            page_count,
+            TableEntryFlags::RW,
+            CacheMode::WriteCombining,
+        )
+        .unwrap();
+    assert_eq!(leaf_depth(&mapper, base), Some(huge_depth));
+
+    // Changing the attributes of a single page splits the huge page around it.
+    let split_page = page(base.get().get() + page_size());
+    // Safety: The mapper is never loaded.
+    unsafe {
+        mapper.set_page_attributes(
+            split_page,
+            Some(TableDepth::min()),
+            TableEntryFlags::WRITABLE,
+            FlagsModify::Remove,
+        )
+    }
+    .unwrap();
+
+    assert_eq!(leaf_depth(&mapper, split_page), Some(TableDepth::min()));
+    assert!(
+        !mapper
+            .get_page_attributes(split_page)
+            .unwrap()
+            .contains(TableEntryFlags::WRITABLE)
+    );
+    assert!(
+        mapper
+            .get_page_attributes(base)
+            .unwrap()
+            .contains(TableEntryFlags::WRITABLE)
+    );
+    assert!(mapper.is_mapped_to(split_page, frame(base_frame.get().get() + page_size())));
+
+    // The memory type is carried over to the split entries, despite the PAT bit moving.
+    for page in [base, split_page] {
+        let (entry, depth) = mapper.root_table().leaf_entry(page).unwrap();
+        assert_eq!(entry.cache_mode(depth), Some(CacheMode::WriteCombining));
+    }
+}
+
+#[test]
+fn unmap_range_not_mapped() {
+    let mut mapper = new_mapper(false);
+    let base = page(0x4000_0000);
+
+    for index in 0..2 {
+        mapper
+            .map(
+                page(base.get().get() + (index * page_size())),
+                TableDepth::min(),
+                frame(0x5000 + (index * page_size())),
+                false,
+                TableEntryFlags::RW,
+                CacheMode::WriteBack,
+            )
+            .unwrap();
+    }
+
+    let missing_page = page(base.get().get() + (2 * page_size()));
+    // Safety: The mapper is never loaded.
+    let result = unsafe { mapper.unmap_range(base, 3, true) };
+    assert_eq!(
+        result,
+        Err(Error::NotMapped {
+            addr: missing_page.get()
+        })
+    );
+
+    // The pages before the missing one are still unmapped (and invalidated together).
+    assert!(!mapper.is_mapped(base, None));
+    assert_eq!(
+        *mapper.memory().freed.borrow(),
+        [frame(0x5000), frame(0x6000)]
+    );
+    assert_eq!(*mapper.memory().invalidated.borrow(), [(base, 3)]);
+}
+
+#[test]
+fn preallocate_higher_half() {
+    let mut mapper = new_mapper(false);
+    mapper.preallocate_higher_half().unwrap();
+
+    let root_table = mapper.view_page_table();
+    let (lower_half, higher_half) = root_table.split_at(root_table.len() / 2);
+    assert!(lower_half.iter().all(|entry| !entry.is_present()));
+    assert!(higher_half.iter().all(|entry| entry.is_present()));
+    // The root table, and one for each higher half entry.
+    assert_eq!(mapper.memory().tables.get(), 1 + higher_half.len());
+
+    // Preallocated tables are empty, rather than holding whatever was in the frame before.
+    let table_ptr = mapper
+        .memory()
+        .frame_ptr(higher_half[0].get_frame())
+        .cast::<crate::PageTableEntry>();
+    // Safety: The frame holds a page table.
+    let table = unsafe { core::slice::from_raw_parts(table_ptr.as_ptr(), root_table.len()) };
+    assert!(table.iter().all(|entry| !entry.is_present()));
+}
+
+#[test]
+fn walker_counts_absent_entries() {
+    let mut mapper = new_mapper(false);
+    mapper
+        .map(
+            page(0x4000_0000),
+            TableDepth::min(),
+            frame(0x5000),
+            false,
+            TableEntryFlags::RW,
+            CacheMode::WriteBack,
+        )
+        .unwrap();
+
+    let root_table = mapper.view_page_table();
+    // Safety: The table is the mapper's root table.
+    let walker = unsafe {
+        Walker::new(
+            root_table,
+            TableDepth::new(4).unwrap(),
+            TableDepth::new(2).unwrap(),
+            mapper.memory(),
+        )
+    }
+    .unwrap();
+
+    let (mut present, mut absent) = (0, 0);
+    let _ = walker.walk::<()>(|entry| {
+        match entry {
+            Some(_) => present += 1,
+            None => absent += 1,
+        }
+
+        ControlFlow::Continue(())
+    });
+
+    // Every 1 GiB entry of the address space is visited once, whether or not its tables exist.
+    assert_eq!(present, 1);
+    assert_eq!(present + absent, root_table.len() * root_table.len());
+}
+
+#[test]
+fn walker_visits_tables_after_entries() {
+    let mut mapper = new_mapper(false);
+    mapper
+        .map(
+            page(0x4000_0000),
+            TableDepth::min(),
+            frame(0x5000),
+            false,
+            TableEntryFlags::RW,
+            CacheMode::WriteBack,
+        )
+        .unwrap();
+
+    // Safety: The table is the mapper's root table.
+    let walker = unsafe {
+        Walker::new(
+            mapper.view_page_table(),
+            TableDepth::new(4).unwrap(),
+            TableDepth::min(),
+            mapper.memory(),
+        )
+    }
+    .unwrap();
+
+    let mut depths = Vec::new();
+    let _ = walker.walk_present::<()>(|entry, depth| {
+        if depth.is_min() {
+            assert_eq!(entry.get_frame(), frame(0x5000));
+        }
+
+        depths.push(depth.get());
+        ControlFlow::Continue(())
+    });
+
+    assert_eq!(depths, [0, 1, 2, 3]);
+}
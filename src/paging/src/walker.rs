@@ -0,0 +1,127 @@
+use core::ops::ControlFlow;
+
+use crate::{PageTableEntry, PhysicalMemory, TableDepth};
+use libsys::table_index_size;
+
+pub struct Walker<'a, M: PhysicalMemory> {
+    root_table: &'a [PageTableEntry],
+    root_depth: TableDepth,
+    target_depth: TableDepth,
+    memory: &'a M,
+}
+
+impl<'a, M: PhysicalMemory> Walker<'a, M> {
+    /// ## Safety
+    ///
+    /// The provided page table must me a valid root-level table, whose tables are within `memory`.
+    pub unsafe fn new(
+        table: &'a [PageTableEntry],
+        depth: TableDepth,
+        target_depth: TableDepth,
+        memory: &'a M,
+    ) -> Option<Self> {
+        (depth >= target_depth).then_some(Self {
+            root_table: table,
+            root_depth: depth,
+            target_depth,
+            memory,
+        })
+    }
+
+    /// Passes every entry at the target depth to `func`, in address order. Entries which aren't mapped
+    /// are passed as `None`, and huge entries are passed once for each target depth entry they span.
+    pub fn walk<E>(
+        &self,
+        mut func: impl FnMut(Option<&PageTableEntry>) -> ControlFlow<E>,
+    ) -> ControlFlow<E> {
+        debug_assert!(self.root_depth > self.target_depth);
+
+        // The entries of a table are one depth below the table itself.
+        self.walk_impl(
+            self.root_table,
+            self.root_depth.next(),
+            self.target_depth,
+            &mut func,
+        )
+    }
+
+    /// Passes every present entry (down to the target depth) to `func`, along with its depth. Entries
+    /// which point to a table are passed after every entry within that table, so `func` may free it.
+    pub fn walk_present<E>(
+        &self,
+        mut func: impl FnMut(&PageTableEntry, TableDepth) -> ControlFlow<E>,
+    ) -> ControlFlow<E> {
+        debug_assert!(self.root_depth > self.target_depth);
+
+        self.walk_present_impl(
+            self.root_table,
+            self.root_depth.next(),
+            self.target_depth,
+            &mut func,
+        )
+    }
+
+    /// ## Safety
+    ///
+    /// `entry` must be a present, non-huge entry, which points to a valid page table.
+    unsafe fn entry_table(&self, entry: &PageTableEntry) -> &'a [PageTableEntry] {
+        let table_ptr = self.memory.frame_ptr(entry.get_frame()).cast().as_ptr();
+
+        // Safety: Caller is required to ensure the entry points to a valid page table, which is within the memory.
+        unsafe { core::slice::from_raw_parts(table_ptr, table_index_size()) }
+    }
+
+    fn walk_present_impl<E>(
+        &self,
+        table: &[PageTableEntry],
+        cur_depth: TableDepth,
+        target_depth: TableDepth,
+        func: &mut impl FnMut(&PageTableEntry, TableDepth) -> ControlFlow<E>,
+    ) -> ControlFlow<E> {
+        for entry in table.iter().filter(|entry| entry.is_present()) {
+            if cur_depth > target_depth && !entry.is_huge() {
+                // Safety: Present non-huge entries above the lowest depth point to page tables.
+                let sub_table = unsafe { self.entry_table(entry) };
+                self.walk_present_impl(sub_table, cur_depth.next(), target_depth, func)?;
+            }
+
+            func(entry, cur_depth)?;
+        }
+
+        ControlFlow::Continue(())
+    }
+
+    fn walk_impl<E>(
+        &self,
+        table: &[PageTableEntry],
+        cur_depth: TableDepth,
+        target_depth: TableDepth,
+        func: &mut impl FnMut(Option<&PageTableEntry>) -> ControlFlow<E>,
+    ) -> ControlFlow<E> {
+        use core::cmp::Ordering;
+
+        match cur_depth.cmp(&target_depth) {
+            Ordering::Equal => table.iter().try_for_each(|entry| func(Some(entry)))?,
+
+            Ordering::Greater => {
+                for entry in table {
+                    if entry.is_present() && !entry.is_huge() {
+                        // Safety: Present non-huge entries above the lowest depth point to page tables.
+                        let table = unsafe { self.entry_table(entry) };
+
+                        self.walk_impl(table, cur_depth.next(), target_depth, func)?;
+                    } else {
+                        let (steps, _) = core::iter::Step::steps_between(&cur_depth, &target_depth);
+                        let iterations = table_index_size().pow(steps.try_into().unwrap());
+                        let entry = entry.is_present().then_some(entry);
+                        (0..iterations).try_for_each(|_| func(entry))?;
+                    }
+                }
+            }
+
+            Ordering::Less => unreachable!(),
+        }
+
+        core::ops::ControlFlow::Continue(())
+    }
+}
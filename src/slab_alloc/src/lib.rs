@@ -1,187 +1,702 @@
+//! Slab allocator for small objects, layered over an allocator of whole slabs (i.e. frames).
+//!
+//! Layouts are rounded up to one of the power-of-two size classes (from [`MIN_BLOCK_SIZE`] to
+//! [`MAX_BLOCK_SIZE`]), each of which carves slabs from the backing allocator into blocks of that size.
+//! Anything larger (or more strictly aligned) is passed straight through to the backing allocator.
+
 #![cfg_attr(not(test), no_std)]
-#![feature(
-    allocator_api,          // #32838 <https://github.com/rust-lang/rust/issues/32838>
-    // drain_filter,           // #43244 <https://github.com/rust-lang/rust/issues/43244>
-)]
 
 extern crate alloc;
 
 #[cfg(test)]
 mod tests;
 
-use alloc::{boxed::Box, vec::Vec};
-use bitvec::{prelude::BitArray, slice::BitSlice, vec::BitVec};
 use core::{
     alloc::{AllocError, Allocator, Layout},
+    cell::UnsafeCell,
     num::NonZeroUsize,
     ptr::NonNull,
-    sync::atomic::AtomicU8,
+    sync::atomic::{AtomicUsize, Ordering},
 };
 use spin::Mutex;
 
-fn check_valid_slab_size(slab_size: NonZeroUsize) {
+/// Size of the smallest size class, which is large enough to hold a free list link.
+pub const MIN_BLOCK_SIZE: usize = 8;
+/// Size of the largest size class. Larger layouts are allocated from the backing allocator.
+pub const MAX_BLOCK_SIZE: usize = 2048;
+
+/// Number of size classes.
+pub const CLASS_COUNT: usize =
+    (MAX_BLOCK_SIZE.trailing_zeros() - MIN_BLOCK_SIZE.trailing_zeros()) as usize + 1;
+
+/// Slabs are grown until they hold at least this many blocks, so the header's share of a slab (which is
+/// always a whole block) stays small for the larger size classes.
+const MIN_BLOCKS_PER_SLAB: usize = 8;
+
+/// Number of blocks each magazine holds.
+pub const MAGAZINE_CAPACITY: usize = 32;
+/// Number of blocks moved between a magazine and its size class at once, which leaves a refilled or
+/// flushed magazine half full (so the next operation in either direction needn't lock the class).
+const MAGAZINE_BATCH: usize = MAGAZINE_CAPACITY / 2;
+
+const fn check_valid_slab_size(slab_size: NonZeroUsize) {
     assert!(slab_size.is_power_of_two());
-    assert!(slab_size.get() >= (u8::BITS as usize));
+    assert!(slab_size.get() >= (MAX_BLOCK_SIZE * 2));
 }
 
-struct SlabManager<A: Allocator> {
-    ledger: BitVec<AtomicU8>,
-    slabs: Vec<Box<[u8], A>, A>,
-    remaining: usize,
-    slab_size: NonZeroUsize,
-    block_size: NonZeroUsize,
-    allocator: A,
+/// Index of the size class which `layout` is allocated from, if it's small enough to be slabbed.
+const fn class_index(layout: Layout) -> Option<usize> {
+    let mut block_size = layout.size();
+    if block_size < layout.align() {
+        block_size = layout.align();
+    }
+    if block_size < MIN_BLOCK_SIZE {
+        block_size = MIN_BLOCK_SIZE;
+    }
+
+    let block_size = block_size.next_power_of_two();
+    if block_size > MAX_BLOCK_SIZE {
+        None
+    } else {
+        Some((block_size.trailing_zeros() - MIN_BLOCK_SIZE.trailing_zeros()) as usize)
+    }
 }
 
-impl<A: Allocator + Clone> SlabManager<A> {
-    fn new_in(slab_size: NonZeroUsize, block_size: NonZeroUsize, allocator: A) -> Self {
+const fn class_block_size(class_index: usize) -> usize {
+    MIN_BLOCK_SIZE << class_index
+}
+
+/// Link stored within each free block of a slab.
+struct FreeBlock {
+    next: Option<NonNull<FreeBlock>>,
+}
+
+/// Stored at the start of each slab, within its first block(s).
+struct SlabHeader {
+    /// Neighbours within the size class's list of slabs which have free blocks.
+    prev: Option<NonNull<SlabHeader>>,
+    next: Option<NonNull<SlabHeader>>,
+
+    free_list: Option<NonNull<FreeBlock>>,
+    in_use: usize,
+}
+
+/// Geometry of the slabs of a size class.
+#[derive(Debug, Clone, Copy)]
+struct SlabGeometry {
+    block_size: usize,
+    slab_layout: Layout,
+}
+
+impl SlabGeometry {
+    const fn new(slab_size: NonZeroUsize, class_index: usize) -> Self {
+        let block_size = class_block_size(class_index);
+
+        let mut slab_size = slab_size.get();
+        if slab_size < (block_size * MIN_BLOCKS_PER_SLAB) {
+            slab_size = block_size * MIN_BLOCKS_PER_SLAB;
+        }
+
         Self {
-            ledger: BitVec::new(),
-            slabs: Vec::new_in(allocator.clone()),
-            remaining: 0,
-            slab_size,
             block_size,
-            allocator,
+            // Slabs are aligned to their size, so the slab of a block is found by aligning down.
+            slab_layout: match Layout::from_size_align(slab_size, slab_size) {
+                Ok(layout) => layout,
+                Err(_) => panic!("invalid slab size"),
+            },
+        }
+    }
+
+    /// Offset of the first block, which is aligned to the block size (and so to any layout of the class).
+    const fn first_block_offset(self) -> usize {
+        size_of::<SlabHeader>().next_multiple_of(self.block_size)
+    }
+
+    const fn blocks_per_slab(self) -> usize {
+        (self.slab_layout.size() - self.first_block_offset()) / self.block_size
+    }
+
+    fn slab_of(self, ptr: NonNull<u8>) -> NonNull<SlabHeader> {
+        ptr.map_addr(|addr| {
+            NonZeroUsize::new(addr.get() & !(self.slab_layout.align() - 1)).unwrap()
+        })
+        .cast()
+    }
+}
+
+/// Manages the slabs of a single size class.
+///
+/// Only slabs with free blocks are tracked; full slabs are found again (by aligning down the address of
+/// a block) once a block within them is freed.
+struct SlabManager {
+    partial: Option<NonNull<SlabHeader>>,
+    /// Number of slabs in the partial list which have no blocks in use.
+    empty_slabs: usize,
+}
+
+// Safety: The manager only references slabs it owns, which aren't tied to any thread.
+unsafe impl Send for SlabManager {}
+
+impl SlabManager {
+    const fn new() -> Self {
+        Self {
+            partial: None,
+            empty_slabs: 0,
+        }
+    }
+
+    /// ## Safety
+    ///
+    /// `slab` must be a valid slab, which isn't in the partial list.
+    unsafe fn push_partial(&mut self, mut slab: NonNull<SlabHeader>) {
+        // Safety: Caller is required to ensure the slab is valid.
+        let header = unsafe { slab.as_mut() };
+        header.prev = None;
+        header.next = self.partial;
+
+        if let Some(mut next) = self.partial {
+            // Safety: Slabs in the partial list are valid.
+            unsafe { next.as_mut() }.prev = Some(slab);
         }
+
+        self.partial = Some(slab);
     }
 
-    #[inline]
-    const fn len(&self) -> usize {
-        self.ledger.len()
+    /// ## Safety
+    ///
+    /// `slab` must be a valid slab, which is in the partial list.
+    unsafe fn unlink_partial(&mut self, mut slab: NonNull<SlabHeader>) {
+        // Safety: Caller is required to ensure the slab is valid.
+        let header = unsafe { slab.as_mut() };
+
+        match header.prev {
+            // Safety: Slabs in the partial list are valid.
+            Some(mut prev) => unsafe { prev.as_mut() }.next = header.next,
+            None => self.partial = header.next,
+        }
+
+        if let Some(mut next) = header.next {
+            // Safety: Slabs in the partial list are valid.
+            unsafe { next.as_mut() }.prev = header.prev;
+        }
+
+        header.prev = None;
+        header.next = None;
     }
 
-    #[inline]
-    const fn remaining(&self) -> usize {
-        self.remaining
+    /// Initializes the header & free list of the newly allocated `slab`, and adds it to the partial list.
+    ///
+    /// ## Safety
+    ///
+    /// `slab` must point to an allocation made with the geometry's slab layout.
+    unsafe fn add_slab(&mut self, slab: NonNull<u8>, geometry: SlabGeometry) {
+        let mut free_list = None;
+        // Link the blocks in reverse, so they're handed out in address order.
+        for block_index in (0..geometry.blocks_per_slab()).rev() {
+            let offset = geometry.first_block_offset() + (block_index * geometry.block_size);
+            // Safety: The block is within the slab.
+            let block = unsafe { slab.add(offset) }.cast::<FreeBlock>();
+            // Safety: The block is within the slab, and aligned to (at least) the link's alignment.
+            unsafe { block.write(FreeBlock { next: free_list }) };
+
+            free_list = Some(block);
+        }
+
+        let slab = slab.cast::<SlabHeader>();
+        // Safety: The header fits within the first block(s) of the slab.
+        unsafe {
+            slab.write(SlabHeader {
+                prev: None,
+                next: None,
+                free_list,
+                in_use: 0,
+            });
+        }
+
+        // Safety: The slab was just initialized.
+        unsafe { self.push_partial(slab) };
+        self.empty_slabs += 1;
     }
 
-    #[inline]
-    const fn is_empty(&self) -> bool {
-        self.remaining == 0
+    /// Takes a free block from the first partial slab, if there is one.
+    fn take_block(&mut self, geometry: SlabGeometry) -> Option<NonNull<u8>> {
+        let mut slab = self.partial?;
+        // Safety: Slabs in the partial list are valid.
+        let header = unsafe { slab.as_mut() };
+
+        // Partial slabs always have a free block.
+        let block = header.free_list.unwrap();
+        // Safety: Blocks in the free list hold a link.
+        header.free_list = unsafe { block.as_ref() }.next;
+
+        if header.in_use == 0 {
+            self.empty_slabs -= 1;
+        }
+        header.in_use += 1;
+
+        if header.in_use == geometry.blocks_per_slab() {
+            // Safety: The slab is in the partial list.
+            unsafe { self.unlink_partial(slab) };
+        }
+
+        Some(block.cast())
+    }
+
+    /// Returns `block` to its slab. If this leaves more than one slab empty, the now-empty slab is
+    /// unlinked and returned, so it can be freed to the backing allocator.
+    ///
+    /// ## Safety
+    ///
+    /// `block` must have been taken from a slab of this manager, and not already returned.
+    unsafe fn return_block(
+        &mut self,
+        block: NonNull<u8>,
+        geometry: SlabGeometry,
+    ) -> Option<NonNull<u8>> {
+        let mut slab = geometry.slab_of(block);
+        // Safety: Caller is required to ensure the block is within one of our slabs.
+        let header = unsafe { slab.as_mut() };
+
+        let was_full = header.free_list.is_none();
+        let block = block.cast::<FreeBlock>();
+        // Safety: The block is no longer in use, so its memory can hold a link.
+        unsafe {
+            block.write(FreeBlock {
+                next: header.free_list,
+            });
+        }
+        header.free_list = Some(block);
+        header.in_use -= 1;
+
+        if was_full {
+            // Safety: Full slabs aren't in the partial list.
+            unsafe { self.push_partial(slab) };
+        }
+
+        if header.in_use > 0 {
+            None
+        } else if self.empty_slabs == 0 {
+            // Keep a single empty slab around, so an allocation which is repeatedly made & freed doesn't
+            // allocate a new slab each time.
+            self.empty_slabs += 1;
+
+            None
+        } else {
+            // Safety: The slab is in the partial list.
+            unsafe { self.unlink_partial(slab) };
+
+            Some(slab.cast())
+        }
+    }
+}
+
+/// Cache of free blocks of a single size class.
+#[derive(Clone, Copy)]
+struct Magazine {
+    blocks: [Option<NonNull<u8>>; MAGAZINE_CAPACITY],
+    len: usize,
+}
+
+impl Magazine {
+    const fn new() -> Self {
+        Self {
+            blocks: [None; MAGAZINE_CAPACITY],
+            len: 0,
+        }
     }
 
-    #[inline]
     const fn is_full(&self) -> bool {
-        self.remaining == self.len()
+        self.len == MAGAZINE_CAPACITY
     }
 
-    #[inline]
-    const fn block_size(&self) -> usize {
-        self.block_size.get()
+    fn push(&mut self, block: NonNull<u8>) {
+        self.blocks[self.len] = Some(block);
+        self.len += 1;
     }
 
-    #[inline]
-    pub fn check_fits_layout(&self, layout: Layout) -> bool {
-        self.block_size() >= layout.align()
-            && self.block_size() == layout.size().next_power_of_two()
+    fn pop(&mut self) -> Option<NonNull<u8>> {
+        self.len = self.len.checked_sub(1)?;
+        self.blocks[self.len].take()
     }
+}
+
+/// Per-hardware-thread caches of free blocks, one for each size class, so most allocations & frees
+/// don't have to lock a size class. Blocks move between a magazine and its size class in batches.
+///
+/// Magazines are only ever accessed by the hardware thread which owns them (see
+/// [`SlabAllocator::allocate_local`]), so they're unsynchronized. They're never borrowed across a call
+/// into the backing allocator, so they may be used re-entrantly from within it.
+pub struct Magazines(UnsafeCell<[Magazine; CLASS_COUNT]>);
 
-    pub fn take_object(&mut self) -> Option<NonNull<[u8]>> {
-        let index = self.ledger.first_zero()?;
-        self.ledger.set(index, true);
-        self.remaining -= 1;
+// Safety: Magazines are only accessed through `SlabAllocator`'s local functions, whose callers are
+//         required to ensure they're only used by a single hardware thread.
+unsafe impl Send for Magazines {}
+// Safety: See above.
+unsafe impl Sync for Magazines {}
 
-        let bits_per_slab = self.len() / self.slabs.len();
-        let slab_index = index / bits_per_slab;
-        let slab_block_index = index % bits_per_slab;
-        let slab_block_offset = slab_block_index * self.block_size();
+impl Magazines {
+    pub const fn new() -> Self {
+        Self(UnsafeCell::new([Magazine::new(); CLASS_COUNT]))
+    }
 
-        let slab = self.slabs[slab_block_index]
-            .get_mut(slab_block_offset..(slab_block_offset + self.block_size()))
-            .unwrap();
+    /// Magazine of the size class with `class_index`.
+    fn get(&self, class_index: usize) -> *mut Magazine {
+        debug_assert!(class_index < CLASS_COUNT);
 
-        Some(NonNull::new(slab as *mut [u8]).unwrap())
+        self.0.get().cast::<Magazine>().wrapping_add(class_index)
     }
+}
 
-    pub unsafe fn return_object(&mut self, ptr: NonNull<u8>) {
-        // TODO
+impl Default for Magazines {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-impl<A: Allocator> core::fmt::Debug for Slab<A> {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        f.debug_struct("Slab")
-            .field("Block Size", &self.block_size())
-            .field("Is Empty", &self.is_empty())
-            .field("Size", &self.memory.len())
-            .finish()
+struct ClassCounters {
+    active_objects: AtomicUsize,
+    high_water: AtomicUsize,
+    slabs: AtomicUsize,
+    allocs: AtomicUsize,
+    frees: AtomicUsize,
+}
+
+impl ClassCounters {
+    const fn new() -> Self {
+        Self {
+            active_objects: AtomicUsize::new(0),
+            high_water: AtomicUsize::new(0),
+            slabs: AtomicUsize::new(0),
+            allocs: AtomicUsize::new(0),
+            frees: AtomicUsize::new(0),
+        }
+    }
+
+    fn record_alloc(&self) {
+        self.allocs.fetch_add(1, Ordering::Relaxed);
+        let active_objects = self.active_objects.fetch_add(1, Ordering::Relaxed) + 1;
+        self.high_water.fetch_max(active_objects, Ordering::Relaxed);
+    }
+
+    fn record_free(&self) {
+        self.frees.fetch_add(1, Ordering::Relaxed);
+        self.active_objects.fetch_sub(1, Ordering::Relaxed);
     }
 }
 
+/// Snapshot of the counters of a size class.
+///
+/// # Remark
+///
+/// The counters are updated without synchronization between them, so a snapshot taken while blocks
+/// are being allocated or freed may be momentarily inconsistent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClassStats {
+    pub block_size: usize,
+    pub slab_size: usize,
+    /// Blocks which are currently allocated (excluding those cached in magazines).
+    pub active_objects: usize,
+    /// Most blocks which have been allocated at once.
+    pub high_water: usize,
+    /// Slabs currently allocated from the backing allocator.
+    pub slabs: usize,
+    pub allocs: usize,
+    pub frees: usize,
+}
+
 pub struct SlabAllocator<A: Allocator> {
     slab_size: NonZeroUsize,
-    slabs: Mutex<Vec<Slab<A>, A>>,
+    managers: [Mutex<SlabManager>; CLASS_COUNT],
+    counters: [ClassCounters; CLASS_COUNT],
     allocator: A,
 }
 
 // Safety: Type does not use thread-specific logic.
-unsafe impl<A: Allocator> Send for SlabAllocator<A> {}
+unsafe impl<A: Allocator + Send> Send for SlabAllocator<A> {}
 // Safety: Type's mutable conversions are synchronized via `spin::Mutex`.
-unsafe impl<A: Allocator> Sync for SlabAllocator<A> {}
+unsafe impl<A: Allocator + Sync> Sync for SlabAllocator<A> {}
 
-impl<A: Allocator + Clone> SlabAllocator<A> {
-    #[inline]
-    pub fn new_in(slab_size: NonZeroUsize, allocator: A) -> Self {
+impl<A: Allocator> SlabAllocator<A> {
+    /// Creates a slab allocator which allocates slabs of (at least) `slab_size` bytes from `allocator`,
+    /// aligned to their size.
+    pub const fn new_in(slab_size: NonZeroUsize, allocator: A) -> Self {
         check_valid_slab_size(slab_size);
 
         Self {
             slab_size,
-            slabs: Mutex::new(Vec::new_in(allocator.clone())),
+            managers: [const { Mutex::new(SlabManager::new()) }; CLASS_COUNT],
+            counters: [const { ClassCounters::new() }; CLASS_COUNT],
             allocator,
         }
     }
 
-    fn max_slabbed_allocation_size(&self) -> usize {
-        self.slab_size.get() / (u8::BITS as usize)
+    /// Whether `layout` is allocated from a size class, rather than the backing allocator.
+    pub const fn fits(&self, layout: Layout) -> bool {
+        class_index(layout).is_some()
     }
-}
 
-unsafe impl<A: Allocator + Clone> Allocator for SlabAllocator<A> {
-    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        let allocation = {
-            if layout.size() > self.max_slabbed_allocation_size() {
-                self.allocator.allocate(layout)
-            } else {
-                let mut slabs = self.slabs.lock();
-
-                let mut item = slabs
-                    .iter_mut()
-                    // check not empty
-                    .filter(|slab| !slab.is_empty())
-                    // check size & align fit
-                    .filter(|slab| slab.check_fits_layout(layout))
-                    // try take & map to allocation result
-                    .find_map(|slab| slab.next_item());
-
-                if item.is_none() {
-                    slabs.push(Slab::new_in(self.slab_size, layout, self.allocator.clone()));
-                    let slab = slabs.last_mut().unwrap();
-                    let old_item = item.replace(slab.next_item().unwrap());
-
-                    assert!(old_item.is_none());
+    pub const fn backing_allocator(&self) -> &A {
+        &self.allocator
+    }
+
+    const fn geometry(&self, class_index: usize) -> SlabGeometry {
+        SlabGeometry::new(self.slab_size, class_index)
+    }
+
+    /// Takes up to `count` blocks from the size class into `batch`, allocating new slabs while none have a
+    /// free block. Only fails if not a single block could be taken.
+    ///
+    /// The class is unlocked while slabs are allocated, as the backing allocator may free blocks to
+    /// this allocator to make room (e.g. by reclaiming memory).
+    fn take_blocks(
+        &self,
+        class_index: usize,
+        batch: &mut Magazine,
+        count: usize,
+    ) -> Result<(), AllocError> {
+        let geometry = self.geometry(class_index);
+
+        loop {
+            {
+                let mut manager = self.managers[class_index].lock();
+                while batch.len < count {
+                    let Some(block) = manager.take_block(geometry) else {
+                        break;
+                    };
+
+                    batch.push(block);
+                }
+            }
+
+            if batch.len == count {
+                return Ok(());
+            }
+
+            match self.allocator.allocate(geometry.slab_layout) {
+                Ok(slab) => {
+                    self.counters[class_index]
+                        .slabs
+                        .fetch_add(1, Ordering::Relaxed);
+
+                    // Safety: The slab was just allocated with the class's slab layout.
+                    unsafe {
+                        self.managers[class_index]
+                            .lock()
+                            .add_slab(slab.cast(), geometry);
+                    }
+                }
+
+                Err(_) if batch.len > 0 => return Ok(()),
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Returns every block in `batch` to the size class, then frees the slabs this leaves empty to the
+    /// backing allocator, once the class is unlocked. Returns the number of bytes of slabs freed.
+    ///
+    /// ## Safety
+    ///
+    /// Every block in `batch` must have been taken from the size class, and not already returned.
+    unsafe fn return_blocks(&self, class_index: usize, batch: &mut Magazine) -> usize {
+        let geometry = self.geometry(class_index);
+        // Each block returned can leave (at most) one slab empty.
+        let mut empty_slabs = Magazine::new();
+
+        {
+            let mut manager = self.managers[class_index].lock();
+            while let Some(block) = batch.pop() {
+                // Safety: Caller is required to ensure the block was taken from this class.
+                if let Some(slab) = unsafe { manager.return_block(block, geometry) } {
+                    empty_slabs.push(slab);
+                }
+            }
+        }
+
+        let freed_bytes = empty_slabs.len * geometry.slab_layout.size();
+        while let Some(slab) = empty_slabs.pop() {
+            self.counters[class_index]
+                .slabs
+                .fetch_sub(1, Ordering::Relaxed);
+
+            // Safety: Slabs are allocated with the class's slab layout.
+            unsafe { self.allocator.deallocate(slab, geometry.slab_layout) };
+        }
+
+        freed_bytes
+    }
+
+    /// Allocates `layout` from the current hardware thread's `magazines`, only locking its size class
+    /// when the magazine has to be refilled.
+    ///
+    /// ## Safety
+    ///
+    /// `magazines` must only be used by the current hardware thread, and never by an interrupt handler
+    /// while they're already in use. They may be used re-entrantly by the backing allocator.
+    pub unsafe fn allocate_local(
+        &self,
+        magazines: &Magazines,
+        layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let Some(class_index) = class_index(layout) else {
+            return self.allocator.allocate(layout);
+        };
+
+        // Safety: Caller is required to ensure the magazines are only used by this hardware thread, and
+        //         the borrow ends before anything else can use them.
+        let block = match unsafe { (*magazines.get(class_index)).pop() } {
+            Some(block) => block,
+
+            None => {
+                // The magazine is refilled from a local batch, as the backing allocator may use it (e.g. to
+                // free blocks) while a slab is allocated.
+                let mut batch = Magazine::new();
+                self.take_blocks(class_index, &mut batch, MAGAZINE_BATCH)?;
+                let block = batch.pop().unwrap();
+
+                {
+                    // Safety: See above.
+                    let magazine = unsafe { &mut *magazines.get(class_index) };
+                    while !magazine.is_full() {
+                        let Some(block) = batch.pop() else {
+                            break;
+                        };
+
+                        magazine.push(block);
+                    }
                 }
 
-                item.ok_or(AllocError)
+                // The magazine may have been filled while the slab was allocated.
+                // Safety: The blocks were just taken from the class.
+                unsafe { self.return_blocks(class_index, &mut batch) };
+
+                block
             }
         };
 
-        allocation
+        self.counters[class_index].record_alloc();
+
+        Ok(NonNull::slice_from_raw_parts(
+            block,
+            self.geometry(class_index).block_size,
+        ))
     }
 
-    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
-        if layout.size() > self.max_slabbed_allocation_size() {
-            self.allocator.deallocate(ptr, layout);
-        } else {
-            let mut slabs = self.slabs.lock();
-            slabs.drain_filter(|slab| {
-                if slab.check_fits_layout(layout) && slab.owns_ptr(ptr.as_ptr()) {
-                    slab.return_item(ptr);
-
-                    slab.is_empty()
-                } else {
-                    false
+    /// Frees `ptr` to the current hardware thread's `magazines`, only locking its size class when the
+    /// magazine has to be flushed.
+    ///
+    /// ## Safety
+    ///
+    /// - `ptr` must have been allocated by this allocator with `layout`, and not already freed.
+    /// - `magazines` must only be used by the current hardware thread, and never by an interrupt handler
+    ///   while they're already in use.
+    pub unsafe fn deallocate_local(&self, magazines: &Magazines, ptr: NonNull<u8>, layout: Layout) {
+        let Some(class_index) = class_index(layout) else {
+            // Safety: Caller is required to ensure the pointer was allocated with this layout, which
+            //         the backing allocator is used for.
+            unsafe { self.allocator.deallocate(ptr, layout) };
+
+            return;
+        };
+
+        self.counters[class_index].record_free();
+
+        // Blocks are flushed from the magazine to a local batch, so it isn't borrowed while they're
+        // returned to the class (which may free a slab to the backing allocator).
+        let mut batch = Magazine::new();
+
+        {
+            // Safety: Caller is required to ensure the magazines are only used by this hardware thread,
+            //         and the borrow ends before anything else can use them.
+            let magazine = unsafe { &mut *magazines.get(class_index) };
+
+            if magazine.is_full() {
+                for _ in 0..MAGAZINE_BATCH {
+                    batch.push(magazine.pop().unwrap());
                 }
-            });
+            }
+
+            magazine.push(ptr);
         }
+
+        // Safety: Blocks in magazines were taken from their class, and are free.
+        unsafe { self.return_blocks(class_index, &mut batch) };
+    }
+
+    /// Returns every block cached in `magazines` to its size class, returning the number of bytes of
+    /// slabs this leaves empty, which are freed to the backing allocator.
+    ///
+    /// ## Safety
+    ///
+    /// `magazines` must only be used by the current hardware thread, and never by an interrupt handler
+    /// while they're already in use.
+    pub unsafe fn flush_local(&self, magazines: &Magazines) -> usize {
+        (0..CLASS_COUNT)
+            .map(|class_index| {
+                // Safety: Caller is required to ensure the magazines are only used by this hardware
+                //         thread, and the borrow ends before anything else can use them.
+                let mut batch = core::mem::replace(
+                    unsafe { &mut *magazines.get(class_index) },
+                    Magazine::new(),
+                );
+
+                // Safety: Blocks in magazines were taken from their class, and are free.
+                unsafe { self.return_blocks(class_index, &mut batch) }
+            })
+            .sum()
+    }
+
+    /// Snapshot of the counters of each size class, from the smallest to the largest.
+    pub fn stats(&self) -> [ClassStats; CLASS_COUNT] {
+        core::array::from_fn(|class_index| {
+            let counters = &self.counters[class_index];
+            let geometry = self.geometry(class_index);
+
+            ClassStats {
+                block_size: geometry.block_size,
+                slab_size: geometry.slab_layout.size(),
+                active_objects: counters.active_objects.load(Ordering::Relaxed),
+                high_water: counters.high_water.load(Ordering::Relaxed),
+                slabs: counters.slabs.load(Ordering::Relaxed),
+                allocs: counters.allocs.load(Ordering::Relaxed),
+                frees: counters.frees.load(Ordering::Relaxed),
+            }
+        })
+    }
+}
+
+// Safety: Blocks are only handed out once until they're freed, and are aligned to the block size.
+unsafe impl<A: Allocator> Allocator for SlabAllocator<A> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let Some(class_index) = class_index(layout) else {
+            return self.allocator.allocate(layout);
+        };
+
+        let mut batch = Magazine::new();
+        self.take_blocks(class_index, &mut batch, 1)?;
+        let block = batch.pop().unwrap();
+        self.counters[class_index].record_alloc();
+
+        Ok(NonNull::slice_from_raw_parts(
+            block,
+            self.geometry(class_index).block_size,
+        ))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        let Some(class_index) = class_index(layout) else {
+            // Safety: Caller is required to ensure the pointer was allocated with this layout, which
+            //         the backing allocator is used for.
+            unsafe { self.allocator.deallocate(ptr, layout) };
+
+            return;
+        };
+
+        self.counters[class_index].record_free();
+
+        let mut batch = Magazine::new();
+        batch.push(ptr);
+        // Safety: Caller is required to ensure the block was allocated with this layout, and so from this class.
+        unsafe { self.return_blocks(class_index, &mut batch) };
     }
 }
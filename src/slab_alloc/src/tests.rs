@@ -1,10 +1,101 @@
-use alloc::{boxed::Box, vec::Vec};
-use core::{mem::MaybeUninit, num::NonZeroUsize};
+use crate::{MAGAZINE_CAPACITY, MAX_BLOCK_SIZE, Magazines, SlabAllocator};
+use alloc::{alloc::Global, vec::Vec};
+use core::{
+    alloc::{AllocError, Allocator, Layout},
+    mem::MaybeUninit,
+    num::NonZeroUsize,
+    ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use std::sync::{Mutex, OnceLock};
+
+const SLAB_SIZE: NonZeroUsize = NonZeroUsize::new(0x1000).unwrap();
+
+/// Backing allocator which counts its live allocations.
+#[derive(Default)]
+struct CountingAllocator {
+    live: AtomicUsize,
+}
+
+// Safety: Allocations are forwarded to the global allocator.
+unsafe impl Allocator for CountingAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let allocation = Global.allocate(layout)?;
+        self.live.fetch_add(1, Ordering::Relaxed);
+
+        Ok(allocation)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.live.fetch_sub(1, Ordering::Relaxed);
+        // Safety: Caller is required to ensure the pointer was allocated with this layout.
+        unsafe { Global.deallocate(ptr, layout) };
+    }
+}
+
+/// Backing allocator which frees a block to the slab allocator it backs when a slab is allocated, as an
+/// allocator which reclaims memory might. Checks no size class is locked whenever it's used.
+#[derive(Default)]
+struct ReclaimingAllocator {
+    slab_allocator: OnceLock<&'static SlabAllocator<ReclaimingAllocator>>,
+    /// Magazines to free the block to, if it's freed locally.
+    magazines: OnceLock<&'static Magazines>,
+    reclaimable: Mutex<Option<(NonNull<u8>, Layout)>>,
+    freed: AtomicUsize,
+}
+
+impl ReclaimingAllocator {
+    fn assert_unlocked(&self) {
+        if let Some(slab_allocator) = self.slab_allocator.get() {
+            assert!(
+                slab_allocator
+                    .managers
+                    .iter()
+                    .all(|manager| !manager.is_locked())
+            );
+        }
+    }
+}
+
+// Safety: The reclaimable block is only accessed with its lock held.
+unsafe impl Send for ReclaimingAllocator {}
+// Safety: See above.
+unsafe impl Sync for ReclaimingAllocator {}
+
+// Safety: Allocations are forwarded to the global allocator.
+unsafe impl Allocator for ReclaimingAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.assert_unlocked();
+
+        if let Some((ptr, block_layout)) = self.reclaimable.lock().unwrap().take() {
+            let slab_allocator = self.slab_allocator.get().unwrap();
+
+            match self.magazines.get() {
+                // Safety: The block was allocated with this layout, and is no longer used. The magazines
+                //         are only used by this thread.
+                Some(magazines) => unsafe {
+                    slab_allocator.deallocate_local(magazines, ptr, block_layout);
+                },
+                // Safety: The block was allocated with this layout, and is no longer used.
+                None => unsafe { slab_allocator.deallocate(ptr, block_layout) },
+            }
+        }
+
+        Global.allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.assert_unlocked();
+        self.freed.fetch_add(1, Ordering::Relaxed);
+
+        // Safety: Caller is required to ensure the pointer was allocated with this layout.
+        unsafe { Global.deallocate(ptr, layout) };
+    }
+}
 
 #[test]
 fn vec_realloc_test() {
-    let slab_allocator =
-        crate::SlabAllocator::new_in(NonZeroUsize::new(0x1000).unwrap(), alloc::alloc::Global);
+    let slab_allocator = SlabAllocator::new_in(SLAB_SIZE, Global);
 
     let mut total_len = 0;
     for size in 0..10000 {
@@ -18,3 +109,323 @@ fn vec_realloc_test() {
 
     println!("{}", total_len)
 }
+
+#[test]
+fn blocks_are_aligned_and_disjoint() {
+    let slab_allocator = SlabAllocator::new_in(SLAB_SIZE, Global);
+
+    let mut allocations = Vec::new();
+    for size in [1, 7, 8, 24, 100, 512, 1000, MAX_BLOCK_SIZE] {
+        for align in [1, 8, 64, 256] {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            assert!(slab_allocator.fits(layout));
+
+            for _ in 0..16 {
+                let ptr = slab_allocator.allocate(layout).unwrap();
+                assert!(ptr.len() >= size);
+                assert_eq!(ptr.addr().get() % align, 0);
+
+                let fill = u8::try_from(allocations.len() % 251).unwrap();
+                // Safety: The block is at least `size` bytes.
+                unsafe { ptr.cast::<u8>().write_bytes(fill, size) };
+
+                allocations.push((ptr.cast::<u8>(), layout, fill));
+            }
+        }
+    }
+
+    for (ptr, layout, fill) in allocations {
+        // Safety: The block is at least `layout.size()` bytes, and was filled above.
+        let bytes = unsafe { core::slice::from_raw_parts(ptr.as_ptr(), layout.size()) };
+        assert!(bytes.iter().all(|&byte| byte == fill));
+
+        // Safety: The block was allocated with this layout.
+        unsafe { slab_allocator.deallocate(ptr, layout) };
+    }
+}
+
+#[test]
+fn empty_slabs_are_freed() {
+    let slab_allocator = SlabAllocator::new_in(SLAB_SIZE, CountingAllocator::default());
+    let layout = Layout::new::<[u64; 8]>();
+
+    // Enough blocks to fill several slabs.
+    let allocations = (0..((SLAB_SIZE.get() / layout.size()) * 4))
+        .map(|_| slab_allocator.allocate(layout).unwrap().cast::<u8>())
+        .collect::<Vec<_>>();
+    assert!(
+        slab_allocator
+            .backing_allocator()
+            .live
+            .load(Ordering::Relaxed)
+            > 4
+    );
+
+    for ptr in allocations {
+        // Safety: The block was allocated with this layout.
+        unsafe { slab_allocator.deallocate(ptr, layout) };
+    }
+
+    // A single empty slab is kept for reuse.
+    assert_eq!(
+        slab_allocator
+            .backing_allocator()
+            .live
+            .load(Ordering::Relaxed),
+        1
+    );
+}
+
+#[test]
+fn slabs_are_allocated_with_class_unlocked() {
+    let slab_allocator = &*Box::leak(Box::new(SlabAllocator::new_in(
+        SLAB_SIZE,
+        ReclaimingAllocator::default(),
+    )));
+    let backing_allocator = slab_allocator.backing_allocator();
+    backing_allocator
+        .slab_allocator
+        .set(slab_allocator)
+        .unwrap_or_else(|_| unreachable!());
+    let layout = Layout::new::<[u64; 8]>();
+
+    // Fill the first slab, then queue one of its blocks to be freed while the next slab is allocated.
+    let mut allocations = (0..(SLAB_SIZE.get() / layout.size()) - 1)
+        .map(|_| slab_allocator.allocate(layout).unwrap().cast::<u8>())
+        .collect::<Vec<_>>();
+    *backing_allocator.reclaimable.lock().unwrap() = Some((allocations.pop().unwrap(), layout));
+    allocations.push(slab_allocator.allocate(layout).unwrap().cast::<u8>());
+
+    assert!(backing_allocator.reclaimable.lock().unwrap().is_none());
+
+    for ptr in allocations {
+        // Safety: The block was allocated with this layout.
+        unsafe { slab_allocator.deallocate(ptr, layout) };
+    }
+}
+
+#[test]
+fn magazines_are_refilled_reentrantly() {
+    let slab_allocator = &*Box::leak(Box::new(SlabAllocator::new_in(
+        SLAB_SIZE,
+        ReclaimingAllocator::default(),
+    )));
+    let magazines = &*Box::leak(Box::new(Magazines::new()));
+    let backing_allocator = slab_allocator.backing_allocator();
+    backing_allocator
+        .slab_allocator
+        .set(slab_allocator)
+        .unwrap_or_else(|_| unreachable!());
+    backing_allocator
+        .magazines
+        .set(magazines)
+        .unwrap_or_else(|_| unreachable!());
+    let layout = Layout::new::<[u64; 8]>();
+
+    // Empty the magazine after a few refills, leaving too few blocks in the first slab for another.
+    let slab_blocks = (SLAB_SIZE.get() / layout.size()) - 1;
+    let refill_count = MAGAZINE_CAPACITY / 2;
+    let count = (slab_blocks / refill_count) * refill_count;
+    // Safety: The magazines are only used by this thread.
+    let mut allocations = (0..count)
+        .map(|_| unsafe { slab_allocator.allocate_local(magazines, layout) }.unwrap())
+        .map(NonNull::cast::<u8>)
+        .collect::<Vec<_>>();
+
+    // Queue a block to be freed to the magazine while it's refilled, as a new slab is allocated.
+    *backing_allocator.reclaimable.lock().unwrap() = Some((allocations.pop().unwrap(), layout));
+    // Safety: The magazines are only used by this thread.
+    let ptr = unsafe { slab_allocator.allocate_local(magazines, layout) }.unwrap();
+    allocations.push(ptr.cast::<u8>());
+
+    assert!(backing_allocator.reclaimable.lock().unwrap().is_none());
+    assert_eq!(slab_allocator.stats()[3].slabs, 2);
+
+    let mut addresses = allocations
+        .iter()
+        .map(|ptr| ptr.addr().get())
+        .collect::<Vec<_>>();
+    addresses.sort_unstable();
+    addresses.dedup();
+    assert_eq!(addresses.len(), allocations.len());
+
+    for ptr in allocations {
+        // Safety: The block was allocated with this layout, and the magazines are only used by this thread.
+        unsafe { slab_allocator.deallocate_local(magazines, ptr, layout) };
+    }
+
+    // Safety: The magazines are only used by this thread.
+    unsafe { slab_allocator.flush_local(magazines) };
+    let stats = slab_allocator
+        .stats()
+        .into_iter()
+        .find(|stats| stats.block_size == layout.size())
+        .unwrap();
+    assert_eq!(stats.active_objects, 0);
+    assert_eq!(stats.slabs, 1);
+}
+
+#[test]
+fn empty_slabs_are_freed_with_class_unlocked() {
+    let slab_allocator = &*Box::leak(Box::new(SlabAllocator::new_in(
+        SLAB_SIZE,
+        ReclaimingAllocator::default(),
+    )));
+    let magazines = Magazines::new();
+    let backing_allocator = slab_allocator.backing_allocator();
+    backing_allocator
+        .slab_allocator
+        .set(slab_allocator)
+        .unwrap_or_else(|_| unreachable!());
+    let layout = Layout::new::<[u64; 8]>();
+
+    // Enough blocks to fill several slabs, and overflow the magazine when they're freed.
+    let count = (SLAB_SIZE.get() / layout.size()) * 4;
+    // Safety: The magazines are only used by this thread.
+    let allocations = (0..count)
+        .map(|_| unsafe { slab_allocator.allocate_local(&magazines, layout) }.unwrap())
+        .map(NonNull::cast::<u8>)
+        .collect::<Vec<_>>();
+
+    for ptr in allocations {
+        // Safety: The block was allocated with this layout, and the magazines are only used by this thread.
+        unsafe { slab_allocator.deallocate_local(&magazines, ptr, layout) };
+    }
+    // Safety: The magazines are only used by this thread.
+    unsafe { slab_allocator.flush_local(&magazines) };
+
+    assert!(backing_allocator.freed.load(Ordering::Relaxed) >= 3);
+}
+
+#[test]
+fn large_layouts_pass_through() {
+    let slab_allocator = SlabAllocator::new_in(SLAB_SIZE, CountingAllocator::default());
+
+    for layout in [
+        Layout::from_size_align(MAX_BLOCK_SIZE + 1, 8).unwrap(),
+        Layout::from_size_align(64, 0x4000).unwrap(),
+    ] {
+        assert!(!slab_allocator.fits(layout));
+
+        let ptr = slab_allocator.allocate(layout).unwrap().cast::<u8>();
+        assert_eq!(ptr.addr().get() % layout.align(), 0);
+        assert_eq!(
+            slab_allocator
+                .backing_allocator()
+                .live
+                .load(Ordering::Relaxed),
+            1
+        );
+
+        // Safety: The allocation was made with this layout.
+        unsafe { slab_allocator.deallocate(ptr, layout) };
+        assert_eq!(
+            slab_allocator
+                .backing_allocator()
+                .live
+                .load(Ordering::Relaxed),
+            0
+        );
+    }
+}
+
+#[test]
+fn stats_track_allocations() {
+    let slab_allocator = SlabAllocator::new_in(SLAB_SIZE, Global);
+    let layout = Layout::new::<[u64; 4]>();
+
+    let allocations = (0..10)
+        .map(|_| slab_allocator.allocate(layout).unwrap().cast::<u8>())
+        .collect::<Vec<_>>();
+    for ptr in allocations.into_iter().take(4) {
+        // Safety: The block was allocated with this layout.
+        unsafe { slab_allocator.deallocate(ptr, layout) };
+    }
+
+    let stats = slab_allocator
+        .stats()
+        .into_iter()
+        .find(|stats| stats.block_size == layout.size())
+        .unwrap();
+    assert_eq!(stats.allocs, 10);
+    assert_eq!(stats.frees, 4);
+    assert_eq!(stats.active_objects, 6);
+    assert_eq!(stats.high_water, 10);
+    assert_eq!(stats.slabs, 1);
+
+    // No other class was touched.
+    assert!(
+        slab_allocator
+            .stats()
+            .iter()
+            .filter(|stats| stats.block_size != layout.size())
+            .all(|stats| stats.allocs == 0 && stats.slabs == 0)
+    );
+}
+
+#[test]
+fn magazines_cache_blocks() {
+    let slab_allocator = SlabAllocator::new_in(SLAB_SIZE, CountingAllocator::default());
+    let magazines = Magazines::new();
+    let layout = Layout::new::<[u64; 8]>();
+
+    // Enough blocks to fill several slabs, and overflow the magazine when they're freed.
+    let count = (SLAB_SIZE.get() / layout.size()) * 4;
+    assert!(count > MAGAZINE_CAPACITY);
+
+    // Safety: The magazines are only used by this thread.
+    let allocations = (0..count)
+        .map(|_| unsafe { slab_allocator.allocate_local(&magazines, layout) }.unwrap())
+        .map(NonNull::cast::<u8>)
+        .collect::<Vec<_>>();
+
+    let mut addresses = allocations
+        .iter()
+        .map(|ptr| ptr.addr().get())
+        .collect::<Vec<_>>();
+    addresses.sort_unstable();
+    addresses.dedup();
+    assert_eq!(addresses.len(), count);
+
+    for ptr in allocations {
+        // Safety: The block was allocated with this layout, and the magazines are only used by this thread.
+        unsafe { slab_allocator.deallocate_local(&magazines, ptr, layout) };
+    }
+
+    let stats = slab_allocator
+        .stats()
+        .into_iter()
+        .find(|stats| stats.block_size == layout.size())
+        .unwrap();
+    assert_eq!(stats.allocs, count);
+    assert_eq!(stats.frees, count);
+    assert_eq!(stats.active_objects, 0);
+    assert_eq!(stats.high_water, count);
+
+    // Blocks cached in the magazine keep their slab alive, until they're flushed.
+    assert!(stats.slabs >= 1);
+    // Safety: The magazines are only used by this thread.
+    let freed_bytes = unsafe { slab_allocator.flush_local(&magazines) };
+    assert_eq!(slab_allocator.stats()[3].slabs, 1);
+    assert_eq!(freed_bytes, (stats.slabs - 1) * SLAB_SIZE.get());
+    assert_eq!(
+        slab_allocator
+            .backing_allocator()
+            .live
+            .load(Ordering::Relaxed),
+        1
+    );
+
+    // A magazine which is refilled while the class has blocks to spare doesn't need a new slab.
+    // Safety: The magazines are only used by this thread.
+    let ptr = unsafe { slab_allocator.allocate_local(&magazines, layout) }
+        .unwrap()
+        .cast::<u8>();
+    assert_eq!(slab_allocator.stats()[3].slabs, 1);
+
+    // Safety: The block was allocated with this layout, and the magazines are only used by this thread.
+    unsafe {
+        slab_allocator.deallocate_local(&magazines, ptr, layout);
+        slab_allocator.flush_local(&magazines);
+    }
+}
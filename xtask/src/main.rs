@@ -1,5 +1,6 @@
 mod build;
 mod run;
+mod test;
 
 #[macro_use]
 extern crate clap;
@@ -12,6 +13,7 @@ extern crate xshell;
 enum Arguments {
     Build(build::Options),
     Run(run::Options),
+    Test(test::Options),
 }
 
 fn main() -> anyhow::Result<()> {
@@ -27,6 +29,10 @@ fn main() -> anyhow::Result<()> {
         Arguments::Run(run_options) => {
             run::run(&sh, temp_dir.path(), run_options)?;
         }
+
+        Arguments::Test(test_options) => {
+            test::test(&sh, temp_dir.path(), test_options)?;
+        }
     }
 
     Ok(())
@@ -82,13 +82,64 @@ pub fn run<P: AsRef<Path>>(
         sh.create_dir(".debug/")?;
     }
 
+    let mut run_cmd = qemu_cmd(
+        sh,
+        temp_dir.as_ref(),
+        "run/system",
+        options.cpu,
+        options.accel,
+        options.smp,
+        options.ram,
+    )?
+    .args([
+        "-device",
+        match options.block {
+            BlockDriver::Ahci => "ahci,drive=disk1,serial=deadbeef",
+            BlockDriver::Nvme => "nvme,drive=disk1,serial=deadbeef",
+            BlockDriver::Virtio => "virtio-blk-pci,drive=disk1,serial=deadbeef",
+        },
+    ]);
+
+    if options.log {
+        run_cmd = run_cmd
+            .args(["-d", "int,guest_errors"])
+            .args(["-D", ".debug/qemu.log"]);
+    }
+
+    if options.nographic {
+        run_cmd = run_cmd.arg("-nographic");
+    }
+
+    if options.gdb {
+        run_cmd = run_cmd.args(["-S", "-s"]);
+    }
+
+    if options.norun {
+        println!("cmd: {run_cmd}");
+    } else {
+        run_cmd.run()?;
+    }
+
+    Ok(())
+}
+
+/// Builds the base QEMU command shared by `run` and `test`, booting from `system_dir`.
+pub fn qemu_cmd<'a, P: AsRef<Path>>(
+    sh: &'a xshell::Shell,
+    temp_dir: P,
+    system_dir: &str,
+    cpu: Cpu,
+    accel: Accelerator,
+    smp: usize,
+    ram: usize,
+) -> anyhow::Result<xshell::Cmd<'a>> {
     // Ensure development disk image exists.
     if !sh.path_exists("run/disk0.img") {
         cmd!(sh, "qemu-img create -f raw run/disk0.img 256M").run()?;
     }
 
-    let mut run_cmd = {
-        match options.cpu {
+    let qemu_cmd = {
+        match cpu {
             Cpu::Host | Cpu::Max | Cpu::Qemu64 => {
                 // Create a temporary copy of the OVMF vars firmware to avoid overwriting
                 // the fresh copy that's saved to the repository.
@@ -107,7 +158,7 @@ pub fn run<P: AsRef<Path>>(
                             ovmf_vars_fd_copy.to_string_lossy()
                         ),
                     ])
-                    .args(["-drive", "format=raw,file=fat:rw:run/system"])
+                    .args(["-drive", &format!("format=raw,file=fat:rw:{system_dir}")])
             }
 
             Cpu::Rv64 => unimplemented!(),
@@ -121,7 +172,7 @@ pub fn run<P: AsRef<Path>>(
     .args(["-M", "smm=off"])
     .args([
         "-machine",
-        match (options.cpu, options.accel) {
+        match (cpu, accel) {
             (Cpu::Rv64, Accelerator::None) => "virt",
             (Cpu::Rv64, accel) => panic!("invalid accelerator for RISC-V: {accel:?}"),
 
@@ -131,43 +182,15 @@ pub fn run<P: AsRef<Path>>(
     ])
     .args([
         "-cpu",
-        match options.cpu {
+        match cpu {
             Cpu::Host => "host",
             Cpu::Max => "max",
             Cpu::Qemu64 => "qemu64",
             Cpu::Rv64 => "rv64",
         },
     ])
-    .args(["-smp", &options.smp.to_string()])
-    .args(["-m", &format!("{}M", options.ram)])
-    .args([
-        "-device",
-        match options.block {
-            BlockDriver::Ahci => "ahci,drive=disk1,serial=deadbeef",
-            BlockDriver::Nvme => "nvme,drive=disk1,serial=deadbeef",
-            BlockDriver::Virtio => "virtio-blk-pci,drive=disk1,serial=deadbeef",
-        },
-    ]);
+    .args(["-smp", &smp.to_string()])
+    .args(["-m", &format!("{ram}M")]);
 
-    if options.log {
-        run_cmd = run_cmd
-            .args(["-d", "int,guest_errors"])
-            .args(["-D", ".debug/qemu.log"]);
-    }
-
-    if options.nographic {
-        run_cmd = run_cmd.arg("-nographic");
-    }
-
-    if options.gdb {
-        run_cmd = run_cmd.args(["-S", "-s"]);
-    }
-
-    if options.norun {
-        println!("cmd: {run_cmd}");
-    } else {
-        run_cmd.run()?;
-    }
-
-    Ok(())
+    Ok(qemu_cmd)
 }
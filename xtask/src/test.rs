@@ -0,0 +1,140 @@
+use crate::run::{Accelerator, Cpu};
+use std::{
+    path::Path,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+/// Exit code QEMU reports when the kernel writes a success status to `isa-debug-exit`.
+///
+/// QEMU exits with `(value << 1) | 1`, and the kernel writes `0x10` on success.
+const QEMU_SUCCESS_EXIT_CODE: i32 = (0x10 << 1) | 1;
+
+#[derive(Parser)]
+#[group(skip)]
+pub struct Options {
+    /// CPU type to emulate.
+    #[arg(long, default_value = "qemu64")]
+    cpu: Cpu,
+
+    /// Emulation accelerator to use.
+    #[arg(long, default_value = "none")]
+    accel: Accelerator,
+
+    /// Number of CPUs to emulate.
+    #[arg(long, default_value = "4")]
+    smp: usize,
+
+    // RAM size in MB.
+    #[arg(long, default_value = "512")]
+    ram: usize,
+
+    /// Seconds to wait for the test run to complete before failing.
+    #[arg(long, default_value = "300")]
+    timeout: u64,
+
+    /// Skips invoking the build pipeline for the kernel.
+    #[arg(long)]
+    nobuild: bool,
+
+    #[clap(flatten)]
+    build_options: crate::build::Options,
+}
+
+pub fn test<P: AsRef<Path>>(
+    sh: &xshell::Shell,
+    temp_dir: P,
+    options: Options,
+) -> anyhow::Result<()> {
+    if !options.nobuild {
+        crate::build::build(sh, temp_dir.as_ref(), options.build_options)?;
+    }
+
+    // Boot from a copy of the system directory, so the `selftest` argument can be
+    // added to the bootloader config without touching the development image.
+    let system_dir = temp_dir.as_ref().join("system");
+    copy_dir(sh, "run/system", &system_dir)?;
+
+    let limine_cfg_path = system_dir.join("EFI/BOOT/limine.cfg");
+    let limine_cfg = sh.read_file(&limine_cfg_path)?;
+    sh.write_file(&limine_cfg_path, with_selftest_cmdline(&limine_cfg))?;
+
+    let test_cmd = crate::run::qemu_cmd(
+        sh,
+        temp_dir.as_ref(),
+        system_dir.to_str().unwrap(),
+        options.cpu,
+        options.accel,
+        options.smp,
+        options.ram,
+    )?
+    .args(["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"])
+    .args(["-display", "none"]);
+
+    println!("cmd: {test_cmd}");
+
+    let mut child = Command::from(test_cmd).spawn()?;
+    let timeout = Duration::from_secs(options.timeout);
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            anyhow::bail!("kernel tests timed out after {}s", options.timeout);
+        }
+
+        std::thread::sleep(Duration::from_millis(100));
+    };
+
+    match status.code() {
+        Some(QEMU_SUCCESS_EXIT_CODE) => Ok(()),
+        Some(code) => anyhow::bail!("kernel tests failed (QEMU exit code {code})"),
+        None => anyhow::bail!("QEMU was terminated by a signal"),
+    }
+}
+
+/// Adds the `selftest` argument to the kernel command line of the boot entries.
+fn with_selftest_cmdline(limine_cfg: &str) -> String {
+    let has_cmdline = limine_cfg
+        .lines()
+        .any(|line| line.starts_with("KERNEL_CMDLINE="));
+
+    let mut new_cfg = String::with_capacity(limine_cfg.len());
+    for line in limine_cfg.lines() {
+        new_cfg.push_str(line);
+
+        if line.starts_with("KERNEL_CMDLINE=") {
+            new_cfg.push_str(" selftest");
+        } else if !has_cmdline && line.starts_with("KERNEL_PATH=") {
+            new_cfg.push_str("\nKERNEL_CMDLINE=selftest");
+        }
+
+        new_cfg.push('\n');
+    }
+
+    new_cfg
+}
+
+fn copy_dir<P1: AsRef<Path>, P2: AsRef<Path>>(
+    sh: &xshell::Shell,
+    from: P1,
+    to: P2,
+) -> anyhow::Result<()> {
+    sh.create_dir(to.as_ref())?;
+
+    for path in sh.read_dir(from.as_ref())? {
+        let dest = to.as_ref().join(path.file_name().unwrap());
+
+        if path.is_dir() {
+            copy_dir(sh, &path, dest)?;
+        } else {
+            sh.copy_file(&path, dest)?;
+        }
+    }
+
+    Ok(())
+}